@@ -0,0 +1,39 @@
+//! Criterion benchmarks of how long each policy takes to analyze bucket sets of increasing size.
+//! The inputs are the seeded bucket sets `--bench-policies` times, so the two can be compared.
+
+use clap::{Parser, ValueEnum};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tokio_util::sync::CancellationToken;
+
+use buckets::bench::{self, SEED};
+use buckets::cli::Args;
+use buckets::policy::{Policy, PolicyState};
+
+const SIZES: [usize; 3] = [10, 100, 1000];
+
+fn analyze(c: &mut Criterion) {
+    let params = Args::parse_from(["buckets"]).policy_params();
+    let ct = CancellationToken::new();
+    for policy in Policy::value_variants() {
+        let mut group = c.benchmark_group(policy.to_string());
+        for size in SIZES {
+            let readings = bench::readings(size);
+            let mut state = PolicyState::new(Some(SEED));
+            group.bench_with_input(
+                BenchmarkId::from_parameter(size),
+                &readings,
+                |b, readings| {
+                    b.iter(|| {
+                        policy
+                            .analyze(readings, &params, &mut state, &ct)
+                            .expect("analysis failed")
+                    })
+                },
+            );
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, analyze);
+criterion_main!(benches);
@@ -5,26 +5,74 @@
 //! "dumb", in that it knows how to do the actions and will do then when instructed by the control
 //! signal, but has absolutely no idea about the original sensor data that encouraged this action.
 
+use std::cmp::Reverse;
+use std::collections::HashSet;
 use std::fmt::Display;
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, Error, Result};
+use clap::ValueEnum;
+use itertools::Itertools;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 
-use crate::events::Events;
+use crate::animation::TransferAnimations;
+use crate::buckets::ids::BucketId;
+use crate::events::{EventSource, Events, Severity};
+use crate::fairness::Participation;
+use crate::flow::NetFlow;
+use crate::ledger::Ledger;
+use crate::metrics::Metrics;
+use crate::scheduler::FairQueue;
+use crate::sensor::Sensor;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Action {
     Transfer {
-        source: u64,
-        destination: u64,
+        source: BucketId,
+        destination: BucketId,
         amount: u64,
     },
+    /// Several transfers, as (source, destination, amount), applied in order as a single action:
+    /// either all of them are applied, or none are.
+    MultiTransfer(Vec<(BucketId, BucketId, u64)>),
+    /// Adds an empty bucket, with an ID no bucket has had before.
+    AddBucket,
+    RemoveBucket {
+        bucket: BucketId,
+    },
     NoAction,
 }
 
+impl Action {
+    /// Returns every transfer the action makes, as (source, destination, amount), in order.
+    pub fn transfers(&self) -> Vec<(BucketId, BucketId, u64)> {
+        match self {
+            Action::Transfer {
+                source,
+                destination,
+                amount,
+            } => vec![(*source, *destination, *amount)],
+            Action::MultiTransfer(transfers) => transfers.clone(),
+            Action::AddBucket | Action::RemoveBucket { .. } | Action::NoAction => Vec::new(),
+        }
+    }
+
+    /// Returns every bucket the action touches, in order, possibly more than once.
+    fn touched(&self) -> Vec<BucketId> {
+        match self {
+            Action::RemoveBucket { bucket } => vec![*bucket],
+            _ => self
+                .transfers()
+                .into_iter()
+                .flat_map(|(source, destination, _)| [source, destination])
+                .collect(),
+        }
+    }
+}
+
 impl Display for Action {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -33,63 +81,705 @@ impl Display for Action {
                 destination,
                 amount,
             } => write!(f, "Transfer {} -({})-> {}", source, amount, destination),
+            Self::MultiTransfer(transfers) => write!(
+                f,
+                "MultiTransfer [{}]",
+                transfers
+                    .iter()
+                    .map(|(source, destination, amount)| format!(
+                        "{} -({})-> {}",
+                        source, amount, destination
+                    ))
+                    .join(", ")
+            ),
+            Self::AddBucket => write!(f, "AddBucket"),
+            Self::RemoveBucket { bucket } => write!(f, "RemoveBucket {}", bucket),
             Self::NoAction => write!(f, "NoAction"),
         }
     }
 }
 
+/// BatchOrder decides the order the transfers of a multi-transfer are applied in. Order matters
+/// when capacities are tight: a transfer into a nearly full bucket only fits once the transfers out
+/// of it have gone through, and a single transfer that doesn't fit rolls back the whole batch.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BatchOrder {
+    /// The order the policy emitted them in. Policies that plan a batch against the quantities the
+    /// earlier transfers leave behind rely on this, which makes it the safe default.
+    AsEmitted,
+    /// The largest transfers first. Usually drains the fullest buckets before anything is moved
+    /// into them, but breaks batches where a transfer moves fluid an earlier, smaller one brought.
+    LargestFirst,
+    /// Grouped by source, in increasing order of ID, keeping the emitted order within each source.
+    /// Easier to follow in the event log, with the same risk as largest-first of pulling a transfer
+    /// ahead of the one that fills its source.
+    BySource,
+}
+
+impl BatchOrder {
+    /// Returns the action with its transfers in this order. Only multi-transfers are reordered.
+    pub fn order(self, action: Action) -> Action {
+        let Action::MultiTransfer(mut transfers) = action else {
+            return action;
+        };
+        // Both sorts are stable, so ties stay in the order they were emitted in.
+        match self {
+            BatchOrder::AsEmitted => {}
+            BatchOrder::LargestFirst => transfers.sort_by_key(|(_, _, amount)| Reverse(*amount)),
+            BatchOrder::BySource => transfers.sort_by_key(|(source, _, _)| *source),
+        }
+        Action::MultiTransfer(transfers)
+    }
+}
+
 /// FinalControlElement represents the device that an actuator uses to apply its actions and incur
 /// changes into the control system.
 pub trait FinalControlElement {
+    /// Checks whether the given transfer could be applied, returning the error `transfer` would,
+    /// without applying it.
+    fn can_transfer(&self, source: BucketId, destination: BucketId, amount: u64) -> Result<()>;
     // TODO: Error type is bad.
-    fn transfer(&mut self, source: u64, destination: u64, amount: u64) -> Result<()>;
-    fn add_bucket(&mut self) -> Result<u64>;
+    fn transfer(&mut self, source: BucketId, destination: BucketId, amount: u64) -> Result<()>;
+    /// Adds an empty bucket, returning its ID.
+    fn add_bucket(&mut self) -> Result<BucketId>;
+    /// Removes the given bucket, returning the quantity it held. That quantity leaves the system.
+    fn remove_bucket(&mut self, bucket: BucketId) -> Result<u64>;
+    fn bucket_count(&self) -> usize;
+    /// Returns whether the given bucket is read-only, i.e. must not be touched by any action.
+    fn is_readonly(&self, bucket: BucketId) -> bool;
 }
 
-pub(crate) struct Actuator<B: FinalControlElement> {
+/// How many received actions the actuator holds on to for interleaving.
+const MAX_QUEUED_ACTIONS: usize = 10;
+
+pub struct Actuator<B: FinalControlElement> {
     buckets: Arc<Mutex<B>>,
     events: Arc<Mutex<Events>>,
     control_signal_rx: Receiver<Action>,
+    // The actuator refuses to remove buckets if doing so would leave fewer than this many.
+    min_buckets: usize,
+    // When set, every applied transfer is also handed to the TUI to animate.
+    animations: Option<Arc<Mutex<TransferAnimations>>>,
+    effectiveness: Effectiveness,
+    // Actions received but not yet applied.
+    queue: FairQueue,
+    // Credited with whatever removed buckets spill.
+    ledger: Arc<Mutex<Ledger>>,
+    // Records every applied transfer.
+    flow: Arc<Mutex<NetFlow>>,
+    participation: Participation,
+    // Counts the applied transfers, if metrics are served.
+    metrics: Option<Arc<Metrics>>,
 }
 
-impl<B: FinalControlElement> Actuator<B> {
+impl<B: FinalControlElement + Sensor> Actuator<B> {
     pub fn new(
         buckets: Arc<Mutex<B>>,
         events: Arc<Mutex<Events>>,
         control_signal_rx: Receiver<Action>,
+        min_buckets: usize,
+        animations: Option<Arc<Mutex<TransferAnimations>>>,
+        ledger: Arc<Mutex<Ledger>>,
+        flow: Arc<Mutex<NetFlow>>,
     ) -> Self {
         Actuator {
             buckets,
             events,
             control_signal_rx,
+            min_buckets,
+            animations,
+            effectiveness: Effectiveness::new(),
+            queue: FairQueue::new(),
+            ledger,
+            flow,
+            participation: Participation::new(),
+            metrics: None,
         }
     }
 
-    pub(crate) async fn run(&mut self, ct: CancellationToken) -> Result<()> {
-        let maybe_action = tokio::select! {
-            maybe_action = self.control_signal_rx.recv() => maybe_action,
-            _ = ct.cancelled() => return Ok(()),
-        };
+    /// Has the actuator count its transfers into `metrics` from now on.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Returns how many transfers each bucket took part in so far.
+    pub fn participation(&self) -> &Participation {
+        &self.participation
+    }
+
+    pub async fn run(&mut self, ct: CancellationToken) -> Result<()> {
+        if self.queue.is_empty() {
+            let maybe_action = tokio::select! {
+                maybe_action = self.control_signal_rx.recv() => maybe_action,
+                _ = ct.cancelled() => return Ok(()),
+            };
+            match maybe_action {
+                Some(action) => self.queue.push(action),
+                None => return Ok(()),
+            }
+        }
+        // Take whatever else has been sent in the meantime, so it can be interleaved fairly. Leave
+        // the rest in the channel, so that a controller outpacing the actuator is still held back.
+        while self.queue.len() < MAX_QUEUED_ACTIONS {
+            let Ok(action) = self.control_signal_rx.try_recv() else {
+                break;
+            };
+            self.queue.push(action);
+        }
+        let maybe_action = self.queue.pop();
         eprintln!("processing action: {:?}", maybe_action);
 
         let mut buckets = self.buckets.lock().await;
+        if let Some(warning) = self.effectiveness.check(&*buckets) {
+            self.events.lock().await.add_with_severity(
+                EventSource::Actuator,
+                Severity::Warn,
+                warning,
+            );
+        }
+        let warning = maybe_action
+            .as_ref()
+            .and_then(|action| degenerate(action).or_else(|| refused(&*buckets, action)));
+        if let Some(warning) = warning {
+            self.events.lock().await.add_with_severity(
+                EventSource::Actuator,
+                Severity::Warn,
+                warning,
+            );
+            return Ok(());
+        }
         match maybe_action {
-            Some(
-                action @ Action::Transfer {
-                    source,
-                    destination,
-                    amount,
-                },
-            ) => {
-                buckets.transfer(source, destination, amount)?;
-                self.events.lock().await.add(
-                    crate::events::EventSource::Actuator,
-                    format!("applied action: {}", action),
-                );
+            Some(action @ (Action::Transfer { .. } | Action::MultiTransfer(_))) => {
+                self.effectiveness.track(&action, &*buckets);
+                // A policy's action can still fail here, e.g. if the buckets changed since it was
+                // validated.
+                if let Err(e) = apply(&mut *buckets, &action) {
+                    self.effectiveness.untrack();
+                    self.log_failure(&action, e).await;
+                    return Ok(());
+                }
+                if let Some(metrics) = &self.metrics {
+                    metrics.record_transfers(action.transfers().len() as u64);
+                }
+                for (source, destination, amount) in action.transfers() {
+                    self.flow
+                        .lock()
+                        .await
+                        .record_transfer(source, destination, amount);
+                    self.participation.record_transfer(source, destination);
+                    if let Some(animations) = &self.animations {
+                        animations.lock().await.push(source, destination);
+                    }
+                }
+                // A multi-transfer is logged as the single action it is.
+                self.events
+                    .lock()
+                    .await
+                    .add(EventSource::Actuator, format!("applied action: {}", action));
+                Ok(())
+            }
+            Some(action @ Action::AddBucket) => {
+                match buckets.add_bucket() {
+                    Ok(bucket) => self
+                        .events
+                        .lock()
+                        .await
+                        .add(EventSource::Actuator, format!("added bucket {}", bucket)),
+                    Err(e) => self.log_failure(&action, e).await,
+                }
+                Ok(())
+            }
+            Some(action @ Action::RemoveBucket { bucket }) => {
+                if buckets.bucket_count() <= self.min_buckets {
+                    self.events.lock().await.add(
+                        EventSource::Actuator,
+                        format!(
+                            "refused to remove bucket {}: would leave fewer than {} buckets",
+                            bucket, self.min_buckets
+                        ),
+                    );
+                    return Ok(());
+                }
+                // The same bucket can be asked to be removed twice before the first one is
+                // applied, e.g. by pressing '-' twice in a row.
+                match buckets.remove_bucket(bucket) {
+                    Ok(spilled) => {
+                        self.ledger.lock().await.spill(spilled);
+                        self.events.lock().await.add(
+                            EventSource::Actuator,
+                            format!("removed bucket {} (spilled {})", bucket, spilled),
+                        );
+                    }
+                    Err(e) => self.log_failure(&action, e).await,
+                }
                 Ok(())
             }
             Some(Action::NoAction) => Ok(()),
-            None => return Ok(()),
+            None => Ok(()),
+        }
+    }
+
+    /// Logs an action that failed to apply. That's no reason to end the run, since the next action
+    /// may well go through.
+    async fn log_failure(&self, action: &Action, e: Error) {
+        self.events.lock().await.add_with_severity(
+            EventSource::Actuator,
+            Severity::Critical,
+            format!("failed to apply action: {}: {}", action, e),
+        );
+    }
+}
+
+/// Returns a warning if the given action is degenerate, i.e. a transfer (or multi-transfer) that
+/// would move nothing. Such actions are ignored rather than applied, whichever policy emitted them.
+pub fn degenerate(action: &Action) -> Option<String> {
+    let moves_nothing = match action {
+        Action::Transfer { amount, .. } => *amount == 0,
+        Action::MultiTransfer(transfers) => transfers.iter().all(|(_, _, amount)| *amount == 0),
+        Action::AddBucket | Action::RemoveBucket { .. } | Action::NoAction => false,
+    };
+    moves_nothing.then(|| format!("ignored degenerate action: {} transfers nothing", action))
+}
+
+/// Returns a warning if the given action touches a read-only bucket. Such actions are refused
+/// rather than applied, whichever policy emitted them.
+pub fn refused<B: FinalControlElement>(buckets: &B, action: &Action) -> Option<String> {
+    action
+        .touched()
+        .into_iter()
+        .find(|bucket| buckets.is_readonly(*bucket))
+        .map(|bucket| {
+            format!(
+                "refused action: {} touches read-only bucket {}",
+                action, bucket
+            )
+        })
+}
+
+/// Checks an action against the current state of the buckets before it's handed to the actuator,
+/// so that an action no bucket state could carry out (e.g. from a buggy policy) is caught where it
+/// was decided on, rather than failing in the actuator later. Every bucket the action touches must
+/// exist and not be read-only, and a transfer must fit: the source must hold the amount, and the
+/// destination must have room for it. The transfers of a multi-transfer must each fit in turn,
+/// after the ones before them.
+///
+/// This doesn't stand in for the actuator's own checks, since the buckets may still change between
+/// validation and actuation.
+pub fn validate<S: Sensor>(
+    sensor: &S,
+    readonly: &HashSet<BucketId>,
+    action: &Action,
+) -> Result<()> {
+    // Transfers are checked against the quantities as the ones before them leave them.
+    let mut buckets = sensor.buckets().clone();
+    for bucket in action.touched() {
+        if !buckets.contains_key(&bucket) {
+            return Err(anyhow!("bucket {} doesn't exist", bucket));
+        }
+        if readonly.contains(&bucket) {
+            return Err(anyhow!("bucket {} is read-only", bucket));
+        }
+    }
+    for (source, destination, amount) in action.transfers() {
+        if source == destination {
+            return Err(anyhow!("bucket {} can't transfer to itself", source));
+        }
+        let held = buckets[&source];
+        if amount > held {
+            return Err(anyhow!(
+                "bucket {} holds only {} of the {} to transfer",
+                source,
+                held,
+                amount
+            ));
+        }
+        let room = sensor.capacity().saturating_sub(buckets[&destination]);
+        if amount > room {
+            return Err(anyhow!(
+                "bucket {} has room for only {} of the {} to transfer",
+                destination,
+                room,
+                amount
+            ));
+        }
+        *buckets.get_mut(&source).expect("checked above") -= amount;
+        *buckets.get_mut(&destination).expect("checked above") += amount;
+    }
+    Ok(())
+}
+
+/// Applies the given action to the buckets. This is the part of actuation that does not depend on
+/// how the action was received, so it is shared with the lockstep simulation.
+pub fn apply<B: FinalControlElement>(buckets: &mut B, action: &Action) -> Result<()> {
+    match *action {
+        Action::Transfer {
+            source,
+            destination,
+            amount,
+        } => buckets.transfer(source, destination, amount),
+        Action::MultiTransfer(ref transfers) => {
+            for (applied, &(source, destination, amount)) in transfers.iter().enumerate() {
+                if let Err(e) = buckets.transfer(source, destination, amount) {
+                    // Undo the transfers that went through, latest first, so that the buckets are
+                    // left just as they were.
+                    for &(source, destination, amount) in transfers[..applied].iter().rev() {
+                        buckets.transfer(destination, source, amount)?;
+                    }
+                    return Err(anyhow!(
+                        "{} failed at {} -({})-> {}, rolled back: {}",
+                        action,
+                        source,
+                        amount,
+                        destination,
+                        e
+                    ));
+                }
+            }
+            Ok(())
         }
+        Action::AddBucket => buckets.add_bucket().map(|_| ()),
+        Action::RemoveBucket { bucket } => buckets.remove_bucket(bucket).map(|_| ()),
+        Action::NoAction => Ok(()),
+    }
+}
+
+/// Effectiveness follows up on applied transfers. Every transfer is meant to reduce the imbalance
+/// of the buckets, so on the tick after one is applied, the variance is compared with what it was
+/// right before the transfer. A transfer that didn't reduce it points at a buggy or
+/// counterproductive policy. Fills in the meantime count against the transfer too, so a transfer
+/// that barely helped may still be flagged.
+pub struct Effectiveness {
+    // The last applied transfer, and the variance right before it was applied.
+    pending: Option<(String, f64)>,
+}
+
+impl Default for Effectiveness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Effectiveness {
+    pub fn new() -> Self {
+        Effectiveness { pending: None }
+    }
+
+    /// Records the variance before the given action is applied, to be checked on the next tick.
+    pub fn track<S: Sensor>(&mut self, action: &Action, sensor: &S) {
+        self.pending = sensor
+            .variance()
+            .map(|variance| (action.to_string(), variance));
+    }
+
+    /// Forgets the tracked action, for when it failed to apply and so changed nothing.
+    pub fn untrack(&mut self) {
+        self.pending = None;
+    }
+
+    /// Checks the transfer tracked on the previous tick, if any, returning a warning if it didn't
+    /// reduce the variance.
+    pub fn check<S: Sensor>(&mut self, sensor: &S) -> Option<String> {
+        let (action, before) = self.pending.take()?;
+        let after = sensor.variance()?;
+        (after >= before).then(|| {
+            format!(
+                "ineffective action: {} did not reduce the variance ({:.2} => {:.2})",
+                action, before, after
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::buckets::n_buckets::NBuckets;
+    use crate::buckets::FillStrategy;
+    use crate::clock::SystemClock;
+
+    // Buckets 1, 2 and 3 hold 95, 10 and 50 of 100.
+    fn buckets() -> NBuckets {
+        let data = HashMap::from([(BucketId(1), 95), (BucketId(2), 10), (BucketId(3), 50)]);
+        NBuckets::new(
+            data,
+            100,
+            FillStrategy::Steady,
+            None,
+            None,
+            HashMap::new(),
+            Some(0),
+        )
+    }
+
+    // Validates the action against buckets(), with bucket 3 read-only.
+    fn check(action: Action) -> Result<()> {
+        validate(&buckets(), &HashSet::from([BucketId(3)]), &action)
+    }
+
+    fn transfer(source: u64, destination: u64, amount: u64) -> Action {
+        Action::Transfer {
+            source: BucketId(source),
+            destination: BucketId(destination),
+            amount,
+        }
+    }
+
+    #[test]
+    fn valid_actions_pass() {
+        assert!(check(transfer(1, 2, 40)).is_ok());
+        assert!(check(transfer(1, 2, 90)).is_ok());
+        assert!(check(Action::MultiTransfer(vec![
+            (BucketId(1), BucketId(2), 80),
+            (BucketId(2), BucketId(1), 85),
+        ]))
+        .is_ok());
+        assert!(check(Action::AddBucket).is_ok());
+        assert!(check(Action::NoAction).is_ok());
+    }
+
+    #[test]
+    fn invalid_actions_are_rejected() {
+        let reason = |action| check(action).unwrap_err().to_string();
+        assert_eq!(reason(transfer(1, 4, 10)), "bucket 4 doesn't exist");
+        assert_eq!(reason(transfer(1, 3, 10)), "bucket 3 is read-only");
+        assert_eq!(
+            reason(Action::RemoveBucket {
+                bucket: BucketId(3)
+            }),
+            "bucket 3 is read-only"
+        );
+        assert_eq!(
+            reason(transfer(1, 1, 10)),
+            "bucket 1 can't transfer to itself"
+        );
+        assert_eq!(
+            reason(transfer(2, 1, 20)),
+            "bucket 2 holds only 10 of the 20 to transfer"
+        );
+        assert_eq!(
+            reason(transfer(2, 1, 10)),
+            "bucket 1 has room for only 5 of the 10 to transfer"
+        );
+        // The second transfer only fails given what the first one took.
+        assert_eq!(
+            reason(Action::MultiTransfer(vec![
+                (BucketId(1), BucketId(2), 50),
+                (BucketId(1), BucketId(2), 50),
+            ])),
+            "bucket 1 holds only 45 of the 50 to transfer"
+        );
+    }
+
+    #[test]
+    fn batch_order_decides_whether_a_batch_fits() {
+        // Bucket 1 only has room for the second 5 once the 40 has left it.
+        let batch = Action::MultiTransfer(vec![
+            (BucketId(2), BucketId(1), 5),
+            (BucketId(2), BucketId(1), 5),
+            (BucketId(1), BucketId(2), 40),
+        ]);
+        let mut as_emitted = buckets();
+        let err = apply(&mut as_emitted, &BatchOrder::AsEmitted.order(batch.clone())).unwrap_err();
+        assert!(err.to_string().contains("rolled back"), "{}", err);
+        assert_eq!(as_emitted.buckets(), buckets().buckets());
+
+        for order in [BatchOrder::LargestFirst, BatchOrder::BySource] {
+            let mut reordered = buckets();
+            apply(&mut reordered, &order.order(batch.clone())).unwrap();
+            assert_eq!(reordered.get_bucket_quantity(BucketId(1)).unwrap(), 65);
+            assert_eq!(reordered.get_bucket_quantity(BucketId(2)).unwrap(), 40);
+        }
+    }
+
+    #[test]
+    fn batch_order_keeps_ties_in_emitted_order() {
+        let batch = Action::MultiTransfer(vec![
+            (BucketId(2), BucketId(1), 5),
+            (BucketId(3), BucketId(1), 10),
+            (BucketId(2), BucketId(3), 10),
+        ]);
+        assert_eq!(
+            BatchOrder::LargestFirst.order(batch.clone()),
+            Action::MultiTransfer(vec![
+                (BucketId(3), BucketId(1), 10),
+                (BucketId(2), BucketId(3), 10),
+                (BucketId(2), BucketId(1), 5),
+            ])
+        );
+        assert_eq!(
+            BatchOrder::BySource.order(batch.clone()),
+            Action::MultiTransfer(vec![
+                (BucketId(2), BucketId(1), 5),
+                (BucketId(2), BucketId(3), 10),
+                (BucketId(3), BucketId(1), 10),
+            ])
+        );
+        assert_eq!(BatchOrder::AsEmitted.order(batch.clone()), batch);
+        assert_eq!(
+            BatchOrder::LargestFirst.order(transfer(1, 2, 5)),
+            transfer(1, 2, 5)
+        );
+    }
+
+    /// Returns an actuator with the given floor over `buckets`, logging to `events`, and where to
+    /// send it actions.
+    async fn actuator(
+        buckets: Arc<Mutex<NBuckets>>,
+        events: Arc<Mutex<Events>>,
+        min_buckets: usize,
+    ) -> (Actuator<NBuckets>, tokio::sync::mpsc::Sender<Action>) {
+        let ledger = Arc::new(Mutex::new(Ledger::new(&*buckets.lock().await)));
+        let flow = Arc::new(Mutex::new(NetFlow::new(
+            chrono::TimeDelta::seconds(10),
+            Arc::new(SystemClock),
+        )));
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let actuator = Actuator::new(buckets, events, rx, min_buckets, None, ledger, flow);
+        (actuator, tx)
+    }
+
+    /// Has an actuator with the given floor apply one removal after another to buckets(),
+    /// returning the bucket count after each one.
+    async fn remove_buckets(min_buckets: usize, removals: &[u64]) -> Result<Vec<usize>> {
+        let buckets = Arc::new(Mutex::new(buckets()));
+        let events = Arc::new(Mutex::new(Events::new()));
+        let (mut actuator, tx) = actuator(buckets.clone(), events, min_buckets).await;
+        let mut counts = Vec::new();
+        for bucket in removals {
+            tx.send(Action::RemoveBucket {
+                bucket: BucketId(*bucket),
+            })
+            .await?;
+            actuator.run(CancellationToken::new()).await?;
+            counts.push(buckets.lock().await.bucket_count());
+        }
+        Ok(counts)
+    }
+
+    #[tokio::test]
+    async fn removals_stop_at_min_buckets() -> Result<()> {
+        assert_eq!(remove_buckets(2, &[1, 2, 3]).await?, [2, 2, 2]);
+        assert_eq!(remove_buckets(1, &[1, 2, 3]).await?, [2, 1, 1]);
+        assert_eq!(remove_buckets(0, &[1, 2, 3]).await?, [2, 1, 0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn zero_transfers_are_ignored() -> Result<()> {
+        let buckets = Arc::new(Mutex::new(buckets()));
+        let events = Arc::new(Mutex::new(Events::new()));
+        let (mut actuator, tx) = actuator(buckets.clone(), events.clone(), 0).await;
+        let before = buckets.lock().await.buckets().clone();
+        for action in [
+            transfer(1, 2, 0),
+            Action::MultiTransfer(vec![(BucketId(1), BucketId(2), 0)]),
+        ] {
+            tx.send(action).await?;
+            actuator.run(CancellationToken::new()).await?;
+        }
+        assert_eq!(*buckets.lock().await.buckets(), before);
+        let events = events.lock().await;
+        let logged = events.get_all();
+        assert_eq!(logged.len(), 2);
+        for event in logged {
+            assert!(event.severity == Severity::Warn);
+            assert!(
+                event.message.starts_with("ignored degenerate action:"),
+                "{}",
+                event.message
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_transfers_are_logged_and_skipped() -> Result<()> {
+        let buckets = Arc::new(Mutex::new(buckets()));
+        let events = Arc::new(Mutex::new(Events::new()));
+        let (mut actuator, tx) = actuator(buckets.clone(), events.clone(), 0).await;
+        // Bucket 2 only holds 10, but the actuator carries on with the next action.
+        for action in [transfer(2, 1, 20), transfer(1, 2, 5)] {
+            tx.send(action).await?;
+            actuator.run(CancellationToken::new()).await?;
+        }
+        assert_eq!(
+            *buckets.lock().await.buckets(),
+            HashMap::from([(BucketId(1), 90), (BucketId(2), 15), (BucketId(3), 50)])
+        );
+        let events = events.lock().await;
+        let logged = events.get_all();
+        assert_eq!(logged.len(), 2);
+        assert!(logged[0].source == EventSource::Actuator);
+        assert!(logged[0].severity == Severity::Critical);
+        assert!(
+            logged[0]
+                .message
+                .starts_with("failed to apply action: Transfer 2 -(20)-> 1: "),
+            "{}",
+            logged[0].message
+        );
+        assert_eq!(logged[1].message, "applied action: Transfer 1 -(5)-> 2");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn actions_touching_readonly_buckets_are_refused() -> Result<()> {
+        let mut readonly = buckets();
+        readonly.set_readonly(HashSet::from([BucketId(3)]), false);
+        let buckets = Arc::new(Mutex::new(readonly));
+        let events = Arc::new(Mutex::new(Events::new()));
+        let (mut actuator, tx) = actuator(buckets.clone(), events.clone(), 0).await;
+        let before = buckets.lock().await.buckets().clone();
+        for action in [
+            transfer(1, 3, 5),
+            transfer(3, 2, 5),
+            Action::RemoveBucket {
+                bucket: BucketId(3),
+            },
+        ] {
+            tx.send(action).await?;
+            actuator.run(CancellationToken::new()).await?;
+        }
+        assert_eq!(*buckets.lock().await.buckets(), before);
+        let events = events.lock().await;
+        let logged = events.get_all();
+        assert_eq!(logged.len(), 3);
+        for event in logged {
+            assert!(event.severity == Severity::Warn);
+            assert!(
+                event.message.ends_with("touches read-only bucket 3"),
+                "{}",
+                event.message
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn counterproductive_transfers_are_flagged() -> Result<()> {
+        let check = |action: Action| -> Result<Option<String>> {
+            let mut buckets = buckets();
+            let mut effectiveness = Effectiveness::new();
+            effectiveness.track(&action, &buckets);
+            apply(&mut buckets, &action)?;
+            Ok(effectiveness.check(&buckets))
+        };
+        // Moving fluid from the emptiest bucket into the fullest one only makes things worse.
+        let warning = check(transfer(2, 1, 5))?.expect("flagged");
+        assert!(
+            warning.starts_with("ineffective action: Transfer 2 -(5)-> 1"),
+            "{}",
+            warning
+        );
+        assert_eq!(check(transfer(1, 2, 40))?, None);
+
+        // An untracked action isn't checked at all.
+        let mut effectiveness = Effectiveness::new();
+        effectiveness.track(&transfer(2, 1, 5), &buckets());
+        effectiveness.untrack();
+        assert_eq!(effectiveness.check(&buckets()), None);
+        Ok(())
     }
 }
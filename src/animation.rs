@@ -0,0 +1,129 @@
+//! Transfer animations briefly highlight the buckets involved in each applied transfer, so that the
+//! fluid can be seen "moving" in the TUI. Every animation lasts a fixed number of frames, and the
+//! TUI advances all of them by one frame each time it draws.
+//!
+//! The settling animation instead plays once, on startup: the bars grow from zero to the initial
+//! quantities over the first few frames, so that the initial state doesn't just appear all at once.
+
+use crate::buckets::ids::BucketId;
+
+/// How many frames a transfer stays highlighted.
+const ANIMATION_FRAMES: u32 = 5;
+
+/// How many frames the bars take to settle on startup.
+const SETTLING_FRAMES: u32 = 10;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Highlight {
+    Source,
+    Destination,
+}
+
+struct TransferAnimation {
+    source: BucketId,
+    destination: BucketId,
+    frames_left: u32,
+}
+
+pub struct TransferAnimations {
+    active: Vec<TransferAnimation>,
+}
+
+impl Default for TransferAnimations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransferAnimations {
+    pub fn new() -> Self {
+        TransferAnimations { active: Vec::new() }
+    }
+
+    pub fn push(&mut self, source: BucketId, destination: BucketId) {
+        self.active.push(TransferAnimation {
+            source,
+            destination,
+            frames_left: ANIMATION_FRAMES,
+        });
+    }
+
+    /// Moves every animation forward by a frame, dropping those that have finished.
+    pub fn advance(&mut self) {
+        for animation in self.active.iter_mut() {
+            animation.frames_left = animation.frames_left.saturating_sub(1);
+        }
+        self.active.retain(|animation| animation.frames_left > 0);
+    }
+
+    /// Returns how the given bucket should be highlighted this frame, if at all, along with the
+    /// marker to draw next to its label. When a bucket is part of several animations, the most
+    /// recent one wins.
+    pub fn highlight(&self, bucket: BucketId) -> Option<(Highlight, &'static str)> {
+        let animation = self
+            .active
+            .iter()
+            .rev()
+            .find(|animation| animation.source == bucket || animation.destination == bucket)?;
+        // Alternate the marker between frames so the direction of the transfer reads as motion.
+        let moving = animation.frames_left % 2 == 0;
+        if animation.source == bucket {
+            Some((Highlight::Source, if moving { "▼" } else { "▽" }))
+        } else {
+            Some((Highlight::Destination, if moving { "▲" } else { "△" }))
+        }
+    }
+}
+
+pub struct Settling {
+    // How many frames have been drawn so far.
+    frame: u32,
+}
+
+impl Default for Settling {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Settling {
+    pub fn new() -> Self {
+        Settling { frame: 0 }
+    }
+
+    /// Returns how much of `quantity` to draw this frame. This grows linearly with every frame,
+    /// from zero on the first to the whole quantity once the animation is done.
+    pub fn interpolate(&self, quantity: u64) -> u64 {
+        let frame = self.frame.min(SETTLING_FRAMES);
+        (quantity as u128 * frame as u128 / SETTLING_FRAMES as u128) as u64
+    }
+
+    /// Moves the animation forward by a frame, returning whether it still has frames left to play.
+    pub fn advance(&mut self) -> bool {
+        self.frame += 1;
+        self.frame <= SETTLING_FRAMES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settling_grows_monotonically_to_the_quantity() {
+        for quantity in [0, 1, 7, 100, u64::MAX] {
+            let mut settling = Settling::new();
+            let mut drawn = vec![settling.interpolate(quantity)];
+            while settling.advance() {
+                drawn.push(settling.interpolate(quantity));
+            }
+            assert_eq!(drawn.len(), SETTLING_FRAMES as usize + 1);
+            assert_eq!(drawn.first(), Some(&0));
+            assert_eq!(drawn.last(), Some(&quantity));
+            assert!(drawn.is_sorted(), "{:?}", drawn);
+            // Once settled, the bars stay put.
+            settling.advance();
+            assert_eq!(settling.interpolate(quantity), quantity);
+        }
+    }
+}
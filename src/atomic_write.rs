@@ -0,0 +1,71 @@
+//! Files that are saved whole, such as fixtures, are written to a temporary file next to their
+//! destination and then renamed into place. A rename within a directory is atomic, so if the
+//! program dies mid-write, whatever was at the destination before is left intact, rather than
+//! truncated or half-written.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+/// Replaces the file at `path` with `contents`, atomically.
+pub fn write(path: &Path, contents: &[u8]) -> Result<()> {
+    let temp_path = temp_path(path)?;
+    let written =
+        write_synced(&temp_path, contents).and_then(|_| Ok(fs::rename(&temp_path, path)?));
+    if written.is_err() {
+        // Don't leave the partial file behind. The destination is untouched either way.
+        let _ = fs::remove_file(&temp_path);
+    }
+    written
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(contents)?;
+    // Make sure the contents are on disk before the rename makes them visible.
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Returns a hidden, process-specific path in the same directory as `path`, so that the rename
+/// stays within a filesystem.
+fn temp_path(path: &Path) -> Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("not a file path: {}", path.display()))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    Ok(path.with_file_name(temp_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupted_write_leaves_the_old_file() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("atomic-{}.txt", std::process::id()));
+        write(&path, b"old")?;
+        assert_eq!(fs::read(&path)?, b"old");
+
+        // A directory where the temporary file goes makes the write fail before the rename.
+        let temp = temp_path(&path)?;
+        fs::create_dir(&temp)?;
+        let failed = write(&path, b"new");
+        fs::remove_dir(&temp)?;
+        assert!(failed.is_err());
+        assert_eq!(fs::read(&path)?, b"old");
+
+        // A partial temporary file left behind by a crash is simply overwritten.
+        fs::write(&temp, b"ne")?;
+        write(&path, b"new")?;
+        assert_eq!(fs::read(&path)?, b"new");
+        assert!(!temp.exists());
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+//! A small benchmark of how long each policy takes to analyze bucket sets of increasing size.
+//! Every policy analyzes the same seeded, random bucket set of each size, repeatedly until a time
+//! budget runs out, and the mean time per analysis is reported. This is meant for comparing
+//! policies and catching regressions, so the inputs are deterministic but the timings are not.
+//! The criterion benchmarks in `benches/policy.rs` analyze the same inputs, built by [`readings`].
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio_util::sync::CancellationToken;
+
+use crate::buckets::ids::BucketId;
+use crate::buckets::MAX_QUANTITY;
+use crate::policy::{Policy, PolicyParams, PolicyState};
+use crate::sensor::Readings;
+
+/// How long to keep analyzing each policy and size for.
+const BUDGET: Duration = Duration::from_millis(200);
+/// The seed of the bucket sets and of the policies' RNG.
+pub const SEED: u64 = 0;
+
+pub struct Measurement {
+    pub policy: Policy,
+    pub size: usize,
+    pub iterations: u32,
+    pub mean: Duration,
+}
+
+/// Measures every policy over bucket sets of each of the given sizes.
+pub fn run(sizes: &[usize], params: &PolicyParams) -> Result<Vec<Measurement>> {
+    let mut measurements = Vec::new();
+    let mut state = PolicyState::new(Some(SEED));
+    let ct = CancellationToken::new();
+    for size in sizes {
+        let readings = readings(*size);
+        for policy in Policy::value_variants() {
+            let mut iterations = 0;
+            let start = Instant::now();
+            // Always analyze at least once, however slow that is.
+            while iterations == 0 || start.elapsed() < BUDGET {
+                std::hint::black_box(policy.analyze(&readings, params, &mut state, &ct)?);
+                iterations += 1;
+            }
+            measurements.push(Measurement {
+                policy: *policy,
+                size: *size,
+                iterations,
+                mean: start.elapsed() / iterations,
+            });
+        }
+    }
+    Ok(measurements)
+}
+
+/// Returns readings of `size` buckets with seeded, random quantities, the same on every call.
+pub fn readings(size: usize) -> Readings {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let buckets: HashMap<BucketId, u64> = (1..=size as u64)
+        .map(|bucket| (BucketId(bucket), rng.random_range(0..=MAX_QUANTITY)))
+        .collect();
+    Readings::new(buckets, MAX_QUANTITY)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::cli::Args;
+    use crate::sensor::Sensor;
+
+    #[test]
+    fn every_policy_is_measured_at_every_size() {
+        let params = Args::try_parse_from(["buckets"]).unwrap().policy_params();
+        let measurements = run(&[1, 10], &params).unwrap();
+        assert_eq!(measurements.len(), 2 * Policy::value_variants().len());
+        assert!(measurements.iter().all(|m| m.iterations > 0));
+    }
+
+    #[test]
+    fn readings_repeat() {
+        assert_eq!(readings(100).buckets(), readings(100).buckets());
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use rand_chacha::ChaCha12Rng;
+
+use crate::actuator::FinalControlElement;
+use crate::sensor::Sensor;
+
+use super::ids::BucketId;
+use super::leaky_buckets::LeakyBuckets;
+use super::n_buckets::NBuckets;
+use super::{Buckets, Datum, Fill};
+
+/// AnyBuckets holds buckets of whichever type --bucket-type picked, so that the rest of the program
+/// can work with them without knowing which one that is.
+pub enum AnyBuckets {
+    NBuckets(NBuckets),
+    LeakyBuckets(LeakyBuckets),
+}
+
+impl AnyBuckets {
+    /// Returns the fill RNG, to save along with the bucket data.
+    pub fn rng(&self) -> &ChaCha12Rng {
+        match self {
+            AnyBuckets::NBuckets(buckets) => buckets.rng(),
+            AnyBuckets::LeakyBuckets(buckets) => buckets.rng(),
+        }
+    }
+
+    /// Replaces the fill RNG with a saved one, so that fills carry on with its sequence.
+    pub fn set_rng(&mut self, rng: ChaCha12Rng) {
+        match self {
+            AnyBuckets::NBuckets(buckets) => buckets.set_rng(rng),
+            AnyBuckets::LeakyBuckets(buckets) => buckets.set_rng(rng),
+        }
+    }
+
+    fn inner(&self) -> &dyn Inner {
+        match self {
+            AnyBuckets::NBuckets(buckets) => buckets,
+            AnyBuckets::LeakyBuckets(buckets) => buckets,
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut dyn Inner {
+        match self {
+            AnyBuckets::NBuckets(buckets) => buckets,
+            AnyBuckets::LeakyBuckets(buckets) => buckets,
+        }
+    }
+}
+
+/// Inner is what every bucket type implements, so that AnyBuckets can forward to whichever it
+/// holds.
+trait Inner: Buckets + Sensor + FinalControlElement {}
+
+impl<T: Buckets + Sensor + FinalControlElement> Inner for T {}
+
+impl Buckets for AnyBuckets {
+    fn fill(&mut self) -> Vec<Fill> {
+        self.inner_mut().fill()
+    }
+
+    fn pour(&mut self, bucket: BucketId, amount: u64) -> Result<Fill> {
+        self.inner_mut().pour(bucket, amount)
+    }
+
+    fn set_capacity(&mut self, capacity: u64) -> Vec<(BucketId, u64)> {
+        self.inner_mut().set_capacity(capacity)
+    }
+
+    fn total_capacity(&self) -> u64 {
+        self.inner().total_capacity()
+    }
+
+    fn take_leaked(&mut self) -> u64 {
+        self.inner_mut().take_leaked()
+    }
+
+    fn data(&self) -> Vec<Datum> {
+        self.inner().data()
+    }
+}
+
+impl Sensor for AnyBuckets {
+    fn buckets(&self) -> &HashMap<BucketId, u64> {
+        self.inner().buckets()
+    }
+
+    fn get_bucket_quantity(&self, bucket: BucketId) -> Result<u64> {
+        self.inner().get_bucket_quantity(bucket)
+    }
+
+    fn capacity(&self) -> u64 {
+        self.inner().capacity()
+    }
+}
+
+impl FinalControlElement for AnyBuckets {
+    fn can_transfer(&self, source: BucketId, destination: BucketId, amount: u64) -> Result<()> {
+        self.inner().can_transfer(source, destination, amount)
+    }
+
+    fn transfer(&mut self, source: BucketId, destination: BucketId, amount: u64) -> Result<()> {
+        self.inner_mut().transfer(source, destination, amount)
+    }
+
+    fn add_bucket(&mut self) -> Result<BucketId> {
+        self.inner_mut().add_bucket()
+    }
+
+    fn remove_bucket(&mut self, bucket: BucketId) -> Result<u64> {
+        self.inner_mut().remove_bucket(bucket)
+    }
+
+    fn bucket_count(&self) -> usize {
+        self.inner().bucket_count()
+    }
+
+    fn is_readonly(&self, bucket: BucketId) -> bool {
+        self.inner().is_readonly(bucket)
+    }
+}
@@ -0,0 +1,149 @@
+//! Bucket IDs are handed out by an allocator, so that adding buckets never collides with a bucket
+//! that exists, or once existed, and never overflows. New IDs count up from the highest ID ever
+//! used, so gaps left by removals (or by the initial data) are never filled in on their own. Freed
+//! IDs can be reused instead, and are once there are no higher IDs left to hand out.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+/// BucketId identifies a bucket. It's a type of its own so that IDs and quantities, which are both
+/// whole numbers, can't be passed for one another. It formats and parses as the plain number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BucketId(pub u64);
+
+impl fmt::Display for BucketId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<u64> for BucketId {
+    fn from(id: u64) -> Self {
+        BucketId(id)
+    }
+}
+
+impl FromStr for BucketId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(BucketId)
+    }
+}
+
+pub struct IdAllocator {
+    // The highest ID ever in use, if any.
+    max_used: Option<BucketId>,
+    // IDs that have been freed and not handed out again since.
+    freed: BTreeSet<BucketId>,
+    // Whether to hand out freed IDs before fresh ones.
+    reuse_freed: bool,
+}
+
+impl IdAllocator {
+    /// Creates an allocator for a set of buckets that already uses the given IDs.
+    pub fn new(used: impl IntoIterator<Item = BucketId>, reuse_freed: bool) -> Self {
+        IdAllocator {
+            max_used: used.into_iter().max(),
+            freed: BTreeSet::new(),
+            reuse_freed,
+        }
+    }
+
+    /// Returns an ID that isn't in use.
+    pub fn allocate(&mut self) -> Result<BucketId> {
+        if self.reuse_freed {
+            if let Some(id) = self.freed.pop_first() {
+                return Ok(id);
+            }
+        }
+        let fresh = match self.max_used {
+            Some(BucketId(max_used)) => max_used.checked_add(1).map(BucketId),
+            None => Some(BucketId(0)),
+        };
+        match fresh {
+            Some(id) => {
+                self.max_used = Some(id);
+                Ok(id)
+            }
+            // Every ID up to u64::MAX has been used, so freed IDs are all that's left.
+            None => self
+                .freed
+                .pop_first()
+                .ok_or_else(|| anyhow!("no bucket IDs left to allocate")),
+        }
+    }
+
+    /// Marks the given ID as no longer in use.
+    pub fn free(&mut self, id: BucketId) {
+        self.freed.insert(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn allocation_after_removals() -> Result<()> {
+        let used = [BucketId(1), BucketId(2), BucketId(5)];
+        let mut fresh = IdAllocator::new(used, false);
+        fresh.free(BucketId(2));
+        assert_eq!(fresh.allocate()?, BucketId(6));
+        assert_eq!(fresh.allocate()?, BucketId(7));
+
+        let mut reusing = IdAllocator::new(used, true);
+        reusing.free(BucketId(5));
+        reusing.free(BucketId(2));
+        assert_eq!(reusing.allocate()?, BucketId(2));
+        assert_eq!(reusing.allocate()?, BucketId(5));
+        assert_eq!(reusing.allocate()?, BucketId(6));
+
+        assert_eq!(IdAllocator::new([], false).allocate()?, BucketId(0));
+        Ok(())
+    }
+
+    #[test]
+    fn allocation_near_the_last_id() -> Result<()> {
+        let mut ids = IdAllocator::new([BucketId(u64::MAX - 1)], false);
+        assert_eq!(ids.allocate()?, BucketId(u64::MAX));
+        assert!(ids.allocate().is_err());
+        // Once the fresh IDs run out, freed ones are handed out even without reuse.
+        ids.free(BucketId(3));
+        assert_eq!(ids.allocate()?, BucketId(3));
+        assert!(ids.allocate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn allocated_ids_never_collide() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(0);
+        for reuse_freed in [false, true] {
+            let mut live = (1..=10).map(BucketId).collect::<HashSet<_>>();
+            let mut ids = IdAllocator::new(live.iter().copied(), reuse_freed);
+            for _ in 0..10_000 {
+                if live.is_empty() || rng.random_bool(0.5) {
+                    let id = ids.allocate()?;
+                    assert!(live.insert(id), "{} allocated while in use", id);
+                } else {
+                    // Sort the live IDs so that seeded runs remove the same ones.
+                    let mut candidates = live.iter().copied().collect::<Vec<_>>();
+                    candidates.sort();
+                    let id = candidates[rng.random_range(0..candidates.len())];
+                    live.remove(&id);
+                    ids.free(id);
+                }
+            }
+        }
+        Ok(())
+    }
+}
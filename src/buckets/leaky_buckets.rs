@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use rand_chacha::ChaCha12Rng;
+
+use crate::actuator::FinalControlElement;
+use crate::sensor::Sensor;
+
+use super::ids::BucketId;
+use super::n_buckets::NBuckets;
+use super::{Buckets, Datum, Fill};
+
+/// LeakyBuckets are NBuckets that lose fluid, modeling evaporation: every fill is followed by a
+/// leak of a fixed amount from every bucket, or whatever it holds if that's less. Leaked fluid
+/// leaves the system, just like spilled fluid does.
+pub struct LeakyBuckets {
+    buckets: NBuckets,
+    // How much every bucket loses per fill.
+    leak_rate: u64,
+    // How much has leaked out since it was last taken.
+    leaked: u64,
+}
+
+impl LeakyBuckets {
+    pub fn new(buckets: NBuckets, leak_rate: u64) -> Self {
+        LeakyBuckets {
+            buckets,
+            leak_rate,
+            leaked: 0,
+        }
+    }
+
+    /// Returns the fill RNG, to save along with the bucket data.
+    pub fn rng(&self) -> &ChaCha12Rng {
+        self.buckets.rng()
+    }
+
+    /// Replaces the fill RNG with a saved one, so that fills carry on with its sequence.
+    pub fn set_rng(&mut self, rng: ChaCha12Rng) {
+        self.buckets.set_rng(rng);
+    }
+}
+
+impl Buckets for LeakyBuckets {
+    /// Fills the buckets as NBuckets do, then leaks from every one of them. The fills report the
+    /// quantities from before the leak.
+    fn fill(&mut self) -> Vec<Fill> {
+        let fills = self.buckets.fill();
+        self.leaked += self.buckets.drain_each(self.leak_rate);
+        fills
+    }
+
+    fn pour(&mut self, bucket: BucketId, amount: u64) -> Result<Fill> {
+        self.buckets.pour(bucket, amount)
+    }
+
+    fn set_capacity(&mut self, capacity: u64) -> Vec<(BucketId, u64)> {
+        self.buckets.set_capacity(capacity)
+    }
+
+    fn total_capacity(&self) -> u64 {
+        self.buckets.total_capacity()
+    }
+
+    fn take_leaked(&mut self) -> u64 {
+        std::mem::take(&mut self.leaked)
+    }
+
+    fn data(&self) -> Vec<Datum> {
+        self.buckets.data()
+    }
+}
+
+impl Sensor for LeakyBuckets {
+    fn buckets(&self) -> &HashMap<BucketId, u64> {
+        self.buckets.buckets()
+    }
+
+    fn get_bucket_quantity(&self, bucket: BucketId) -> Result<u64> {
+        self.buckets.get_bucket_quantity(bucket)
+    }
+
+    fn capacity(&self) -> u64 {
+        self.buckets.capacity()
+    }
+}
+
+impl FinalControlElement for LeakyBuckets {
+    fn can_transfer(&self, source: BucketId, destination: BucketId, amount: u64) -> Result<()> {
+        self.buckets.can_transfer(source, destination, amount)
+    }
+
+    fn transfer(&mut self, source: BucketId, destination: BucketId, amount: u64) -> Result<()> {
+        self.buckets.transfer(source, destination, amount)
+    }
+
+    fn add_bucket(&mut self) -> Result<BucketId> {
+        self.buckets.add_bucket()
+    }
+
+    fn remove_bucket(&mut self, bucket: BucketId) -> Result<u64> {
+        self.buckets.remove_bucket(bucket)
+    }
+
+    fn bucket_count(&self) -> usize {
+        self.buckets.bucket_count()
+    }
+
+    fn is_readonly(&self, bucket: BucketId) -> bool {
+        self.buckets.is_readonly(bucket)
+    }
+}
@@ -1,26 +1,114 @@
 use std::fmt::Display;
 
+use anyhow::Result;
 use clap::ValueEnum;
 
+pub mod any_buckets;
+pub mod ids;
+pub mod leaky_buckets;
 pub mod n_buckets;
+pub mod throttle;
 
-// TODO: This should be a parameter.
+use ids::BucketId;
+
+/// The capacity buckets start out with, unless given another with --max-quantity.
 pub const MAX_QUANTITY: u64 = 100;
 
-pub(crate) trait Buckets {
-    fn fill(&mut self) -> (u64, u64, u64);
-    fn data(&self) -> Vec<(String, u64)>;
+/// Datum is what the TUI needs to draw a single bucket.
+pub struct Datum {
+    pub bucket: BucketId,
+    pub label: String,
+    pub quantity: u64,
+    /// The category the bucket was tagged with, if any.
+    pub tag: Option<String>,
+}
+
+/// Fill is what a single fill did to a bucket.
+pub struct Fill {
+    pub bucket: BucketId,
+    /// How much the fill added to the bucket.
+    pub added: u64,
+    /// How much more the fill poured in than the bucket had room for. This leaves the system.
+    pub spilled: u64,
+    /// How much of the fill was held back by the fill rate limit. This never enters the system.
+    pub throttled: u64,
+    /// The quantity of the bucket after the fill.
+    pub quantity: u64,
+}
+
+impl Display for Fill {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "filled +{} to bucket {} => {}",
+            self.added, self.bucket, self.quantity
+        )?;
+        if self.spilled > 0 {
+            write!(f, " (spilled {})", self.spilled)?;
+        }
+        if self.throttled > 0 {
+            write!(f, " (throttled {})", self.throttled)?;
+        }
+        Ok(())
+    }
+}
+
+/// FillStrategy decides which bucket each fill goes to, and how much fluid it adds.
+#[derive(Clone, Copy)]
+pub enum FillStrategy {
+    /// Adds 0 or 1 on every fill.
+    Steady,
+    /// Adds nothing on most fills, but with the given probability dumps a burst of between `min`
+    /// and `max` (inclusive), modeling traffic spikes.
+    Burst {
+        probability: f64,
+        min: u64,
+        max: u64,
+    },
+    /// Adds 0 or 1 on every fill, choosing the bucket with a probability proportional to its free
+    /// capacity, so fuller buckets fill up more slowly and full buckets not at all.
+    CapacityWeighted,
+}
+
+pub trait Buckets {
+    /// Adds fluid to one or more buckets, returning what was done to each filled bucket. Fills
+    /// never take a bucket above its capacity; whatever doesn't fit is spilled. Returns nothing if
+    /// there are no buckets to fill.
+    ///
+    /// Buckets are filled, and returned, in increasing order of ID, never in hash map order, so
+    /// that buckets seeded the same way fill the same way.
+    fn fill(&mut self) -> Vec<Fill>;
+    /// Pours `amount` into the given bucket, just as if a fill had picked it to add that much.
+    /// Fails if there is no such bucket.
+    fn pour(&mut self, bucket: BucketId, amount: u64) -> Result<Fill>;
+    /// Changes the capacity of every bucket. Buckets holding more than the new capacity are
+    /// clamped to it, spilling the excess out of the system. Returns how much each clamped bucket
+    /// spilled, ordered by bucket.
+    fn set_capacity(&mut self, capacity: u64) -> Vec<(BucketId, u64)>;
+    /// Returns how much the system can hold in all, across every bucket.
+    fn total_capacity(&self) -> u64;
+    /// Returns how much has leaked out of the buckets since this was last called, for bucket types
+    /// that leak. Like spilled fluid, leaked fluid leaves the system.
+    fn take_leaked(&mut self) -> u64 {
+        0
+    }
+    fn data(&self) -> Vec<Datum>;
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
 pub enum BucketType {
+    /// A fixed set of buckets that fill up at random.
     NBuckets,
+    /// Buckets that fill up like NBuckets, but also lose --leak-rate each on every fill, modeling
+    /// evaporation.
+    LeakyBuckets,
 }
 
 impl Display for BucketType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             BucketType::NBuckets => write!(f, "NBuckets"),
+            BucketType::LeakyBuckets => write!(f, "LeakyBuckets"),
         }
     }
 }
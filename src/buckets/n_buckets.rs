@@ -1,70 +1,296 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::{anyhow, Result};
 use itertools::Itertools;
-use rand::Rng;
+use rand::seq::index;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
 
 use crate::actuator::FinalControlElement;
 use crate::sensor::Sensor;
 
-use super::{Buckets, MAX_QUANTITY};
+use super::ids::{BucketId, IdAllocator};
+use super::throttle::Throttle;
+use super::{Buckets, Datum, Fill, FillStrategy};
 
 /// NBuckets represents a fixed number set of buckets that randomly, monotonically increase in
-/// fluid quantity.
-/// TODO: Parameterize fill strategy?
+/// fluid quantity, at a pace set by its fill strategy.
 pub struct NBuckets {
-    data: HashMap<u64, u64>,
+    data: HashMap<BucketId, u64>,
+    // The most any single bucket can hold. Transfers never take a bucket above it.
+    capacity: u64,
+    fill: FillStrategy,
+    fanout: Option<usize>,
+    // Limits how much inflow each bucket can receive, if set.
+    throttle: Option<Throttle>,
+    // User-defined categories of some of the buckets, e.g. "source" or "sink".
+    tags: HashMap<BucketId, String>,
+    // Buckets no transfer may touch.
+    readonly: HashSet<BucketId>,
+    // Whether fills still go to read-only buckets.
+    fill_readonly: bool,
+    ids: IdAllocator,
+    // The same generator as rand's StdRng, but one whose position in its sequence can be saved.
+    rng: ChaCha12Rng,
 }
 
 impl NBuckets {
-    pub fn new(data: HashMap<u64, u64>) -> NBuckets {
-        NBuckets { data }
+    /// Creates the buckets, each able to hold up to `capacity`, seeding the fill RNG with `seed` if
+    /// given and from entropy otherwise. With a `fanout`, every fill goes to that many distinct
+    /// buckets instead of a single one. With a `throttle`, fills only add as much as the throttle
+    /// lets through.
+    pub fn new(
+        data: HashMap<BucketId, u64>,
+        capacity: u64,
+        fill: FillStrategy,
+        fanout: Option<usize>,
+        throttle: Option<Throttle>,
+        tags: HashMap<BucketId, String>,
+        seed: Option<u64>,
+    ) -> NBuckets {
+        let rng = match seed {
+            Some(seed) => ChaCha12Rng::seed_from_u64(seed),
+            None => ChaCha12Rng::from_os_rng(),
+        };
+        let ids = IdAllocator::new(data.keys().copied(), false);
+        NBuckets {
+            data,
+            capacity,
+            fill,
+            fanout,
+            throttle,
+            tags,
+            readonly: HashSet::new(),
+            fill_readonly: false,
+            ids,
+            rng,
+        }
+    }
+
+    /// Takes up to `amount` out of every bucket, emptying those that hold less, and returns how
+    /// much was taken in all.
+    pub fn drain_each(&mut self, amount: u64) -> u64 {
+        self.data
+            .values_mut()
+            .map(|quantity| {
+                let drained = amount.min(*quantity);
+                *quantity -= drained;
+                drained
+            })
+            .sum()
+    }
+
+    /// Returns the fill RNG, to save along with the bucket data.
+    pub fn rng(&self) -> &ChaCha12Rng {
+        &self.rng
+    }
+
+    /// Replaces the fill RNG with a saved one, so that fills carry on with its sequence.
+    pub fn set_rng(&mut self, rng: ChaCha12Rng) {
+        self.rng = rng;
+    }
+
+    /// Marks the given buckets as read-only, so that no transfer can touch them. Fills go to
+    /// read-only buckets only if `fill` is set.
+    pub fn set_readonly(&mut self, buckets: HashSet<BucketId>, fill: bool) {
+        self.readonly = buckets;
+        self.fill_readonly = fill;
+    }
+
+    /// Returns the buckets fills can go to, ordered by ID so that seeded runs are repeatable.
+    fn fillable(&self) -> Vec<BucketId> {
+        self.data
+            .keys()
+            .filter(|bucket| self.fill_readonly || !self.readonly.contains(bucket))
+            .copied()
+            .sorted()
+            .collect()
+    }
+
+    /// Picks a fillable bucket uniformly at random, or None if there are none.
+    fn pick_uniformly(&mut self) -> Option<BucketId> {
+        // Bucket IDs need not be contiguous (buckets may have been removed), so pick one of the
+        // existing IDs rather than a number in 1..=len.
+        let buckets = self.fillable();
+        if buckets.is_empty() {
+            return None;
+        }
+        Some(buckets[self.rng.random_range(0..buckets.len())])
+    }
+
+    /// Picks `count` distinct buckets uniformly at random, or every bucket if there are fewer.
+    fn sample_uniformly(&mut self, count: usize) -> Vec<BucketId> {
+        let buckets = self.fillable();
+        let count = count.min(buckets.len());
+        index::sample(&mut self.rng, buckets.len(), count)
+            .into_iter()
+            .map(|index| buckets[index])
+            .sorted()
+            .collect()
+    }
+
+    /// Picks a bucket with probability proportional to its free capacity, or None if every bucket
+    /// is full.
+    fn pick_by_free_capacity(&mut self) -> Option<BucketId> {
+        let free = self
+            .fillable()
+            .into_iter()
+            .map(|bucket| (bucket, self.capacity.saturating_sub(self.data[&bucket])))
+            .collect::<Vec<_>>();
+        let total = free.iter().map(|(_, free)| free).sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+        let mut target = self.rng.random_range(0..total);
+        for (bucket, free) in free {
+            if target < free {
+                return Some(bucket);
+            }
+            target -= free;
+        }
+        unreachable!("target is below the total free capacity")
+    }
+
+    /// Pours `requested` into the given bucket, which must exist, as far as the throttle and the
+    /// capacity allow.
+    fn pour_into(&mut self, bucket: BucketId, requested: u64) -> Fill {
+        let change = match &mut self.throttle {
+            Some(throttle) => throttle.admit(bucket, requested),
+            None => requested,
+        };
+        let capacity = self.capacity;
+        let value = self.data.get_mut(&bucket).expect("unreachable");
+        let added = change.min(capacity.saturating_sub(*value));
+        *value += added;
+        Fill {
+            bucket,
+            added,
+            spilled: change - added,
+            throttled: requested - change,
+            quantity: *value,
+        }
     }
 
-    fn get_bucket(&self, bucket: u64) -> Result<u64> {
+    /// Decides how much a fill adds to the given bucket.
+    fn fill_amount(&mut self, bucket: BucketId) -> u64 {
+        match self.fill {
+            FillStrategy::Steady => self.rng.random_range(0..=1) as u64,
+            FillStrategy::CapacityWeighted => {
+                let free = self.capacity.saturating_sub(self.data[&bucket]);
+                (self.rng.random_range(0..=1) as u64).min(free)
+            }
+            FillStrategy::Burst {
+                probability,
+                min,
+                max,
+            } => {
+                if self.rng.random_bool(probability) {
+                    self.rng.random_range(min..=max)
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    fn get_bucket(&self, bucket: BucketId) -> Result<u64> {
         self.data
             .get(&bucket)
-            .map(|quantity| *quantity)
+            .copied()
             .ok_or(anyhow!("no bucket @ {}", bucket))
     }
 }
 
 impl Buckets for NBuckets {
-    fn fill(&mut self) -> (u64, u64, u64) {
-        let mut rng = rand::rng();
+    fn fill(&mut self) -> Vec<Fill> {
+        if self.data.is_empty() {
+            return Vec::new();
+        }
+        if let Some(throttle) = &mut self.throttle {
+            throttle.advance();
+        }
+        let targets = match self.fanout {
+            Some(fanout) => self.sample_uniformly(fanout),
+            None => {
+                let bucket = match self.fill {
+                    FillStrategy::CapacityWeighted => self.pick_by_free_capacity(),
+                    FillStrategy::Steady | FillStrategy::Burst { .. } => None,
+                }
+                .or_else(|| self.pick_uniformly());
+                bucket.into_iter().collect()
+            }
+        };
+        targets
+            .into_iter()
+            .map(|bucket| {
+                let requested = self.fill_amount(bucket);
+                self.pour_into(bucket, requested)
+            })
+            .collect()
+    }
 
-        let bucket = rng.random_range(1..=self.data.len()) as u64;
+    fn pour(&mut self, bucket: BucketId, amount: u64) -> Result<Fill> {
+        self.get_bucket(bucket)?;
+        Ok(self.pour_into(bucket, amount))
+    }
 
-        let change = rng.random_range(0..=1) as u64;
-        let value = self.data.get_mut(&bucket).expect("unreachable");
-        *value = value.saturating_add(change);
+    fn set_capacity(&mut self, capacity: u64) -> Vec<(BucketId, u64)> {
+        self.capacity = capacity;
+        self.data
+            .iter_mut()
+            .filter(|(_, quantity)| **quantity > capacity)
+            .map(|(bucket, quantity)| {
+                let spilled = *quantity - capacity;
+                *quantity = capacity;
+                (*bucket, spilled)
+            })
+            .sorted()
+            .collect()
+    }
 
-        (bucket, change, *value)
+    fn total_capacity(&self) -> u64 {
+        // Every bucket shares the same capacity.
+        (self.data.len() as u64).saturating_mul(self.capacity)
     }
 
-    fn data(&self) -> Vec<(String, u64)> {
+    fn data(&self) -> Vec<Datum> {
         self.data
             .iter()
             .sorted()
-            .map(|(name, val)| (format!("B{}", name), *val))
+            .map(|(name, val)| Datum {
+                bucket: *name,
+                label: format!("B{}", name),
+                quantity: *val,
+                tag: self.tags.get(name).cloned(),
+            })
             .collect()
     }
 }
 
 impl Sensor for NBuckets {
-    fn buckets(&self) -> &HashMap<u64, u64> {
+    fn buckets(&self) -> &HashMap<BucketId, u64> {
         &self.data
     }
 
-    fn get_bucket_quantity(&self, bucket: u64) -> Result<u64> {
+    fn get_bucket_quantity(&self, bucket: BucketId) -> Result<u64> {
         self.get_bucket(bucket)
     }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
 }
 
 impl FinalControlElement for NBuckets {
-    fn transfer(&mut self, source: u64, destination: u64, amount: u64) -> Result<()> {
+    fn can_transfer(&self, source: BucketId, destination: BucketId, amount: u64) -> Result<()> {
         let source_amount = self.get_bucket(source)?;
         let destination_amount = self.get_bucket(destination)?;
+        if let Some(bucket) = [source, destination]
+            .into_iter()
+            .find(|bucket| self.readonly.contains(bucket))
+        {
+            return Err(anyhow!("bucket {} is read-only", bucket));
+        }
         if source_amount < amount {
             return Err(anyhow!(
                 "transfer amount exceeds source amount ({} > {})",
@@ -73,25 +299,272 @@ impl FinalControlElement for NBuckets {
             ));
         }
 
-        if destination_amount + amount > MAX_QUANTITY {
+        let new_destination_amount = destination_amount.checked_add(amount).ok_or_else(|| {
+            anyhow!(
+                "destination amount would overflow in transfer ({} + {})",
+                destination_amount,
+                amount
+            )
+        })?;
+        if new_destination_amount > self.capacity {
             return Err(anyhow!(
                 "destination amount is too large for transfer ({} + {} > {})",
                 destination_amount,
                 amount,
-                MAX_QUANTITY
+                self.capacity
             ));
         }
 
-        let new_source_amount = source_amount - amount;
-        let new_destination_amount = destination_amount + amount;
+        Ok(())
+    }
+
+    fn transfer(&mut self, source: BucketId, destination: BucketId, amount: u64) -> Result<()> {
+        self.can_transfer(source, destination, amount)?;
+
+        // The transfer was validated above, so neither of these can wrap.
+        *self.data.get_mut(&source).expect("unreachable") -= amount;
+        *self.data.get_mut(&destination).expect("unreachable") += amount;
+
+        Ok(())
+    }
+
+    fn add_bucket(&mut self) -> Result<BucketId> {
+        let bucket = self.ids.allocate()?;
+        self.data.insert(bucket, 0);
+        Ok(bucket)
+    }
+
+    fn remove_bucket(&mut self, bucket: BucketId) -> Result<u64> {
+        let quantity = self
+            .data
+            .remove(&bucket)
+            .ok_or(anyhow!("no bucket @ {}", bucket))?;
+        self.tags.remove(&bucket);
+        self.readonly.remove(&bucket);
+        self.ids.free(bucket);
+        Ok(quantity)
+    }
+
+    fn bucket_count(&self) -> usize {
+        self.data.len()
+    }
+
+    fn is_readonly(&self, bucket: BucketId) -> bool {
+        self.readonly.contains(&bucket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn n_buckets(data: &[(u64, u64)], capacity: u64, seed: u64) -> NBuckets {
+        let data = data
+            .iter()
+            .map(|&(id, value)| (BucketId(id), value))
+            .collect();
+        NBuckets::new(
+            data,
+            capacity,
+            FillStrategy::Steady,
+            None,
+            None,
+            HashMap::new(),
+            Some(seed),
+        )
+    }
 
-        self.data.insert(source, new_source_amount);
-        self.data.insert(destination, new_destination_amount);
+    #[test]
+    fn total_capacity_is_count_times_capacity() {
+        let mut buckets = n_buckets(&[(1, 45), (2, 72), (3, 38)], 100, 0);
+        assert_eq!(buckets.total_capacity(), 300);
+        buckets.set_capacity(80);
+        assert_eq!(buckets.total_capacity(), 240);
+        assert_eq!(n_buckets(&[], 100, 0).total_capacity(), 0);
+        assert_eq!(
+            n_buckets(&[(1, 0), (2, 0)], u64::MAX, 0).total_capacity(),
+            u64::MAX
+        );
+    }
 
+    #[test]
+    fn add_bucket_adds_distinct_empty_buckets() -> Result<()> {
+        let mut buckets = n_buckets(&[(1, 45), (2, 72)], 100, 0);
+        let first = buckets.add_bucket()?;
+        let second = buckets.add_bucket()?;
+        assert_ne!(first, second);
+        assert!(![BucketId(1), BucketId(2)].contains(&first));
+        assert!(![BucketId(1), BucketId(2)].contains(&second));
+        assert_eq!(buckets.buckets()[&first], 0);
+        assert_eq!(buckets.buckets()[&second], 0);
+        assert_eq!(buckets.buckets().len(), 4);
         Ok(())
     }
 
-    fn add_bucket(&mut self) -> Result<u64> {
-        Err(anyhow!("not implemented for type"))
+    #[test]
+    fn can_transfer_fails_as_transfer_would() {
+        // Checks that previewing the transfer fails just as making it does, leaving the buckets as
+        // they were.
+        let check = |buckets: &mut NBuckets, source, destination, amount| {
+            let (source, destination) = (BucketId(source), BucketId(destination));
+            let before = buckets.buckets().clone();
+            let preview = buckets
+                .can_transfer(source, destination, amount)
+                .unwrap_err();
+            let error = buckets.transfer(source, destination, amount).unwrap_err();
+            assert_eq!(preview.to_string(), error.to_string());
+            assert_eq!(*buckets.buckets(), before);
+        };
+        let mut buckets = n_buckets(&[(1, 45), (2, 72), (3, 38)], 100, 0);
+        buckets.set_readonly(HashSet::from([BucketId(3)]), false);
+        check(&mut buckets, 1, 4, 10);
+        check(&mut buckets, 1, 3, 10);
+        check(&mut buckets, 1, 2, 50);
+        check(&mut buckets, 2, 1, 60);
+        check(
+            &mut n_buckets(&[(1, 10), (2, u64::MAX - 5)], u64::MAX, 0),
+            1,
+            2,
+            10,
+        );
+
+        // A feasible transfer is previewed without being made.
+        assert!(buckets.can_transfer(BucketId(1), BucketId(2), 28).is_ok());
+        assert_eq!(buckets.buckets()[&BucketId(1)], 45);
+    }
+
+    #[test]
+    fn same_seed_fills_the_same() {
+        let fills = |seed| {
+            let mut buckets = n_buckets(&[(1, 0), (2, 0), (3, 0)], 100, seed);
+            (0..50)
+                .flat_map(|_| buckets.fill())
+                .map(|fill| fill.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(fills(42), fills(42));
+        assert_ne!(fills(42), fills(43));
+    }
+
+    #[test]
+    fn bursts_follow_their_probability_and_sizes() {
+        let mut buckets = NBuckets::new(
+            HashMap::from([(BucketId(1), 0), (BucketId(2), 0)]),
+            u64::MAX,
+            FillStrategy::Burst {
+                probability: 0.1,
+                min: 10,
+                max: 30,
+            },
+            None,
+            None,
+            HashMap::new(),
+            Some(0),
+        );
+        let bursts = (0..10_000)
+            .flat_map(|_| buckets.fill())
+            .map(|fill| fill.added)
+            .filter(|added| *added > 0)
+            .collect::<Vec<_>>();
+        // 1000 bursts are expected, give or take about 30.
+        assert!(
+            (900..=1100).contains(&bursts.len()),
+            "{} bursts",
+            bursts.len()
+        );
+        assert!(bursts.iter().all(|burst| (10..=30).contains(burst)));
+        assert!(bursts.contains(&10) && bursts.contains(&30));
+    }
+
+    #[test]
+    fn capacity_weighted_fills_favor_emptier_buckets() {
+        let inflow = |fill| {
+            let mut buckets = NBuckets::new(
+                HashMap::from([(BucketId(1), 990), (BucketId(2), 0)]),
+                1000,
+                fill,
+                None,
+                None,
+                HashMap::new(),
+                Some(0),
+            );
+            let mut added = HashMap::<BucketId, u64>::new();
+            for fill in (0..1000).flat_map(|_| buckets.fill()) {
+                *added.entry(fill.bucket).or_default() += fill.added;
+            }
+            (added[&BucketId(1)], added[&BucketId(2)])
+        };
+        // The nearly full bucket has a hundredth of the free capacity of the empty one, at first.
+        let (nearly_full, empty) = inflow(FillStrategy::CapacityWeighted);
+        assert!(nearly_full * 10 < empty, "{} vs {}", nearly_full, empty);
+        // Whereas steady fills pick either bucket as often.
+        let (nearly_full, empty) = inflow(FillStrategy::Steady);
+        assert_eq!(nearly_full, 10);
+        assert!(empty > 200, "{}", empty);
+    }
+
+    #[test]
+    fn seeded_fills_process_buckets_in_the_same_order() {
+        // Returns the order every fill of the first 50 processed the buckets in. The buckets are
+        // inserted in a different order every time, so the map iterates them differently too.
+        let order = |rotation| {
+            let mut ids = (1..=10).collect::<Vec<u64>>();
+            ids.rotate_left(rotation);
+            let mut buckets = NBuckets::new(
+                ids.into_iter()
+                    .map(|bucket| (BucketId(bucket), 0))
+                    .collect(),
+                u64::MAX,
+                FillStrategy::Steady,
+                Some(4),
+                None,
+                HashMap::new(),
+                Some(42),
+            );
+            (0..50)
+                .map(|_| {
+                    buckets
+                        .fill()
+                        .iter()
+                        .map(|fill| fill.bucket)
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = order(1);
+        assert_eq!(order(2), first);
+        assert_eq!(order(7), first);
+        assert!(first.iter().all(|processed| processed.is_sorted()));
+    }
+
+    #[test]
+    fn fanout_fills_exactly_that_many_buckets() {
+        for fanout in [1, 3, 10] {
+            // Bursts that always happen, so every bucket a fill goes to changes.
+            let mut buckets = NBuckets::new(
+                (1..=10).map(|bucket| (BucketId(bucket), 0)).collect(),
+                u64::MAX,
+                FillStrategy::Burst {
+                    probability: 1.0,
+                    min: 1,
+                    max: 5,
+                },
+                Some(fanout),
+                None,
+                HashMap::new(),
+                Some(0),
+            );
+            for _ in 0..100 {
+                let before = buckets.buckets().clone();
+                let fills = buckets.fill();
+                let changed = buckets
+                    .buckets()
+                    .iter()
+                    .filter(|(bucket, quantity)| before[bucket] != **quantity)
+                    .count();
+                assert_eq!(fills.len(), fanout);
+                assert_eq!(changed, fanout);
+            }
+        }
     }
 }
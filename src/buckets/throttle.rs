@@ -0,0 +1,79 @@
+//! A throttle models a rate-limited source: no bucket can receive more than a fixed amount of
+//! inflow over the last few fill ticks. Whatever a fill would add beyond that is held back at the
+//! source, so unlike a spill, it never enters the system in the first place. Once enough ticks have
+//! passed for the window to slide past earlier inflow, the bucket can be filled again.
+
+use std::collections::{HashMap, VecDeque};
+
+use super::ids::BucketId;
+
+pub struct Throttle {
+    // The most inflow any bucket can receive over the window.
+    limit: u64,
+    // How many fill ticks the window spans, including the current one.
+    window: usize,
+    // The inflow every bucket received on each tick in the window, oldest first.
+    recent: VecDeque<HashMap<BucketId, u64>>,
+}
+
+impl Throttle {
+    pub fn new(limit: u64, window: usize) -> Self {
+        Throttle {
+            limit,
+            window,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Starts a new fill tick, sliding the window past the oldest one if it's full.
+    pub fn advance(&mut self) {
+        if self.recent.len() == self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(HashMap::new());
+    }
+
+    /// Lets through as much of `amount` as the bucket's remaining allowance permits, counting it
+    /// against that allowance. Returns how much was let through.
+    pub fn admit(&mut self, bucket: BucketId, amount: u64) -> u64 {
+        let received = self
+            .recent
+            .iter()
+            .filter_map(|inflow| inflow.get(&bucket))
+            .sum::<u64>();
+        let admitted = amount.min(self.limit.saturating_sub(received));
+        if let Some(current) = self.recent.back_mut() {
+            *current.entry(bucket).or_default() += admitted;
+        }
+        admitted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttled_buckets_refill_once_the_window_slides() {
+        // At most 10 over any 3 ticks.
+        let mut throttle = Throttle::new(10, 3);
+        let mut admitted = Vec::new();
+        for _ in 0..6 {
+            throttle.advance();
+            admitted.push(throttle.admit(BucketId(1), 4));
+        }
+        assert_eq!(admitted, [4, 4, 2, 4, 4, 2]);
+
+        // Every bucket has its own allowance.
+        let mut throttle = Throttle::new(10, 3);
+        throttle.advance();
+        assert_eq!(throttle.admit(BucketId(1), 15), 10);
+        assert_eq!(throttle.admit(BucketId(1), 5), 0);
+        assert_eq!(throttle.admit(BucketId(2), 5), 5);
+        throttle.advance();
+        throttle.advance();
+        assert_eq!(throttle.admit(BucketId(1), 5), 0);
+        throttle.advance();
+        assert_eq!(throttle.admit(BucketId(1), 5), 5);
+    }
+}
@@ -1,9 +1,23 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-use clap::Parser;
+use anyhow::{bail, Result};
+use chrono::format::StrftimeItems;
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, Parser, ValueEnum};
+use itertools::Itertools;
 
-use crate::buckets::BucketType;
-use crate::policy::Policy;
+use crate::actuator::BatchOrder;
+use crate::buckets::ids::BucketId;
+use crate::buckets::throttle::Throttle;
+use crate::buckets::{BucketType, FillStrategy, MAX_QUANTITY};
+use crate::controller::Schedule;
+use crate::events::DEFAULT_CAPACITY;
+use crate::overflow::AutoGrow;
+use crate::policy::{ObjectiveWeights, PidGains, Policy, PolicyParams};
 
 #[derive(Parser, Clone)]
 #[command(author, version, about, long_about = None)]
@@ -16,29 +30,551 @@ pub struct Args {
     #[arg(short, long, value_enum, default_value_t = Policy::NoOp)]
     pub policy: Policy,
 
+    /// File to read the policy and its parameters from instead, re-read every
+    /// --policy-reload-interval so that they can be changed while running. See policy_file.rs for
+    /// the format.
+    #[arg(long)]
+    pub policy_file: Option<PathBuf>,
+
+    /// How often to re-read --policy-file (ms).
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 1000)]
+    pub policy_reload_interval: u64,
+
+    /// File with a timeline of steps to take at given ticks, such as fills and policy switches. See
+    /// script.rs for the format.
+    #[arg(long)]
+    pub script: Option<PathBuf>,
+
+    /// Quantity at or above which the EmergencyDrain policy drains a bucket.
+    #[arg(long, default_value_t = 90)]
+    pub critical_level: u64,
+
+    /// Quantity the EmergencyDrain policy drains a critical bucket down to.
+    #[arg(long, default_value_t = 50)]
+    pub safe_level: u64,
+
+    /// Weight of the Weighted policy's balance objective (variance of the quantities).
+    #[arg(long, value_parser = parse_non_negative, default_value_t = 1.0)]
+    pub balance_weight: f64,
+
+    /// Weight of the Weighted policy's cap objective (total quantity above --soft-cap).
+    #[arg(long, value_parser = parse_non_negative, default_value_t = 1.0)]
+    pub cap_weight: f64,
+
+    /// Weight of the Weighted policy's priority objective (total quantity in
+    /// --priority-buckets).
+    #[arg(long, value_parser = parse_non_negative, default_value_t = 0.0)]
+    pub priority_weight: f64,
+
+    /// Quantity above which a bucket counts against the Weighted policy's cap objective.
+    #[arg(long, default_value_t = 80)]
+    pub soft_cap: u64,
+
+    /// Comma-separated IDs of buckets the Weighted policy tries to keep empty.
+    #[arg(long, value_delimiter = ',')]
+    pub priority_buckets: Vec<BucketId>,
+
+    /// Proportional gain of the PID policy, applied to how far below the mean a bucket is.
+    #[arg(long, value_parser = parse_non_negative, default_value_t = 0.5)]
+    pub kp: f64,
+
+    /// Integral gain of the PID policy, applied to a bucket's error summed over every analysis.
+    #[arg(long, value_parser = parse_non_negative, default_value_t = 0.05)]
+    pub ki: f64,
+
+    /// Derivative gain of the PID policy, applied to how much a bucket's error changed since the
+    /// last analysis.
+    #[arg(long, value_parser = parse_non_negative, default_value_t = 0.0)]
+    pub kd: f64,
+
+    /// Quantity the Target policy drives every bucket to.
+    #[arg(long, required_if_eq("policy", "target"))]
+    pub target: Option<u64>,
+
+    /// Order the transfers of a multi-transfer are applied in. When buckets are nearly full, a
+    /// batch may only fit in some orders; see BatchOrder in actuator.rs for the tradeoffs.
+    #[arg(long, value_enum, default_value_t = BatchOrder::AsEmitted)]
+    pub batch_order: BatchOrder,
+
+    /// Bucket the Reserve policy keeps topped up.
+    #[arg(long, required_if_eq("policy", "reserve"))]
+    pub reserve_bucket: Option<BucketId>,
+
+    /// Quantity the Reserve policy keeps the reserve bucket at or above.
+    #[arg(long, default_value_t = 50)]
+    pub reserve_level: u64,
+
+    /// Number of analyses a bucket is left out of after taking part in a transfer, so that
+    /// policies can't thrash a single bucket.
+    #[arg(long, default_value_t = 0)]
+    pub cooldown: u64,
+
+    /// The capacity of every bucket, i.e. the most any single bucket can hold. It can still be
+    /// changed while running.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = MAX_QUANTITY)]
+    pub max_quantity: u64,
+
+    /// How much every bucket loses on every fill, with --bucket-type=leaky-buckets.
+    #[arg(long, default_value_t = 1)]
+    pub leak_rate: u64,
+
     /// Initial data in format "id1:value1,id2:value2,...".
-    #[arg(short, long, value_parser = parse_initial_data, default_value = "1:45,2:72,3:38")]
-    pub initial_data: HashMap<u64, u64>,
+    #[arg(short, long, value_parser = parse_initial_pairs, default_value = "1:45,2:72,3:38")]
+    pub initial_data: InitialData,
+
+    /// File to read the initial data from instead, as CSV ("id,value" rows) or a JSON object
+    /// ({"id": value, ...}). The format is picked by the .csv or .json extension.
+    #[arg(long, value_parser = parse_initial_data_file, conflicts_with = "initial_data")]
+    pub initial_data_file: Option<InitialData>,
 
-    /// Controller loop latency (ms).
-    #[arg(short, long, default_value_t = 1000)]
+    /// Sum the values of bucket IDs that appear more than once in the initial data, instead of
+    /// rejecting them.
+    #[arg(long)]
+    pub merge_duplicates: bool,
+
+    /// Names to show for buckets in the chart, in format "id1:name1,id2:name2,...". Press 'n' in
+    /// the TUI to toggle between names and raw bucket IDs.
+    #[arg(long, value_parser = parse_bucket_name, value_delimiter = ',')]
+    pub bucket_names: Vec<(BucketId, String)>,
+
+    /// Categories to tag buckets with, in format "id1:tag1,id2:tag2,...", e.g. "1:source,2:sink".
+    /// Tags are shown next to the bucket labels in the chart.
+    #[arg(long, value_parser = parse_bucket_tag, value_delimiter = ',')]
+    pub bucket_tags: Vec<(BucketId, String)>,
+
+    /// Comma-separated IDs of buckets to pin as read-only, e.g. "3" or "1,3". No transfer may touch
+    /// them, so policies leave them alone, and they model fixed references. They aren't filled
+    /// either, unless --fill-readonly is given.
+    #[arg(long, value_delimiter = ',')]
+    pub readonly: Vec<BucketId>,
+
+    /// Keep filling read-only buckets.
+    #[arg(long, requires = "readonly")]
+    pub fill_readonly: bool,
+
+    /// Fill in bursts: most fills add nothing, but some dump a large burst into a bucket.
+    #[arg(long)]
+    pub fill_burst: bool,
+
+    /// Fill buckets in proportion to their free capacity, so fuller buckets fill more slowly.
+    #[arg(long, conflicts_with = "fill_burst")]
+    pub fill_capacity_weighted: bool,
+
+    /// Number of distinct buckets, picked at random, that every fill goes to. By default, each
+    /// fill goes to a single bucket.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "fill_capacity_weighted")]
+    pub fill_fanout: Option<u64>,
+
+    /// Most inflow any single bucket can receive over the last --fill-rate-window fill ticks,
+    /// modeling a throttled source. Fills beyond it are held back until the window slides.
+    #[arg(long)]
+    pub fill_rate_limit: Option<u64>,
+
+    /// Grow the capacity of the buckets by this factor every time a bucket overflows it, modeling
+    /// elastic storage, up to --auto-grow-max.
+    #[arg(long, value_parser = parse_growth_factor)]
+    pub auto_grow: Option<f64>,
+
+    /// The capacity --auto-grow never grows past.
+    #[arg(long, default_value_t = 1000, requires = "auto_grow")]
+    pub auto_grow_max: u64,
+
+    /// Number of fill ticks, including the current one, that --fill-rate-limit applies over.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 10)]
+    pub fill_rate_window: u64,
+
+    /// Probability that a fill is a burst, with --fill-burst.
+    #[arg(long, value_parser = parse_probability, default_value_t = 0.05)]
+    pub burst_prob: f64,
+
+    /// Range of burst sizes with --fill-burst, in format "min-max" (inclusive).
+    #[arg(long, value_parser = parse_burst_size, default_value = "10-30")]
+    pub burst_size: (u64, u64),
+
+    /// Start with the controller in manual override, so it takes no actions of its own and the
+    /// buckets only change through fills and manual commands. Press 'm' in the TUI to toggle it.
+    #[arg(long)]
+    pub manual: bool,
+
+    /// When the controller analyzes the sensor data.
+    #[arg(long, value_enum, default_value_t = Schedule::Periodic)]
+    pub controller_schedule: Schedule,
+
+    /// Total fill since the last analysis that triggers the next one, with
+    /// --controller-schedule=on-change.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 5)]
+    pub change_threshold: u64,
+
+    /// Controller loop latency (ms), with --controller-schedule=periodic. At least 1, so the loop
+    /// never spins.
+    #[arg(short, long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 1000)]
     pub controller_latency: u64,
 
-    /// Actuator loop latency (ms).
-    #[arg(short, long, default_value_t = 1000)]
+    /// Actuator loop latency (ms). At least 1, so the loop never spins.
+    #[arg(short, long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 1000)]
     pub actuator_latency: u64,
 
-    /// Fill loop latency (ms).
-    #[arg(short, long, default_value_t = 1000)]
+    /// Fill loop latency (ms). At least 1, so the loop never spins.
+    #[arg(short, long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 1000)]
     pub fill_latency: u64,
+
+    /// End the run after this many fill ticks, printing the final bucket state. Ticks spent paused
+    /// don't count.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_ticks: Option<u64>,
+
+    /// Standard deviation of the Gaussian noise added to the values policies sense. The true
+    /// bucket quantities are unaffected.
+    #[arg(long, value_parser = parse_non_negative, default_value_t = 0.0)]
+    pub sensor_noise: f64,
+
+    /// Offsets added to the values policies sense for some buckets, in format
+    /// "id1:offset1,id2:offset2,...", e.g. "1:+2,2:-1". The true bucket quantities are unaffected.
+    #[arg(long, value_parser = parse_calibration, value_delimiter = ',', allow_hyphen_values = true)]
+    pub calibration: Vec<(BucketId, i64)>,
+
+    /// Largest difference between the fullest and emptiest buckets that still counts as balanced.
+    /// The Spread policy stops acting once the buckets are balanced. Press '[' and ']' in the TUI
+    /// to tighten and loosen it.
+    #[arg(long, default_value_t = 1)]
+    pub balance_tolerance: u64,
+
+    /// Number of recent ticks the TUI reports how long the buckets were balanced for.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 20)]
+    pub convergence_window: u64,
+
+    /// Number of fill ticks the bucket quantities are kept in history for.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 600)]
+    pub history_size: u64,
+
+    /// Minimum number of buckets the actuator keeps when asked to remove buckets.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 1)]
+    pub min_buckets: u64,
+
+    /// Briefly highlight the buckets involved in each applied transfer.
+    #[arg(long)]
+    pub animate_transfers: bool,
+
+    /// Grow the bars from zero to the initial quantities over the first couple of seconds, rather
+    /// than showing them right away.
+    #[arg(long)]
+    pub settle: bool,
+
+    /// JSON file mapping TUI elements to colors, overriding the default palette.
+    #[arg(long)]
+    pub theme_from_file: Option<PathBuf>,
+
+    /// File to write a JSON summary of the run to on exit, including how many ticks each bucket
+    /// took to settle within --balance-tolerance of the mean for good, and how evenly transfers
+    /// were spread across the buckets.
+    #[arg(long)]
+    pub summary_file: Option<PathBuf>,
+
+    /// File to resume the bucket state from, which takes the place of --initial-data if it exists.
+    /// The final state is saved to it on exit, along with the fill RNG, so that a resumed run
+    /// carries on with the same fills. See state_store.rs for the format.
+    #[arg(long)]
+    pub state_file: Option<PathBuf>,
+
+    /// Don't capture the mouse, leaving text selection (e.g. to copy from the event log) to the
+    /// terminal.
+    #[arg(long)]
+    pub no_mouse: bool,
+
+    /// Write every event to the given file as it happens.
+    #[arg(long)]
+    pub event_log_file: Option<PathBuf>,
+
+    /// How many of the most recent events the event log keeps, older ones being dropped as new
+    /// ones arrive. The exporters, like `--event-log-file`, still see every event.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = DEFAULT_CAPACITY as u64)]
+    pub event_log_capacity: u64,
+
+    /// Abort the run with a nonzero exit on the first event of Warn severity or worse, e.g. a
+    /// failed transfer or a conservation discrepancy. Meant for CI, where these should never
+    /// happen.
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Format of event timestamps, as a strftime string (e.g. "%Y-%m-%d %H:%M:%S%.3f"), in both
+    /// the TUI and the event log file. By default, the TUI shows "%H:%M:%S" and the file RFC 3339.
+    #[arg(long, value_parser = parse_timestamp_format)]
+    pub timestamp_format: Option<String>,
+
+    /// Save every frame the TUI draws as a numbered text file in the given directory.
+    #[arg(long)]
+    pub record_frames: Option<PathBuf>,
+
+    /// Text of the banner event that opens the event log, ahead of the run's ID and configuration.
+    #[arg(long, default_value = "buckets started")]
+    pub banner: String,
+
+    /// Print the bucket quantities and the most recent events to stderr whenever the process
+    /// receives SIGUSR1 (Unix only).
+    #[arg(long)]
+    pub dump_state_on_signal: bool,
+
+    /// Serve Prometheus-style metrics over HTTP on this port, on localhost: every bucket's
+    /// quantity, and counts of transfers, NoAction decisions and events.
+    #[arg(long)]
+    pub metrics_port: Option<u16>,
+
+    /// Seed for the fill, policy and sensor noise RNGs. When absent, they are seeded from entropy.
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Run headless and record the run as a regression fixture at the given path.
+    #[arg(long, conflicts_with = "verify_fixture")]
+    pub record_fixture: Option<PathBuf>,
+
+    /// Run headless and check that the fixture at the given path is reproduced exactly.
+    #[arg(long)]
+    pub verify_fixture: Option<PathBuf>,
+
+    /// Run headless, twice, and check that both runs log the same events and end in the same state,
+    /// and that resuming from a state file halfway through doesn't change the fills.
+    #[arg(long, conflicts_with_all = ["record_fixture", "verify_fixture"])]
+    pub verify_determinism: bool,
+
+    /// Run headless this many times, each run with its own seed, and print a summary of how the
+    /// runs went.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = ["record_fixture", "verify_fixture", "verify_determinism"])]
+    pub runs: Option<u64>,
+
+    /// Run headless up to the given tick (the first, if none is given), and print a step-by-step
+    /// trace of its control cycle: the fill, the sensor snapshot, the policy's decision and the
+    /// resulting changes to the buckets.
+    #[arg(long, num_args = 0..=1, default_missing_value = "1", value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = ["record_fixture", "verify_fixture", "verify_determinism", "runs"])]
+    pub explain: Option<u64>,
+
+    /// Number of ticks to record with --record-fixture, or to run with --verify-determinism or
+    /// --runs.
+    #[arg(long, default_value_t = 50)]
+    pub fixture_ticks: u64,
+
+    /// Print the available policies and exit.
+    #[arg(long)]
+    pub list_policies: bool,
+
+    /// Print the available bucket types and exit.
+    #[arg(long)]
+    pub list_bucket_types: bool,
+
+    /// Run headless, timing how long every policy takes to analyze bucket sets of each of
+    /// --bench-sizes, and print the results.
+    #[arg(long)]
+    pub bench_policies: bool,
+
+    /// Comma-separated bucket set sizes to time with --bench-policies.
+    #[arg(long, value_delimiter = ',', default_value = "10,100,1000")]
+    pub bench_sizes: Vec<usize>,
+
+    /// Also print the effective configuration to stderr at startup.
+    #[arg(long)]
+    pub dump_config: bool,
+}
+
+/// InitialData is the initial bucket data as given, in order. The same bucket ID may appear more
+/// than once; `Args::initial_state` decides whether that's allowed.
+#[derive(Clone)]
+pub struct InitialData(Vec<(BucketId, u64)>);
+
+fn parse_non_negative(s: &str) -> Result<f64, String> {
+    let value = f64::from_str(s.trim()).map_err(|e| format!("Invalid number: {}", e))?;
+    if !value.is_finite() || value < 0.0 {
+        return Err(format!("Must be a finite, non-negative number: {}", value));
+    }
+    Ok(value)
+}
+
+fn parse_probability(s: &str) -> Result<f64, String> {
+    let value = f64::from_str(s.trim()).map_err(|e| format!("Invalid number: {}", e))?;
+    if !(0.0..=1.0).contains(&value) {
+        return Err(format!("Must be a probability between 0 and 1: {}", value));
+    }
+    Ok(value)
+}
+
+fn parse_growth_factor(s: &str) -> Result<f64, String> {
+    let value = f64::from_str(s.trim()).map_err(|e| format!("Invalid number: {}", e))?;
+    if !(value > 1.0 && value.is_finite()) {
+        return Err(format!("Must be a growth factor above 1: {}", value));
+    }
+    Ok(value)
+}
+
+fn parse_burst_size(s: &str) -> Result<(u64, u64), String> {
+    let Some((min, max)) = s.split_once('-') else {
+        return Err(format!("Invalid format for range: {}", s));
+    };
+    let min = u64::from_str(min.trim()).map_err(|e| format!("Invalid minimum: {}", e))?;
+    let max = u64::from_str(max.trim()).map_err(|e| format!("Invalid maximum: {}", e))?;
+    if min > max {
+        return Err(format!("Minimum exceeds maximum: {} > {}", min, max));
+    }
+    Ok((min, max))
+}
+
+impl Args {
+    /// Returns the bucket data to start with, from whichever of --initial-data and
+    /// --initial-data-file was given. A bucket ID may only appear more than once with
+    /// --merge-duplicates, which sums its values. Fails if any bucket would start out holding more
+    /// than --max-quantity.
+    pub fn initial_state(&self) -> Result<HashMap<BucketId, u64>> {
+        let InitialData(pairs) = self
+            .initial_data_file
+            .as_ref()
+            .unwrap_or(&self.initial_data);
+        let mut data = HashMap::new();
+        for (id, value) in pairs {
+            if self.merge_duplicates {
+                let total = data.entry(*id).or_insert(0u64);
+                *total = total.saturating_add(*value);
+            } else if data.insert(*id, *value).is_some() {
+                bail!(
+                    "bucket {} appears more than once in the initial data (pass --merge-duplicates to sum its values)",
+                    id
+                );
+            }
+        }
+        check_capacity(&data, self.max_quantity)?;
+        Ok(data)
+    }
+
+    pub fn fill_strategy(&self) -> FillStrategy {
+        if self.fill_capacity_weighted {
+            return FillStrategy::CapacityWeighted;
+        }
+        if !self.fill_burst {
+            return FillStrategy::Steady;
+        }
+        let (min, max) = self.burst_size;
+        FillStrategy::Burst {
+            probability: self.burst_prob,
+            min,
+            max,
+        }
+    }
+
+    pub fn readonly(&self) -> HashSet<BucketId> {
+        self.readonly.iter().copied().collect()
+    }
+
+    pub fn fill_fanout(&self) -> Option<usize> {
+        self.fill_fanout.map(|fanout| fanout as usize)
+    }
+
+    pub fn auto_grow(&self) -> Option<AutoGrow> {
+        self.auto_grow.map(|factor| AutoGrow {
+            factor,
+            max: self.auto_grow_max,
+        })
+    }
+
+    pub fn fill_throttle(&self) -> Option<Throttle> {
+        self.fill_rate_limit
+            .map(|limit| Throttle::new(limit, self.fill_rate_window as usize))
+    }
+
+    pub fn calibration_offsets(&self) -> HashMap<BucketId, i64> {
+        self.calibration.iter().copied().collect()
+    }
+
+    pub fn bucket_names(&self) -> HashMap<BucketId, String> {
+        self.bucket_names.iter().cloned().collect()
+    }
+
+    pub fn bucket_tags(&self) -> HashMap<BucketId, String> {
+        self.bucket_tags.iter().cloned().collect()
+    }
+
+    pub fn policy_params(&self) -> PolicyParams {
+        PolicyParams {
+            critical_level: self.critical_level,
+            safe_level: self.safe_level,
+            weights: ObjectiveWeights {
+                balance: self.balance_weight,
+                cap: self.cap_weight,
+                priority: self.priority_weight,
+            },
+            soft_cap: self.soft_cap,
+            priority_buckets: self.priority_buckets.iter().copied().collect(),
+            reserve_bucket: self.reserve_bucket,
+            reserve_level: self.reserve_level,
+            cooldown: self.cooldown,
+            balance_tolerance: self.balance_tolerance,
+            gains: PidGains {
+                kp: self.kp,
+                ki: self.ki,
+                kd: self.kd,
+            },
+            target: self.target,
+            batch_order: self.batch_order,
+        }
+    }
+}
+
+/// Lists every variant of a command-line enum as it's spelled on the command line, one per line,
+/// along with its description.
+pub fn list_variants<T: ValueEnum>() -> String {
+    let variants = T::value_variants()
+        .iter()
+        .filter_map(|variant| variant.to_possible_value())
+        .collect::<Vec<_>>();
+    let width = variants
+        .iter()
+        .map(|value| value.get_name().len())
+        .max()
+        .unwrap_or(0);
+    variants
+        .iter()
+        .map(|value| {
+            let help = value.get_help().map(|help| help.to_string());
+            format!(
+                "{:width$}  {}",
+                value.get_name(),
+                help.unwrap_or_default(),
+                width = width
+            )
+        })
+        .join("\n")
+}
+
+/// Setting is a single resolved configuration value, as it would be given on the command line.
+pub struct Setting {
+    pub flag: String,
+    pub value: String,
+    pub defaulted: bool,
+}
+
+/// Returns every setting the run uses, in the order the flags are declared, whether it was given
+/// explicitly or left at its default. Optional flags that weren't given are left out.
+pub fn effective_config(matches: &ArgMatches) -> Vec<Setting> {
+    Args::command()
+        .get_arguments()
+        .filter_map(|arg| {
+            let id = arg.get_id().as_str();
+            let values = matches.try_get_raw(id).ok().flatten()?;
+            Some(Setting {
+                flag: format!("--{}", arg.get_long().unwrap_or(id)),
+                value: values.map(|value| value.to_string_lossy()).join(","),
+                defaulted: matches.value_source(id) == Some(ValueSource::DefaultValue),
+            })
+        })
+        .collect()
 }
 
 // Custom parser for the initial data
-fn parse_initial_data(s: &str) -> Result<HashMap<u64, u64>, String> {
-    let mut data = HashMap::new();
+pub fn parse_initial_data(s: &str) -> Result<HashMap<BucketId, u64>, String> {
+    let InitialData(pairs) = parse_initial_pairs(s)?;
+    Ok(pairs.into_iter().collect())
+}
+
+fn parse_initial_pairs(s: &str) -> Result<InitialData, String> {
+    let mut data = Vec::new();
 
     if s.is_empty() {
-        return Ok(data);
+        return Ok(InitialData(data));
     }
 
     for pair in s.split(',') {
@@ -47,11 +583,215 @@ fn parse_initial_data(s: &str) -> Result<HashMap<u64, u64>, String> {
             return Err(format!("Invalid format for pair: {}", pair));
         }
 
-        let id = u64::from_str(parts[0].trim()).map_err(|e| format!("Invalid ID: {}", e))?;
+        let id = BucketId::from_str(parts[0].trim()).map_err(|e| format!("Invalid ID: {}", e))?;
         let value = u64::from_str(parts[1].trim()).map_err(|e| format!("Invalid value: {}", e))?;
 
-        data.insert(id, value);
+        data.push((id, value));
+    }
+
+    Ok(InitialData(data))
+}
+
+fn parse_bucket_name(s: &str) -> Result<(BucketId, String), String> {
+    let Some((id, name)) = s.split_once(':') else {
+        return Err(format!("Invalid format for name: {}", s));
+    };
+    let id = BucketId::from_str(id.trim()).map_err(|e| format!("Invalid ID: {}", e))?;
+    Ok((id, name.trim().to_string()))
+}
+
+fn parse_bucket_tag(s: &str) -> Result<(BucketId, String), String> {
+    let Some((id, tag)) = s.split_once(':') else {
+        return Err(format!("Invalid format for tag: {}", s));
+    };
+    let id = BucketId::from_str(id.trim()).map_err(|e| format!("Invalid ID: {}", e))?;
+    Ok((id, tag.trim().to_string()))
+}
+
+fn parse_timestamp_format(s: &str) -> Result<String, String> {
+    StrftimeItems::new(s)
+        .parse()
+        .map_err(|e| format!("Invalid timestamp format: {}", e))?;
+    Ok(s.to_string())
+}
+
+fn parse_calibration(s: &str) -> Result<(BucketId, i64), String> {
+    let Some((id, offset)) = s.split_once(':') else {
+        return Err(format!("Invalid format for offset: {}", s));
+    };
+    let id = BucketId::from_str(id.trim()).map_err(|e| format!("Invalid ID: {}", e))?;
+    let offset = i64::from_str(offset.trim()).map_err(|e| format!("Invalid offset: {}", e))?;
+    Ok((id, offset))
+}
+
+fn parse_initial_data_file(s: &str) -> Result<InitialData, String> {
+    let path = Path::new(s);
+    let contents = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", s, e))?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("csv") => parse_initial_data_csv(&contents),
+        Some("json") => parse_initial_data_json(&contents),
+        _ => Err(format!(
+            "Unknown initial data format (expected .csv or .json): {}",
+            s
+        )),
     }
+}
+
+fn parse_initial_data_csv(contents: &str) -> Result<InitialData, String> {
+    let mut data = Vec::new();
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((id, value)) = line.split_once(',') else {
+            return Err(format!("Invalid row on line {}: {}", lineno + 1, line));
+        };
+        // Allow a header row, as most tools that write CSV will add one.
+        if lineno == 0 && BucketId::from_str(id.trim()).is_err() {
+            continue;
+        }
+
+        let id = BucketId::from_str(id.trim()).map_err(|e| format!("Invalid ID: {}", e))?;
+        let value = u64::from_str(value.trim()).map_err(|e| format!("Invalid value: {}", e))?;
 
-    Ok(data)
+        data.push((id, value));
+    }
+
+    Ok(InitialData(data))
+}
+
+fn parse_initial_data_json(contents: &str) -> Result<InitialData, String> {
+    // JSON object keys are always strings, so parse the IDs out of them separately.
+    let object: BTreeMap<String, u64> =
+        serde_json::from_str(contents).map_err(|e| format!("Invalid JSON: {}", e))?;
+    object
+        .into_iter()
+        .map(|(id, value)| {
+            let id = BucketId::from_str(id.trim()).map_err(|e| format!("Invalid ID: {}", e))?;
+            Ok((id, value))
+        })
+        .collect::<Result<_, String>>()
+        .map(InitialData)
+}
+
+/// Checks that no bucket in `data` holds more than `capacity`, naming the first one that does.
+pub fn check_capacity(data: &HashMap<BucketId, u64>, capacity: u64) -> Result<()> {
+    match data.iter().sorted().find(|(_, value)| **value > capacity) {
+        Some((id, value)) => bail!(
+            "bucket {} starts out holding {}, more than its capacity of {}",
+            id,
+            value,
+            capacity
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Formats bucket data in the same format accepted by `parse_initial_data`, ordered by bucket ID.
+pub fn format_initial_data(data: &HashMap<BucketId, u64>) -> String {
+    data.iter()
+        .sorted()
+        .map(|(id, value)| format!("{}:{}", id, value))
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn initial_state(args: &[&str]) -> Result<HashMap<BucketId, u64>> {
+        Args::try_parse_from(std::iter::once("buckets").chain(args.iter().copied()))?
+            .initial_state()
+    }
+
+    #[test]
+    fn initial_data_over_capacity_is_rejected() {
+        assert_eq!(
+            initial_state(&["-i", "1:500"]).unwrap_err().to_string(),
+            "bucket 1 starts out holding 500, more than its capacity of 100"
+        );
+        assert!(initial_state(&["-i", "1:500", "--max-quantity", "500"]).is_ok());
+    }
+
+    #[test]
+    fn repeated_bucket_ids_are_rejected() {
+        assert_eq!(
+            initial_state(&["-i", "1:5,1:6"]).unwrap_err().to_string(),
+            "bucket 1 appears more than once in the initial data (pass --merge-duplicates to sum its values)"
+        );
+        assert_eq!(
+            initial_state(&["-i", "1:5,1:6", "--merge-duplicates"]).unwrap(),
+            HashMap::from([(BucketId(1), 11)])
+        );
+    }
+
+    #[test]
+    fn invalid_timestamp_formats_are_rejected() {
+        let parse = |format| Args::try_parse_from(["buckets", "--timestamp-format", format]);
+        let args = parse("%Y-%m-%d %H:%M:%S%.3f").expect("valid format");
+        assert_eq!(
+            args.timestamp_format.as_deref(),
+            Some("%Y-%m-%d %H:%M:%S%.3f")
+        );
+        let error = parse("%H:%Q").map(|_| ()).unwrap_err().to_string();
+        assert!(error.contains("Invalid timestamp format"), "{}", error);
+    }
+
+    #[test]
+    fn state_formats_as_initial_data() {
+        let data = HashMap::from([(BucketId(10), 0), (BucketId(2), 72), (BucketId(1), 45)]);
+        let formatted = format_initial_data(&data);
+        assert_eq!(formatted, "1:45,2:72,10:0");
+        // Pasted back in, it describes the same state.
+        assert_eq!(parse_initial_data(&formatted), Ok(data));
+        assert_eq!(format_initial_data(&HashMap::new()), "");
+    }
+
+    // Reads the initial state from a file with the given extension and contents.
+    fn initial_state_from_file(extension: &str, contents: &str) -> Result<HashMap<BucketId, u64>> {
+        let path =
+            std::env::temp_dir().join(format!("initial-{}.{}", std::process::id(), extension));
+        fs::write(&path, contents)?;
+        let state = initial_state(&["--initial-data-file", path.to_str().expect("UTF-8 path")]);
+        fs::remove_file(&path)?;
+        state
+    }
+
+    #[test]
+    fn initial_data_files_are_parsed_by_extension() -> Result<()> {
+        let expected = HashMap::from([(BucketId(1), 10), (BucketId(2), 45), (BucketId(7), 0)]);
+        assert_eq!(
+            initial_state_from_file("csv", "id,value\n1,10\n\n2, 45\n7,0\n")?,
+            expected
+        );
+        assert_eq!(initial_state_from_file("csv", "1,10\n2,45\n7,0")?, expected);
+        assert_eq!(
+            initial_state_from_file("json", r#"{ "1": 10, "2": 45, "7": 0 }"#)?,
+            expected
+        );
+        assert!(initial_state_from_file("json", r#"{ "one": 10 }"#).is_err());
+        assert!(initial_state_from_file("csv", "1,10\n2;45").is_err());
+        assert!(initial_state_from_file("txt", "1,10").is_err());
+        Ok(())
+    }
+
+    // Checks that the listing has a described line for every variant, in order.
+    fn assert_lists_every_variant<T: ValueEnum>() {
+        let listing = list_variants::<T>();
+        let lines = listing.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), T::value_variants().len(), "{}", listing);
+        for (line, variant) in lines.iter().zip(T::value_variants()) {
+            let value = variant.to_possible_value().expect("no skipped variants");
+            let (name, help) = line.split_once("  ").expect("a description");
+            assert_eq!(name, value.get_name());
+            assert!(!help.trim().is_empty(), "{} has no description", name);
+        }
+    }
+
+    #[test]
+    fn listings_include_every_variant() {
+        assert_lists_every_variant::<Policy>();
+        assert_lists_every_variant::<BucketType>();
+    }
 }
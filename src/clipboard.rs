@@ -0,0 +1,32 @@
+//! The clipboard lets the TUI hand text to the system clipboard. Not every system has one (e.g.
+//! headless Linux without X11 or Wayland), so failing to copy is an error for the caller to report
+//! rather than a reason to stop.
+
+use anyhow::Result;
+
+pub struct Clipboard {
+    // Connected on the first copy. On some platforms, copied text only stays on the clipboard for
+    // as long as the connection lives, so it's kept open afterwards.
+    connection: Option<arboard::Clipboard>,
+}
+
+impl Default for Clipboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clipboard {
+    pub fn new() -> Self {
+        Clipboard { connection: None }
+    }
+
+    pub fn copy(&mut self, text: String) -> Result<()> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => self.connection.insert(arboard::Clipboard::new()?),
+        };
+        connection.set_text(text)?;
+        Ok(())
+    }
+}
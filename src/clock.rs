@@ -0,0 +1,76 @@
+//! A clock is where the simulation reads the wall-clock time from, e.g. to timestamp events.
+//! Reading the time through a `Clock` rather than calling `Local::now()` directly lets a run that
+//! must be deterministic (such as the lockstep simulation) control time itself.
+//!
+//! Note that this only covers reading the time. The loops wait using `tokio::time`, which can
+//! already be paused and advanced manually with `tokio::time::pause`.
+
+use std::sync::Mutex;
+
+use chrono::{DateTime, Local, TimeDelta};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Local>;
+}
+
+/// SystemClock reports the actual local time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// ManualClock reports a time that only moves when it's advanced.
+pub struct ManualClock {
+    now: Mutex<DateTime<Local>>,
+}
+
+impl ManualClock {
+    pub fn new(start: DateTime<Local>) -> Self {
+        ManualClock {
+            now: Mutex::new(start),
+        }
+    }
+
+    pub fn advance(&self, by: TimeDelta) {
+        *self.now.lock().expect("clock lock poisoned") += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Local> {
+        *self.now.lock().expect("clock lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::events::{EventSource, Events};
+
+    #[test]
+    fn manual_clock_timestamps_events() {
+        let start = Local.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let mut events = Events::with_clock(clock.clone());
+        events.add(EventSource::System, String::from("first"));
+        events.add(EventSource::System, String::from("second"));
+        clock.advance(TimeDelta::milliseconds(1500));
+        events.add(EventSource::System, String::from("third"));
+        let timestamps = events
+            .get_all()
+            .iter()
+            .map(|event| event.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            timestamps,
+            [start, start, start + TimeDelta::milliseconds(1500)]
+        );
+    }
+}
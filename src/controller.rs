@@ -6,59 +6,379 @@
 //! used by the actuator (actuator.rs) to know what actions to take in order to correct the system
 //! under control.
 
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::thread::sleep;
-use std::time::Duration;
 
 use anyhow::Result;
+use clap::ValueEnum;
+use itertools::Itertools;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 
-use crate::actuator::{Action, Actuator};
-use crate::events::{EventSource, Events};
-use crate::policy::Policy;
-use crate::sensor::Sensor;
+use crate::actuator::{self, Action};
+use crate::buckets::ids::BucketId;
+use crate::convergence::Tolerance;
+use crate::cooldown::Cooldown;
+use crate::events::{EventSource, Events, Severity};
+use crate::metrics::Metrics;
+use crate::policy::{Policy, PolicyParams, PolicyState};
+use crate::sensor::{Calibration, Readings, Sensor, SensorNoise};
+
+/// Schedule decides when the controller analyzes the sensor data.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Schedule {
+    /// Analyze on a fixed timer.
+    Periodic,
+    /// Analyze whenever the fills since the last analysis add up to a threshold, and not at all
+    /// while nothing changes.
+    OnChange,
+}
+
+/// ManualOverride is a switch, shared with the TUI, that stops the controller from acting on its
+/// own. While it's on, the controller still runs on its schedule but always emits `NoAction`, so
+/// the system only changes through fills and manual commands.
+#[derive(Clone)]
+pub struct ManualOverride(Arc<AtomicBool>);
+
+impl ManualOverride {
+    pub fn new(enabled: bool) -> Self {
+        ManualOverride(Arc::new(AtomicBool::new(enabled)))
+    }
+
+    pub fn toggle(&self) {
+        self.0.fetch_xor(true, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Decision is the outcome of analyzing a snapshot of the readings.
+#[derive(Clone)]
+struct Decision {
+    // A hash of the readings that were analyzed.
+    snapshot: u64,
+    action: Action,
+}
+
+/// Hashes the readings, ordered by bucket so that the same readings always hash the same.
+fn snapshot<T: Sensor>(readings: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for reading in readings.buckets().iter().sorted() {
+        reading.hash(&mut hasher);
+    }
+    readings.capacity().hash(&mut hasher);
+    hasher.finish()
+}
 
 pub struct Controller<S: Sensor> {
     policy: Policy,
+    params: PolicyParams,
     sensor: Arc<Mutex<S>>,
+    // When set, the policy analyzes noisy readings rather than the true sensor data.
+    noise: Option<SensorNoise>,
+    // When set, the policy analyzes readings with these offsets added.
+    calibration: Option<Calibration>,
+    // Hides buckets that were just part of a transfer from the policy.
+    cooldown: Cooldown,
+    // Read-only buckets, which are always hidden from the policy since it can't act on them.
+    readonly: HashSet<BucketId>,
+    manual: ManualOverride,
+    // Overrides the balance tolerance in the policy parameters, so it can change at runtime.
+    tolerance: Tolerance,
+    // What the policy draws its random choices from and carries between analyses.
+    state: PolicyState,
+    // The last analysis, reused as long as the readings don't change.
+    last_decision: Option<Decision>,
     events: Arc<Mutex<Events>>,
     control_signal_tx: Sender<Action>,
+    // Counts the NoAction decisions, if metrics are served.
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl<S: Sensor> Controller<S> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         policy: Policy,
+        params: PolicyParams,
         sensor: Arc<Mutex<S>>,
+        noise: Option<SensorNoise>,
+        calibration: Option<Calibration>,
+        readonly: HashSet<BucketId>,
+        manual: ManualOverride,
+        tolerance: Tolerance,
+        seed: Option<u64>,
         events: Arc<Mutex<Events>>,
         control_signal_tx: Sender<Action>,
     ) -> Self {
         Controller {
             policy,
+            cooldown: Cooldown::new(params.cooldown),
+            params,
             sensor,
+            noise,
+            calibration,
+            readonly,
+            manual,
+            tolerance,
+            state: PolicyState::new(seed),
+            last_decision: None,
             events,
             control_signal_tx,
+            metrics: None,
         }
     }
 
-    pub async fn run(&self, ct: CancellationToken) -> Result<()> {
-        let sensor = self.sensor.lock().await;
-        let action = self.policy.analyze(sensor);
-        self.events.lock().await.add(
-            EventSource::Controller,
+    /// Has the controller count its decisions into `metrics` from now on.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Swaps in a new policy and parameters, starting from the next analysis. This resets the
+    /// cooldown, since its length is one of the parameters, and whatever the old policy learned.
+    pub fn set_policy(&mut self, policy: Policy, params: PolicyParams) {
+        self.policy = policy;
+        self.cooldown = Cooldown::new(params.cooldown);
+        self.params = params;
+        self.state.reset();
+        self.last_decision = None;
+    }
+
+    pub async fn run(&mut self, ct: CancellationToken) -> Result<()> {
+        let tolerance = self.tolerance.get();
+        if tolerance != self.params.balance_tolerance {
+            // The last decision was made with a different idea of balance, so it no longer stands.
+            self.params.balance_tolerance = tolerance;
+            self.last_decision = None;
+        }
+        if self.manual.is_enabled() {
+            self.events.lock().await.add(
+                EventSource::Controller,
+                format!("manual override, skipped analysis => {}", Action::NoAction),
+            );
+            return self.send(ct, Action::NoAction).await;
+        }
+
+        let sensor = self.sensor.clone();
+        let sensor = sensor.lock().await;
+        // Sensing errors stack: calibration offsets first, then noise on top of them.
+        let calibrated = self
+            .calibration
+            .as_ref()
+            .map(|calibration| calibration.read(&*sensor));
+        let noisy = self.noise.as_mut().map(|noise| match &calibrated {
+            Some(calibrated) => noise.read(calibrated),
+            None => noise.read(&*sensor),
+        });
+        let sensed = noisy.or(calibrated);
+        // Alarms are about every bucket, including those cooling down, which the policy doesn't
+        // get to see. A bucket that's still critical after a drain must keep alarming.
+        let alarm = match &sensed {
+            Some(sensed) => self.policy.alarm(sensed, &self.params),
+            None => self.policy.alarm(&*sensor, &self.params),
+        };
+        let eligible = match &sensed {
+            Some(sensed) => self.cooldown.eligible(sensed),
+            None => self.cooldown.eligible(&*sensor),
+        };
+        // Analyze a copy of the readings, so that the buckets aren't locked for as long as the
+        // policy takes. Otherwise a slow analysis would also hold up the filler and the TUI.
+        let readings = eligible
+            .or(sensed)
+            .unwrap_or_else(|| Readings::new(sensor.buckets().clone(), sensor.capacity()));
+        drop(sensor);
+        let readings = Readings::without(&readings, &self.readonly).unwrap_or(readings);
+        // Policies can take a while to analyze, so let the runtime get on with the other tasks in
+        // the meantime, including the one that would cancel us.
+        let decided = tokio::task::block_in_place(|| self.decide(&readings, &ct));
+        let (decision, reused) = match decided {
+            // We're shutting down, so there's nothing left to act on.
+            Err(_) if ct.is_cancelled() => return Ok(()),
+            decided => decided?,
+        };
+        let action = decision.action;
+        self.cooldown.record(&action);
+        if let (Some(metrics), Action::NoAction) = (&self.metrics, &action) {
+            metrics.record_noaction();
+        }
+        let mut events = self.events.lock().await;
+        if let Some(alarm) = alarm {
+            events.add_with_severity(EventSource::Controller, Severity::Critical, alarm);
+        }
+        let message = if reused {
+            format!(
+                "sensor data unchanged, reused '{}' policy decision => {}",
+                self.policy, action
+            )
+        } else {
             format!(
                 "analyzed sensor data with '{}' policy => {}",
                 self.policy, action
-            ),
-        );
+            )
+        };
+        events.add(EventSource::Controller, message);
+        drop(events);
+        // The decision was made on a copy of the readings, so check it against the buckets as they
+        // are now.
+        let validated = actuator::validate(&*self.sensor.lock().await, &self.readonly, &action);
+        if let Err(e) = validated {
+            self.events.lock().await.add_with_severity(
+                EventSource::Controller,
+                Severity::Warn,
+                format!("dropped invalid action: {}: {}", action, e),
+            );
+            return Ok(());
+        }
+        self.send(ct, action).await
+    }
+
+    /// Analyzes the readings, unless they're the same as last time, in which case the last decision
+    /// still stands. Policies that make random choices or learn from past analyses always analyze,
+    /// since they could decide differently every time. Returns the decision and whether it was
+    /// reused.
+    fn decide<T: Sensor>(
+        &mut self,
+        readings: &T,
+        ct: &CancellationToken,
+    ) -> Result<(Decision, bool)> {
+        let snapshot = snapshot(readings);
+        let cacheable = !matches!(self.policy, Policy::Random | Policy::Pid);
+        if let Some(last) = &self.last_decision {
+            if cacheable && last.snapshot == snapshot {
+                return Ok((last.clone(), true));
+            }
+        }
+        let decision = Decision {
+            snapshot,
+            action: self
+                .policy
+                .analyze(readings, &self.params, &mut self.state, ct)?,
+        };
+        self.last_decision = Some(decision.clone());
+        Ok((decision, false))
+    }
+
+    /// Sends the action to the actuator. If the actuator is gone, there's no one left to act on
+    /// anything we decide, so this logs why and shuts the whole run down.
+    async fn send(&self, ct: CancellationToken, action: Action) -> Result<()> {
         tokio::select! {
             res = self.control_signal_tx.send(action) => {
-                res?;
+                if res.is_err() {
+                    self.events.lock().await.add_with_severity(
+                        EventSource::System,
+                        Severity::Critical,
+                        "the actuator stopped receiving actions, shutting down".to_string(),
+                    );
+                    ct.cancel();
+                }
                 Ok(())
             },
             _ = ct.cancelled() => Ok(())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use clap::Parser;
+    use tokio::sync::mpsc::{self, Receiver};
+
+    use super::*;
+    use crate::cli::Args;
+
+    // A controller over buckets 1, 2 and 3, holding 90, 10 and 50 of 100, and where it sends its
+    // actions.
+    fn controller(
+        policy: Policy,
+        manual: ManualOverride,
+    ) -> (Controller<Readings>, Receiver<Action>) {
+        let args = Args::try_parse_from(["buckets"]).expect("default arguments parse");
+        let data = HashMap::from([(BucketId(1), 90), (BucketId(2), 10), (BucketId(3), 50)]);
+        let (control_signal_tx, control_signal_rx) = mpsc::channel(10);
+        let controller = Controller::new(
+            policy,
+            args.policy_params(),
+            Arc::new(Mutex::new(Readings::new(data, 100))),
+            None,
+            None,
+            HashSet::new(),
+            manual,
+            Tolerance::new(0),
+            Some(0),
+            Arc::new(Mutex::new(Events::new())),
+            control_signal_tx,
+        );
+        (controller, control_signal_rx)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn manual_override_emits_nothing() -> Result<()> {
+        let manual = ManualOverride::new(true);
+        for policy in [Policy::Spread, Policy::EmergencyDrain, Policy::Concentrate] {
+            let (mut controller, mut actions) = controller(policy, manual.clone());
+            controller.run(CancellationToken::new()).await?;
+            assert_eq!(actions.recv().await, Some(Action::NoAction), "{}", policy);
+        }
+        // The same imbalance gets acted on once the override is off.
+        manual.toggle();
+        let (mut controller, mut actions) = controller(Policy::Spread, manual);
+        controller.run(CancellationToken::new()).await?;
+        assert_ne!(actions.recv().await, Some(Action::NoAction));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unchanged_readings_reuse_the_last_decision() -> Result<()> {
+        // Runs the controller once and returns the decision it logged.
+        async fn decide(controller: &mut Controller<Readings>) -> Result<String> {
+            controller.run(CancellationToken::new()).await?;
+            let events = controller.events.lock().await;
+            Ok(events
+                .get_all()
+                .back()
+                .expect("decision logged")
+                .message
+                .clone())
+        }
+        let (mut cached, _actions) = controller(Policy::NoOp, ManualOverride::new(false));
+        assert!(decide(&mut cached).await?.starts_with("analyzed"));
+        assert!(decide(&mut cached)
+            .await?
+            .starts_with("sensor data unchanged, reused"));
+        let data = HashMap::from([(BucketId(1), 91), (BucketId(2), 10), (BucketId(3), 50)]);
+        *cached.sensor.lock().await = Readings::new(data, 100);
+        assert!(decide(&mut cached).await?.starts_with("analyzed"));
+
+        // A random policy could decide differently on the same readings.
+        let (mut random, _actions) = controller(Policy::Random, ManualOverride::new(false));
+        for _ in 0..3 {
+            assert!(decide(&mut random).await?.starts_with("analyzed"));
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_gone_actuator_shuts_down_cleanly() -> Result<()> {
+        let (mut controller, actions) = controller(Policy::Spread, ManualOverride::new(false));
+        drop(actions);
+        let ct = CancellationToken::new();
+        controller.run(ct.clone()).await?;
+        assert!(ct.is_cancelled());
+        let events = controller.events.lock().await;
+        let event = events.get_all().back().expect("closure logged");
+        assert!(event.source == EventSource::System);
+        assert!(event.severity == Severity::Critical);
+        assert_eq!(
+            event.message,
+            "the actuator stopped receiving actions, shutting down"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+//! Convergence tracks how steadily the buckets stay balanced. Near equilibrium, single fills keep
+//! knocking the buckets in and out of balance, so rather than whether they're balanced right now,
+//! this counts how many of the last few ticks they were balanced for.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::sensor::Sensor;
+
+/// Tolerance is the balance tolerance (see Sensor::is_balanced), shared with the TUI so that it can
+/// be adjusted while the simulation runs, and with the controller, whose policies stop acting once
+/// the buckets are balanced within it.
+#[derive(Clone)]
+pub struct Tolerance(Arc<AtomicU64>);
+
+impl Tolerance {
+    pub fn new(tolerance: u64) -> Self {
+        Tolerance(Arc::new(AtomicU64::new(tolerance)))
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, tolerance: u64) {
+        self.0.store(tolerance, Ordering::Relaxed);
+    }
+}
+
+pub struct Convergence {
+    // Whether the buckets were balanced on each of the most recent ticks, oldest first.
+    window: VecDeque<bool>,
+    size: usize,
+    tolerance: Tolerance,
+}
+
+impl Convergence {
+    pub fn new(size: usize, tolerance: Tolerance) -> Self {
+        Convergence {
+            window: VecDeque::with_capacity(size),
+            size,
+            tolerance,
+        }
+    }
+
+    /// Records whether the buckets are balanced as of the latest tick.
+    pub fn observe<S: Sensor>(&mut self, sensor: &S) {
+        self.push(sensor.is_balanced(self.tolerance.get()));
+    }
+
+    /// Records whether the buckets were balanced on the latest tick, forgetting the oldest tick
+    /// once the window is full.
+    fn push(&mut self, balanced: bool) {
+        if self.window.len() == self.size {
+            self.window.pop_front();
+        }
+        self.window.push_back(balanced);
+    }
+
+    /// Returns how many ticks in the window the buckets were balanced for, and how many ticks the
+    /// window holds. The window holds fewer than its size until that many ticks have passed.
+    pub fn balanced_ticks(&self) -> (usize, usize) {
+        let balanced = self.window.iter().filter(|balanced| **balanced).count();
+        (balanced, self.window.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::buckets::ids::BucketId;
+    use crate::sensor::Readings;
+
+    #[test]
+    fn counts_balanced_ticks_in_the_window() {
+        let mut convergence = Convergence::new(3, Tolerance::new(0));
+        assert_eq!(convergence.balanced_ticks(), (0, 0));
+        convergence.push(true);
+        convergence.push(false);
+        assert_eq!(convergence.balanced_ticks(), (1, 2));
+        convergence.push(true);
+        assert_eq!(convergence.balanced_ticks(), (2, 3));
+        // The oldest tick, which was balanced, falls out of the window.
+        convergence.push(false);
+        assert_eq!(convergence.balanced_ticks(), (1, 3));
+        convergence.push(true);
+        convergence.push(true);
+        convergence.push(true);
+        assert_eq!(convergence.balanced_ticks(), (3, 3));
+    }
+
+    #[test]
+    fn changing_the_tolerance_changes_what_is_balanced() {
+        // The buckets are 10 apart.
+        let readings = Readings::new(HashMap::from([(BucketId(1), 45), (BucketId(2), 55)]), 100);
+        let tolerance = Tolerance::new(9);
+        let mut convergence = Convergence::new(3, tolerance.clone());
+        convergence.observe(&readings);
+        assert_eq!(convergence.balanced_ticks(), (0, 1));
+        // The tolerance is shared, so loosening it takes effect from the next tick.
+        tolerance.set(10);
+        convergence.observe(&readings);
+        assert_eq!(convergence.balanced_ticks(), (1, 2));
+        // And tightening it again, as '[' does, takes effect just the same.
+        tolerance.set(tolerance.get().saturating_sub(1));
+        convergence.observe(&readings);
+        assert_eq!(convergence.balanced_ticks(), (1, 3));
+    }
+}
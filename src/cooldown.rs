@@ -0,0 +1,96 @@
+//! A cooldown keeps buckets out of transfers for a while after they were part of one, so that a
+//! policy can't thrash a single bucket back and forth. It counts in analyses: once a bucket is the
+//! source or destination of a transfer, the policy doesn't see it for the next `ticks` analyses.
+
+use std::collections::HashMap;
+
+use crate::actuator::Action;
+use crate::buckets::ids::BucketId;
+use crate::sensor::{Readings, Sensor};
+
+pub struct Cooldown {
+    ticks: u64,
+    // How many more analyses each cooling bucket is hidden from.
+    remaining: HashMap<BucketId, u64>,
+}
+
+impl Cooldown {
+    pub fn new(ticks: u64) -> Self {
+        Cooldown {
+            ticks,
+            remaining: HashMap::new(),
+        }
+    }
+
+    /// Returns readings of only the sensed buckets that aren't cooling down, or None if no bucket
+    /// is, in which case the sensed buckets can be analyzed as they are.
+    pub fn eligible<S: Sensor>(&self, sensor: &S) -> Option<Readings> {
+        if self.remaining.is_empty() {
+            return None;
+        }
+        Some(Readings::new(
+            sensor
+                .buckets()
+                .iter()
+                .filter(|(bucket, _)| !self.remaining.contains_key(bucket))
+                .map(|(bucket, quantity)| (*bucket, *quantity))
+                .collect(),
+            sensor.capacity(),
+        ))
+    }
+
+    /// Counts every cooling bucket down by an analysis, then starts the cooldown of the buckets
+    /// the given action transfers between. Call this once per analysis, with its action.
+    pub fn record(&mut self, action: &Action) {
+        self.remaining.retain(|_, left| {
+            *left -= 1;
+            *left > 0
+        });
+        for (source, destination, amount) in action.transfers() {
+            // A transfer of nothing doesn't touch either bucket.
+            if self.ticks > 0 && amount > 0 {
+                self.remaining.insert(source, self.ticks);
+                self.remaining.insert(destination, self.ticks);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the IDs of the buckets the policy would see, in order.
+    fn eligible(cooldown: &Cooldown, sensor: &Readings) -> Vec<u64> {
+        let mut eligible = match cooldown.eligible(sensor) {
+            Some(readings) => readings.buckets().keys().map(|bucket| bucket.0).collect(),
+            None => sensor
+                .buckets()
+                .keys()
+                .map(|bucket| bucket.0)
+                .collect::<Vec<_>>(),
+        };
+        eligible.sort();
+        eligible
+    }
+
+    #[test]
+    fn transferring_buckets_are_skipped_until_cooled_down() {
+        let sensor = Readings::new(
+            HashMap::from([(BucketId(1), 80), (BucketId(2), 10), (BucketId(3), 40)]),
+            100,
+        );
+        let mut cooldown = Cooldown::new(2);
+        cooldown.record(&Action::Transfer {
+            source: BucketId(1),
+            destination: BucketId(2),
+            amount: 30,
+        });
+        assert_eq!(eligible(&cooldown, &sensor), [3]);
+        cooldown.record(&Action::NoAction);
+        assert_eq!(eligible(&cooldown, &sensor), [3]);
+        cooldown.record(&Action::NoAction);
+        assert!(cooldown.eligible(&sensor).is_none());
+        assert_eq!(eligible(&cooldown, &sensor), [1, 2, 3]);
+    }
+}
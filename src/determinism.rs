@@ -0,0 +1,180 @@
+//! The determinism check runs the same seeded configuration twice in lockstep and compares the two
+//! runs. Lockstep runs are meant to be exactly reproducible, so any difference between them, in the
+//! event log or in the final bucket state, means something nondeterministic (e.g. iterating a
+//! HashMap, or reading the real clock) has crept into the fill or policy code.
+//!
+//! It also checks that resuming from a state file is seamless: the fills of a run that's saved and
+//! resumed halfway through must be exactly the fills of a run that went straight through.
+
+use anyhow::{anyhow, Result};
+
+use crate::buckets::Buckets;
+use crate::cli::{format_initial_data, Args};
+use crate::events::Event;
+use crate::sensor::Sensor;
+use crate::simulation::Simulation;
+use crate::state_store::{self, SavedState};
+
+/// Runs the configuration given by `args` twice for `ticks` ticks, returning an error describing
+/// the first difference between the runs, if any. Then checks that resuming the fills halfway
+/// through changes nothing either.
+pub fn verify(args: &Args, seed: u64, ticks: u64) -> Result<()> {
+    compare(&run(args, seed, ticks)?, &run(args, seed, ticks)?)?;
+    // The throttle's window isn't part of the saved state, so a resumed throttle starts afresh.
+    if args.fill_rate_limit.is_none() {
+        verify_resume(args, seed, ticks)?;
+    }
+    Ok(())
+}
+
+/// Returns an error describing the first difference between the two runs, if any.
+fn compare(first: &Outcome, second: &Outcome) -> Result<()> {
+    for (index, (a, b)) in first.log.iter().zip(second.log.iter()).enumerate() {
+        if a != b {
+            return Err(anyhow!(
+                "runs diverged at event {}:\n  first:  {}\n  second: {}",
+                index + 1,
+                a,
+                b
+            ));
+        }
+    }
+    if first.log.len() != second.log.len() {
+        return Err(anyhow!(
+            "runs diverged: first logged {} events, second logged {}",
+            first.log.len(),
+            second.log.len()
+        ));
+    }
+    if first.state != second.state {
+        return Err(anyhow!(
+            "runs diverged in their final state:\n  first:  {}\n  second: {}",
+            first.state,
+            second.state
+        ));
+    }
+    Ok(())
+}
+
+/// Fills the buckets given by `args` for `ticks` ticks, once straight through and once saved and
+/// resumed into new buckets halfway through, and compares every fill of the two.
+fn verify_resume(args: &Args, seed: u64, ticks: u64) -> Result<()> {
+    let mut buckets = Simulation::buckets_from_args(args, args.initial_state()?, Some(seed));
+    let straight = (0..ticks)
+        .flat_map(|_| buckets.fill())
+        .map(|fill| fill.to_string())
+        .collect::<Vec<_>>();
+
+    // Resume from a state saved and reloaded just as --state-file would, into buckets that are
+    // deliberately seeded differently, so that only the saved RNG can carry the sequence on.
+    let halfway = ticks / 2;
+    let mut buckets = Simulation::buckets_from_args(args, args.initial_state()?, Some(seed));
+    let mut resumed = (0..halfway)
+        .flat_map(|_| buckets.fill())
+        .collect::<Vec<_>>();
+    let saved = state_store::format_state(&SavedState {
+        buckets: buckets.buckets().clone(),
+        fill_rng: Some(buckets.rng().clone()),
+    });
+    let state = state_store::parse_state(&saved)?;
+    let mut buckets =
+        Simulation::buckets_from_args(args, state.buckets, Some(seed.wrapping_add(1)));
+    buckets.set_rng(state.fill_rng.expect("saved with the state"));
+    resumed.extend((halfway..ticks).flat_map(|_| buckets.fill()));
+    let resumed = resumed
+        .iter()
+        .map(|fill| fill.to_string())
+        .collect::<Vec<_>>();
+
+    match straight
+        .iter()
+        .zip(resumed.iter())
+        .position(|(a, b)| a != b)
+    {
+        Some(index) => Err(anyhow!(
+            "resuming at tick {} diverged at fill {}:\n  straight: {}\n  resumed:  {}",
+            halfway + 1,
+            index + 1,
+            straight[index],
+            resumed[index]
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Outcome is what a run is compared by: its event log and final state, both formatted as text.
+struct Outcome {
+    log: Vec<String>,
+    state: String,
+}
+
+fn run(args: &Args, seed: u64, ticks: u64) -> Result<Outcome> {
+    let mut simulation = Simulation::from_args(args, seed)?;
+    for _ in 0..ticks {
+        simulation.tick()?;
+    }
+    Ok(Outcome {
+        log: simulation
+            .events()
+            .get_all()
+            .iter()
+            .map(format_event)
+            .collect(),
+        state: format_initial_data(simulation.buckets().buckets()),
+    })
+}
+
+fn format_event(event: &Event) -> String {
+    let mut line = format!(
+        "{} {} {} {}",
+        event.timestamp.to_rfc3339(),
+        event.source,
+        event.severity,
+        event.message
+    );
+    for (name, value) in &event.fields {
+        line.push_str(&format!(" {}={}", name, value));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn args(argv: &[&str]) -> Args {
+        Args::try_parse_from(["buckets"].iter().chain(argv)).expect("arguments parse")
+    }
+
+    #[test]
+    fn deterministic_runs_pass() -> Result<()> {
+        verify(&args(&["--policy", "spread"]), 7, 50)?;
+        verify(&args(&["--policy", "random", "--fill-burst"]), 7, 50)
+    }
+
+    #[test]
+    fn resumed_runs_fill_the_same() -> Result<()> {
+        for extra in [
+            &[][..],
+            &["--fill-burst"],
+            &["--fill-capacity-weighted"],
+            &["--fill-fanout", "2"],
+            &["--bucket-type", "leaky-buckets"],
+        ] {
+            verify_resume(&args(extra), 7, 50)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn nondeterministic_runs_fail() -> Result<()> {
+        // A run that draws different random numbers the second time round, as it would if it
+        // were seeded from somewhere other than the seed it was given.
+        let args = args(&["--policy", "random"]);
+        let error = compare(&run(&args, 7, 50)?, &run(&args, 8, 50)?).unwrap_err();
+        assert!(error.to_string().starts_with("runs diverged"), "{}", error);
+        Ok(())
+    }
+}
@@ -1,13 +1,22 @@
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Display;
-use std::time::SystemTime;
+use std::sync::Arc;
 
-use chrono::{DateTime, Local};
+use anyhow::Result;
+use chrono::{DateTime, Local, TimeDelta};
 use ratatui::style::Color;
+use tokio_util::sync::CancellationToken;
 
+use crate::clock::{Clock, SystemClock};
+use crate::exporter::Exporter;
+use crate::theme::palette;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum EventSource {
     Controller,
     Actuator,
     Filler,
+    System,
 }
 
 impl Display for EventSource {
@@ -16,16 +25,38 @@ impl Display for EventSource {
             Self::Controller => write!(f, "Controller"),
             Self::Actuator => write!(f, "Actuator"),
             Self::Filler => write!(f, "Filler"),
+            Self::System => write!(f, "System"),
         }
     }
 }
 
 impl EventSource {
+    pub const ALL: [EventSource; 4] =
+        [Self::Controller, Self::Actuator, Self::Filler, Self::System];
+
     pub fn color(&self) -> Color {
         match self {
-            Self::Controller => Color::Yellow,
-            Self::Actuator => Color::Green,
-            Self::Filler => Color::Cyan,
+            Self::Controller => palette::ORANGE,
+            Self::Actuator => palette::SKY_BLUE,
+            Self::Filler => palette::BLUISH_GREEN,
+            Self::System => palette::REDDISH_PURPLE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warn,
+    Critical,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Info => write!(f, "Info"),
+            Self::Warn => write!(f, "Warn"),
+            Self::Critical => write!(f, "Critical"),
         }
     }
 }
@@ -33,27 +64,206 @@ impl EventSource {
 pub struct Event {
     pub timestamp: DateTime<Local>,
     pub source: EventSource,
+    pub severity: Severity,
     pub message: String,
+    /// Structured data attached to the event, as name and value pairs. Most events have none.
+    pub fields: Vec<(String, String)>,
 }
 
+/// How many events an event log keeps by default.
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
 pub struct Events {
-    events: Vec<Event>,
+    clock: Arc<dyn Clock>,
+    // Only the most recent `capacity` events are kept; older ones are evicted as new ones arrive.
+    events: VecDeque<Event>,
+    capacity: usize,
+    // How many events have been evicted so far.
+    dropped: usize,
+    counts: HashMap<EventSource, usize>,
+    exporters: Vec<Box<dyn Exporter>>,
+    // add() can't fail, so the first export error is held until the exporters are flushed.
+    export_error: Option<anyhow::Error>,
+    // In strict mode, the first event of Warn severity or worse cancels this token.
+    strict: Option<CancellationToken>,
+    // Describes the event that ended the run in strict mode, if any did.
+    fatal: Option<String>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Events {
+    /// Creates an event log that keeps the most recent `DEFAULT_CAPACITY` events.
     pub fn new() -> Self {
-        Events { events: Vec::new() }
+        Self::new_with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// Creates an event log that keeps only the most recent `capacity` events.
+    pub fn new_with_capacity(capacity: usize) -> Self {
+        let mut events = Self::with_clock(Arc::new(SystemClock));
+        events.capacity = capacity;
+        events
+    }
+
+    /// Creates an event log that timestamps events using the given clock. It keeps every event,
+    /// since the runs that use their own clock are bounded by their tick count anyway.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Events {
+            clock,
+            events: VecDeque::new(),
+            capacity: usize::MAX,
+            dropped: 0,
+            counts: HashMap::new(),
+            exporters: Vec::new(),
+            export_error: None,
+            strict: None,
+            fatal: None,
+        }
+    }
+
+    /// Turns on strict mode, where any event of Warn severity or worse is fatal: the first one
+    /// cancels `ct` and is kept as the reason the run ended.
+    pub fn set_strict(&mut self, ct: CancellationToken) {
+        self.strict = Some(ct);
+    }
+
+    /// Returns a description of the event that ended the run in strict mode, if any did.
+    pub fn fatal(&self) -> Option<&str> {
+        self.fatal.as_deref()
+    }
+
+    /// Registers an exporter that receives every event added from now on.
+    pub fn add_exporter(&mut self, exporter: Box<dyn Exporter>) {
+        self.exporters.push(exporter);
     }
 
     pub fn add(&mut self, source: EventSource, message: String) {
-        self.events.push(Event {
-            timestamp: Local::now(),
+        self.add_with_severity(source, Severity::Info, message);
+    }
+
+    pub fn add_with_severity(&mut self, source: EventSource, severity: Severity, message: String) {
+        self.add_with_fields(source, severity, message, Vec::new());
+    }
+
+    /// Adds an event that carries structured data along with its message.
+    pub fn add_with_fields(
+        &mut self,
+        source: EventSource,
+        severity: Severity,
+        message: String,
+        fields: Vec<(String, String)>,
+    ) {
+        *self.counts.entry(source).or_default() += 1;
+        let event = Event {
+            timestamp: self.clock.now(),
             source,
+            severity,
             message,
-        });
+            fields,
+        };
+        for exporter in self.exporters.iter_mut() {
+            if let Err(err) = exporter.export(&event) {
+                self.export_error.get_or_insert(err);
+            }
+        }
+        if let Some(ct) = &self.strict {
+            if event.severity >= Severity::Warn && self.fatal.is_none() {
+                self.fatal = Some(format!(
+                    "{} event from {}: {}",
+                    event.severity, event.source, event.message
+                ));
+                ct.cancel();
+            }
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+            self.dropped += 1;
+        }
+        self.events.push_back(event);
+    }
+
+    /// Flushes and drops all exporters, returning the first error any of them hit since they were
+    /// added.
+    pub fn close_exporters(&mut self) -> Result<()> {
+        for mut exporter in self.exporters.drain(..) {
+            if let Err(err) = exporter.flush() {
+                self.export_error.get_or_insert(err);
+            }
+        }
+        match self.export_error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
     }
 
-    pub fn get_all(&self) -> &Vec<Event> {
+    /// Returns the events still kept, oldest first.
+    pub fn get_all(&self) -> &VecDeque<Event> {
         &self.events
     }
+
+    /// Returns how many of the oldest events have been evicted to keep within the capacity, so
+    /// that an event's index in `get_all` plus this is its position among every event ever added.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Returns the total number of events ever added by the given source.
+    pub fn count(&self, source: EventSource) -> usize {
+        self.counts.get(&source).copied().unwrap_or(0)
+    }
+
+    /// Returns the rate, in events per second, at which the given source added events over the
+    /// trailing `window`.
+    pub fn rate(&self, source: EventSource, window: TimeDelta) -> f64 {
+        let since = self.clock.now() - window;
+        let recent = self
+            .events
+            .iter()
+            .rev()
+            .take_while(|event| event.timestamp >= since)
+            .filter(|event| event.source == source)
+            .count();
+        recent as f64 / (window.num_milliseconds() as f64 / 1000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn events_are_counted_per_source() {
+        let clock = Arc::new(ManualClock::new(Local.timestamp_opt(0, 0).unwrap()));
+        let mut events = Events::with_clock(clock.clone());
+        events.capacity = 4;
+        let sources = [
+            EventSource::Controller,
+            EventSource::Actuator,
+            EventSource::Controller,
+            EventSource::Filler,
+            EventSource::Controller,
+            EventSource::Actuator,
+        ];
+        for source in sources {
+            clock.advance(TimeDelta::seconds(1));
+            events.add(source, String::from("event"));
+        }
+        // The counts include the evicted events, unlike the rates.
+        assert_eq!(events.count(EventSource::Controller), 3);
+        assert_eq!(events.count(EventSource::Actuator), 2);
+        assert_eq!(events.count(EventSource::Filler), 1);
+        assert_eq!(events.count(EventSource::System), 0);
+        let window = TimeDelta::seconds(4);
+        assert_eq!(events.rate(EventSource::Controller, window), 0.5);
+        assert_eq!(events.rate(EventSource::Actuator, window), 0.25);
+        assert_eq!(events.rate(EventSource::Filler, window), 0.25);
+        assert_eq!(events.rate(EventSource::System, window), 0.0);
+    }
 }
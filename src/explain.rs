@@ -0,0 +1,161 @@
+//! Explaining a tick traces a single control cycle of a headless run step by step: what the fill
+//! did, what the sensor read afterwards and the aggregates policies work from, what the policy
+//! decided and why, and how the buckets changed once the decision was applied. Every tick before
+//! the explained one runs as usual, so the trace shows the state the run actually got into.
+
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use clap::ValueEnum;
+
+use crate::actuator::FinalControlElement;
+use crate::buckets::Buckets;
+use crate::cli::{format_initial_data, Args};
+use crate::sensor::Sensor;
+use crate::simulation::Simulation;
+
+/// Runs the configuration given by `args` up to and including `tick`, returning the trace of that
+/// tick, line by line.
+pub fn explain(args: &Args, seed: u64, tick: u64) -> Result<Vec<String>> {
+    let mut simulation = Simulation::from_args(args, seed)?;
+    for _ in 1..tick {
+        simulation.tick()?;
+    }
+    let mut trace = vec![format!(
+        "tick {} of the run seeded with {}, under the '{}' policy",
+        tick,
+        seed,
+        simulation.policy()
+    )];
+    let before = simulation.buckets().buckets().clone();
+    let mut logged = simulation.events().get_all().len();
+
+    trace.push("1. fill".to_string());
+    simulation.fill();
+    logged = trace_events(&simulation, logged, &mut trace);
+
+    let sensor = simulation.buckets();
+    trace.push(format!(
+        "2. sensor snapshot: {}",
+        format_initial_data(sensor.buckets())
+    ));
+    if let (Some(mean), Some(variance), Some(gini)) =
+        (sensor.mean(), sensor.variance(), sensor.gini_coefficient())
+    {
+        trace.push(format!(
+            "   total {}, mean {:.2}, variance {:.2}, Gini {:.2}",
+            sensor.total(),
+            mean,
+            variance,
+            gini
+        ));
+    }
+    if let (Some((emptiest, min)), Some((fullest, max))) =
+        (sensor.get_smallest_bucket(), sensor.get_largest_bucket())
+    {
+        let balanced = if sensor.is_balanced(args.balance_tolerance) {
+            "yes"
+        } else {
+            "no"
+        };
+        trace.push(format!(
+            "   emptiest bucket {} ({}), fullest bucket {} ({}), balanced within {}: {}",
+            emptiest, min, fullest, max, args.balance_tolerance, balanced
+        ));
+    }
+
+    let policy = simulation.policy();
+    trace.push(format!("3. '{}' policy", policy));
+    if let Some(help) = policy
+        .to_possible_value()
+        .and_then(|value| value.get_help().map(ToString::to_string))
+    {
+        trace.push(format!("   {}", help));
+    }
+    let action = simulation.analyze()?;
+    logged = trace_events(&simulation, logged, &mut trace);
+
+    trace.push("4. actuation".to_string());
+    let filled = simulation.buckets().buckets().clone();
+    simulation.actuate(&action)?;
+    trace_events(&simulation, logged, &mut trace);
+
+    trace.push("5. changes over the tick: before => after the fill => after actuation".to_string());
+    let after = simulation.buckets().buckets();
+    let buckets = before.keys().chain(after.keys()).collect::<BTreeSet<_>>();
+    for bucket in buckets {
+        let (old, mid, new) = (before.get(bucket), filled.get(bucket), after.get(bucket));
+        if old == new {
+            continue;
+        }
+        let describe = |quantity: Option<&u64>| match quantity {
+            Some(quantity) => quantity.to_string(),
+            None => "gone".to_string(),
+        };
+        let delta = new.copied().unwrap_or(0) as i128 - old.copied().unwrap_or(0) as i128;
+        trace.push(format!(
+            "   bucket {}: {} => {} => {} ({:+})",
+            bucket,
+            describe(old),
+            describe(mid),
+            describe(new),
+            delta
+        ));
+    }
+    Ok(trace)
+}
+
+/// Adds every event logged since the first `logged` to the trace, returning how many have been
+/// logged now.
+fn trace_events(
+    simulation: &Simulation<impl Buckets + Sensor + FinalControlElement>,
+    logged: usize,
+    trace: &mut Vec<String>,
+) -> usize {
+    let events = simulation.events().get_all();
+    for event in events.range(logged..) {
+        trace.push(format!(
+            "   [{} {}] {}",
+            event.source, event.severity, event.message
+        ));
+    }
+    events.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn explains_the_decision_and_its_deltas() -> Result<()> {
+        // Hold back every fill, so only the policy changes anything.
+        let args = Args::try_parse_from([
+            "buckets",
+            "--policy",
+            "spread",
+            "-i",
+            "1:10,2:50,3:90",
+            "--fill-rate-limit",
+            "0",
+        ])?;
+        let trace = explain(&args, 7, 1)?;
+        assert_eq!(
+            trace[0],
+            "tick 1 of the run seeded with 7, under the 'Spread' policy"
+        );
+        for line in [
+            "2. sensor snapshot: 1:10,2:50,3:90",
+            "   [Controller Info] analyzed sensor data with 'Spread' policy => Transfer 3 -(40)-> 1",
+            "   [Actuator Info] applied action: Transfer 3 -(40)-> 1",
+        ] {
+            assert!(trace.iter().any(|traced| traced == line), "{:#?}", trace);
+        }
+        assert!(trace.ends_with(&[
+            "   bucket 1: 10 => 10 => 50 (+40)".to_string(),
+            "   bucket 3: 90 => 90 => 50 (-40)".to_string(),
+        ]));
+        Ok(())
+    }
+}
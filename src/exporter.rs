@@ -0,0 +1,112 @@
+//! Exporters write simulation output to files as the simulation runs. Their writes are buffered,
+//! so every exporter must be flushed before the program exits, otherwise the tail of the output is
+//! lost.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::events::Event;
+
+pub trait Exporter: Send {
+    fn export(&mut self, event: &Event) -> Result<()>;
+    fn flush(&mut self) -> Result<()>;
+}
+
+/// EventLogExporter writes every event to a file as a line of tab-separated timestamp, source,
+/// severity and message, followed by a `name=value` column for each of the event's fields.
+/// Timestamps are in RFC 3339, unless given a strftime format to use instead.
+pub struct EventLogExporter {
+    writer: BufWriter<File>,
+    timestamp_format: Option<String>,
+}
+
+impl EventLogExporter {
+    pub fn create(path: &Path, timestamp_format: Option<String>) -> Result<Self> {
+        Ok(EventLogExporter {
+            writer: BufWriter::new(File::create(path)?),
+            timestamp_format,
+        })
+    }
+}
+
+impl Exporter for EventLogExporter {
+    fn export(&mut self, event: &Event) -> Result<()> {
+        let timestamp = match &self.timestamp_format {
+            Some(format) => event.timestamp.format(format).to_string(),
+            None => event.timestamp.to_rfc3339(),
+        };
+        write!(
+            self.writer,
+            "{}\t{}\t{}\t{}",
+            timestamp, event.source, event.severity, event.message
+        )?;
+        for (name, value) in &event.fields {
+            write!(self.writer, "\t{}={}", name, value)?;
+        }
+        writeln!(self.writer)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{Local, TimeZone};
+
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::events::{EventSource, Events, Severity};
+
+    #[test]
+    fn closing_the_exporters_flushes_every_event() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("events-{}.log", std::process::id()));
+        let mut events = Events::new();
+        events.add_exporter(Box::new(EventLogExporter::create(&path, None)?));
+        for i in 0..1000 {
+            events.add_with_fields(
+                EventSource::Filler,
+                Severity::Info,
+                format!("event {}", i),
+                vec![(String::from("index"), i.to_string())],
+            );
+        }
+        let lines = || -> Result<Vec<String>> {
+            Ok(std::fs::read_to_string(&path)?
+                .lines()
+                .map(String::from)
+                .collect())
+        };
+        // The writes are buffered, so some are still missing before the exporters are closed.
+        assert!(lines()?.len() < 1000);
+        events.close_exporters()?;
+        let lines = lines()?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(lines.len(), 1000);
+        assert!(lines[999].ends_with("\tFiller\tInfo\tevent 999\tindex=999"));
+        Ok(())
+    }
+
+    #[test]
+    fn timestamps_follow_the_given_format() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("timestamps-{}.log", std::process::id()));
+        let start = Local.with_ymd_and_hms(2024, 3, 1, 12, 30, 5).unwrap();
+        let mut events = Events::with_clock(Arc::new(ManualClock::new(start)));
+        let format = String::from("%Y-%m-%d %H:%M:%S%.3f");
+        events.add_exporter(Box::new(EventLogExporter::create(&path, Some(format))?));
+        events.add(EventSource::System, String::from("started"));
+        events.close_exporters()?;
+        let contents = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(contents, "2024-03-01 12:30:05.000\tSystem\tInfo\tstarted\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+//! Participation counts how many transfers each bucket took part in, as either source or
+//! destination. In a real system every transfer wears on the pumps and valves involved, so a
+//! policy that keeps reaching for the same few buckets wears them out early. The fairness score
+//! sums this up as Jain's fairness index over the counts: 1 when every bucket took part equally
+//! often, down to 1/n when a single bucket out of n took part in all of them.
+
+use std::collections::HashMap;
+
+use crate::buckets::ids::BucketId;
+
+pub struct Participation {
+    counts: HashMap<BucketId, u64>,
+}
+
+impl Default for Participation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Participation {
+    pub fn new() -> Self {
+        Participation {
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn record_transfer(&mut self, source: BucketId, destination: BucketId) {
+        *self.counts.entry(source).or_default() += 1;
+        *self.counts.entry(destination).or_default() += 1;
+    }
+
+    /// Returns the fairness score over the given buckets, counting those that never took part as
+    /// zeroes, or None if none of them took part in any transfer.
+    pub fn fairness(&self, buckets: impl Iterator<Item = BucketId>) -> Option<f64> {
+        let counts = buckets
+            .map(|bucket| self.counts.get(&bucket).copied().unwrap_or(0) as f64)
+            .collect::<Vec<_>>();
+        let sum = counts.iter().sum::<f64>();
+        let sum_of_squares = counts.iter().map(|count| count * count).sum::<f64>();
+        if sum_of_squares == 0.0 {
+            return None;
+        }
+        Some(sum * sum / (counts.len() as f64 * sum_of_squares))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the fairness score over buckets 1 to `buckets` after the given transfers.
+    fn fairness(buckets: u64, transfers: &[(u64, u64)]) -> Option<f64> {
+        let mut participation = Participation::new();
+        for (source, destination) in transfers {
+            participation.record_transfer(BucketId(*source), BucketId(*destination));
+        }
+        participation.fairness((1..=buckets).map(BucketId))
+    }
+
+    #[test]
+    fn fairness_is_jains_index_over_participation() {
+        assert_eq!(fairness(3, &[]), None);
+        assert_eq!(fairness(3, &[(1, 2), (2, 3), (3, 1)]), Some(1.0));
+        // A bucket that never took part counts as a zero.
+        assert_eq!(fairness(3, &[(1, 2)]), Some(4.0 / 6.0));
+        // Bucket 1 takes part in every transfer, so it's worked three times as hard.
+        assert_eq!(fairness(4, &[(1, 2), (1, 3), (4, 1)]), Some(0.75));
+    }
+}
@@ -0,0 +1,255 @@
+//! A fixture is a compact, deterministic recording of a headless run: the seed and command-line
+//! arguments it was started with, followed by the expected bucket state after every tick.
+//! Replaying the arguments with the current code must reproduce every recorded state exactly,
+//! which makes a fixture usable as a regression test case.
+//!
+//! Fixtures are stored as plain text, one entry per line, e.g.:
+//!
+//! ```text
+//! seed 42
+//! arg --policy
+//! arg spread
+//! tick 1:46,2:55,3:55
+//! tick 1:51,2:55,3:51
+//! ```
+//!
+//! Arguments are stored one per line, and any flag that wasn't given takes its default value on
+//! replay. Files the run reads, like a `--script`, are given relative to the fixture. Bucket
+//! states use the same format as `--initial-data`. Older fixtures stored their `bucket-type`,
+//! `policy` and `initial` data as entries of their own, and still load.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+
+use crate::atomic_write;
+use crate::buckets::ids::BucketId;
+use crate::cli::{format_initial_data, parse_initial_data, Args};
+use crate::sensor::Sensor;
+use crate::simulation::Simulation;
+
+/// Flags that control recording itself, rather than the run being recorded.
+const RECORDING_FLAGS: [&str; 3] = ["--record-fixture", "--fixture-ticks", "--seed"];
+
+/// Flags whose value is a file the run reads. In a fixture, these files are given relative to the
+/// fixture itself, so that it replays the same from any directory.
+const PATH_FLAGS: [&str; 2] = ["--script", "--initial-data-file"];
+
+pub struct Fixture {
+    seed: u64,
+    args: Vec<String>,
+    states: Vec<HashMap<BucketId, u64>>,
+}
+
+impl Fixture {
+    /// Runs the configuration given by `args` for `ticks` ticks, recording the state after each
+    /// one. Flags that only control recording are dropped from `args`.
+    pub fn record(seed: u64, args: impl Iterator<Item = String>, ticks: u64) -> Result<Fixture> {
+        let mut fixture = Fixture {
+            seed,
+            args: replayable_args(args),
+            states: Vec::new(),
+        };
+        fixture.states = fixture.replay(ticks)?;
+        Ok(fixture)
+    }
+
+    /// Replays the fixture's configuration and checks that every recorded state is reproduced.
+    pub fn verify(&self) -> Result<()> {
+        let states = self.replay(self.states.len() as u64)?;
+        for (tick, (expected, actual)) in self.states.iter().zip(states.iter()).enumerate() {
+            if expected != actual {
+                return Err(anyhow!(
+                    "fixture diverged at tick {}: expected {}, got {}",
+                    tick + 1,
+                    format_initial_data(expected),
+                    format_initial_data(actual)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn ticks(&self) -> usize {
+        self.states.len()
+    }
+
+    fn replay(&self, ticks: u64) -> Result<Vec<HashMap<BucketId, u64>>> {
+        let argv = std::iter::once("buckets".to_string()).chain(self.args.iter().cloned());
+        let args = Args::try_parse_from(argv)?;
+        let mut simulation = Simulation::from_args(&args, self.seed)?;
+        let mut states = Vec::new();
+        for _ in 0..ticks {
+            simulation.tick()?;
+            states.push(simulation.buckets().buckets().clone());
+        }
+        Ok(states)
+    }
+
+    pub fn load(path: &Path) -> Result<Fixture> {
+        let contents = fs::read_to_string(path)?;
+        let mut seed = None;
+        let mut args = Vec::new();
+        let mut states = Vec::new();
+        for (lineno, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            let invalid = |e: String| anyhow!("{}:{}: {}", path.display(), lineno + 1, e);
+            match key {
+                "seed" => seed = Some(value.parse::<u64>().map_err(|e| invalid(e.to_string()))?),
+                "arg" => args.push(value.to_string()),
+                // Fixtures used to store a fixed set of settings instead of the arguments, and
+                // those still replay as the arguments they stood for.
+                "bucket-type" | "policy" | "initial" => {
+                    let flag = match key {
+                        "initial" => "--initial-data",
+                        "policy" => "--policy",
+                        _ => "--bucket-type",
+                    };
+                    args.extend([flag.to_string(), value.to_string()]);
+                }
+                "tick" => states.push(parse_initial_data(value).map_err(invalid)?),
+                _ => return Err(invalid(format!("unknown entry '{}'", key))),
+            }
+        }
+
+        let dir = directory_of(path);
+        Ok(Fixture {
+            seed: seed.ok_or_else(|| anyhow!("{}: missing 'seed' entry", path.display()))?,
+            args: map_paths(args, |file| dir.join(file)),
+            states,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut contents = String::new();
+        contents.push_str(&format!("seed {}\n", self.seed));
+        let dir = directory_of(path);
+        for arg in map_paths(self.args.clone(), |file| relative_to(file, dir)) {
+            contents.push_str(&format!("arg {}\n", arg));
+        }
+        for state in &self.states {
+            contents.push_str(&format!("tick {}\n", format_initial_data(state)));
+        }
+        atomic_write::write(path, contents.as_bytes())
+    }
+}
+
+/// Drops the recording flags (and their values) from the given arguments.
+fn replayable_args(args: impl Iterator<Item = String>) -> Vec<String> {
+    let mut replayable = Vec::new();
+    let mut skip_value = false;
+    for arg in args {
+        if skip_value {
+            skip_value = false;
+            continue;
+        }
+        match RECORDING_FLAGS.iter().find(|flag| arg.starts_with(*flag)) {
+            // --flag=value carries its own value, while --flag is followed by it.
+            Some(flag) => skip_value = arg.len() == flag.len(),
+            None => replayable.push(arg),
+        }
+    }
+    replayable
+}
+
+/// Rewrites the values of the path flags in the given arguments with `map`.
+fn map_paths(args: Vec<String>, map: impl Fn(&Path) -> PathBuf) -> Vec<String> {
+    let mut mapped = Vec::new();
+    let mut is_path = false;
+    for arg in args {
+        if is_path {
+            is_path = false;
+            mapped.push(map(Path::new(&arg)).display().to_string());
+            continue;
+        }
+        let path = PATH_FLAGS
+            .iter()
+            .find_map(|flag| Some((flag, arg.strip_prefix(flag)?)));
+        match path {
+            // --flag=value carries its own value, while --flag is followed by it.
+            Some((flag, value)) if value.starts_with('=') => mapped.push(format!(
+                "{}={}",
+                flag,
+                map(Path::new(&value[1..])).display()
+            )),
+            Some((_, "")) => {
+                is_path = true;
+                mapped.push(arg);
+            }
+            _ => mapped.push(arg),
+        }
+    }
+    mapped
+}
+
+/// Returns the directory the given file is in.
+fn directory_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Returns the path to `file` from `dir`, if `file` is somewhere under it, and its absolute path
+/// otherwise. Files that can't be found are left as given.
+fn relative_to(file: &Path, dir: &Path) -> PathBuf {
+    match (file.canonicalize(), dir.canonicalize()) {
+        (Ok(file), Ok(dir)) => file
+            .strip_prefix(&dir)
+            .map(Path::to_path_buf)
+            .unwrap_or(file),
+        _ => file.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixtures_reproduce() -> Result<()> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        let mut verified = 0;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "fixture") {
+                Fixture::load(&path)?
+                    .verify()
+                    .map_err(|e| e.context(path.display().to_string()))?;
+                verified += 1;
+            }
+        }
+        assert!(verified > 0, "no fixtures found in {}", dir.display());
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_entries_load_as_arguments() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("legacy-{}.fixture", std::process::id()));
+        fs::write(
+            &path,
+            "seed 7\nbucket-type n-buckets\npolicy spread\ninitial 1:45,2:72,3:38\n",
+        )?;
+        let fixture = Fixture::load(&path);
+        fs::remove_file(&path)?;
+        assert_eq!(
+            fixture?.args,
+            [
+                "--bucket-type",
+                "n-buckets",
+                "--policy",
+                "spread",
+                "--initial-data",
+                "1:45,2:72,3:38"
+            ]
+        );
+        Ok(())
+    }
+}
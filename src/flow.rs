@@ -0,0 +1,95 @@
+//! Net flow tracks how much fluid went into and out of each bucket recently, counting fills and
+//! transferred amounts as they happen. A bucket whose net flow stays positive is a sink under the
+//! current policy, and one whose net flow stays negative is a source.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use chrono::{DateTime, Local, TimeDelta};
+
+use crate::buckets::ids::BucketId;
+use crate::clock::Clock;
+
+pub struct NetFlow {
+    // Every flow in the window, as when it happened, the bucket and the signed amount, oldest
+    // first.
+    flows: VecDeque<(DateTime<Local>, BucketId, i64)>,
+    window: TimeDelta,
+    clock: Arc<dyn Clock>,
+}
+
+impl NetFlow {
+    pub fn new(window: TimeDelta, clock: Arc<dyn Clock>) -> Self {
+        NetFlow {
+            flows: VecDeque::new(),
+            window,
+            clock,
+        }
+    }
+
+    pub fn record_fill(&mut self, bucket: BucketId, amount: u64) {
+        self.record(bucket, amount as i64);
+    }
+
+    pub fn record_transfer(&mut self, source: BucketId, destination: BucketId, amount: u64) {
+        self.record(source, -(amount as i64));
+        self.record(destination, amount as i64);
+    }
+
+    /// Returns the bucket's inflow minus its outflow over the trailing window.
+    pub fn net(&self, bucket: BucketId) -> i64 {
+        let since = self.clock.now() - self.window;
+        self.flows
+            .iter()
+            .rev()
+            .take_while(|(timestamp, _, _)| *timestamp >= since)
+            .filter(|(_, flowed, _)| *flowed == bucket)
+            .map(|(_, _, amount)| amount)
+            .sum()
+    }
+
+    fn record(&mut self, bucket: BucketId, amount: i64) {
+        if amount == 0 {
+            return;
+        }
+        let now = self.clock.now();
+        // Forget whatever has fallen out of the window, so the record stays bounded.
+        while self
+            .flows
+            .front()
+            .is_some_and(|(timestamp, _, _)| *timestamp < now - self.window)
+        {
+            self.flows.pop_front();
+        }
+        self.flows.push_back((now, bucket, amount));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn net_flow_counts_fills_and_transfers_in_the_window() {
+        let clock = Arc::new(ManualClock::new(DateTime::<Local>::from(
+            DateTime::UNIX_EPOCH,
+        )));
+        let mut flow = NetFlow::new(TimeDelta::seconds(10), clock.clone());
+        flow.record_fill(BucketId(1), 30);
+        clock.advance(TimeDelta::seconds(5));
+        flow.record_transfer(BucketId(1), BucketId(2), 20);
+        flow.record_fill(BucketId(2), 5);
+        assert_eq!(flow.net(BucketId(1)), 10);
+        assert_eq!(flow.net(BucketId(2)), 25);
+        assert_eq!(flow.net(BucketId(3)), 0);
+
+        // The fill falls out of the window, leaving bucket 1 a net source.
+        clock.advance(TimeDelta::seconds(6));
+        assert_eq!(flow.net(BucketId(1)), -20);
+        assert_eq!(flow.net(BucketId(2)), 25);
+        clock.advance(TimeDelta::seconds(5));
+        assert_eq!(flow.net(BucketId(1)), 0);
+        assert_eq!(flow.net(BucketId(2)), 0);
+    }
+}
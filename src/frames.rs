@@ -0,0 +1,81 @@
+//! Frame dumps save every frame the TUI draws as a plain text file, numbered in drawing order, so a
+//! run can be turned into a recording for documentation, or its layout inspected after the fact.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use ratatui::buffer::Buffer;
+
+pub struct FrameDump {
+    dir: PathBuf,
+    frames: u64,
+}
+
+impl FrameDump {
+    /// Creates a dump into the given directory, creating it if needed.
+    pub fn create(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(FrameDump {
+            dir: dir.to_path_buf(),
+            frames: 0,
+        })
+    }
+
+    /// Writes the given frame to the next file in the sequence, e.g. `frame-000001.txt`.
+    pub fn dump(&mut self, buffer: &Buffer) -> io::Result<()> {
+        self.frames += 1;
+        let path = self.dir.join(format!("frame-{:06}.txt", self.frames));
+        fs::write(path, frame_text(buffer))
+    }
+}
+
+/// Renders the buffer's symbols as lines of text, dropping styling and trailing whitespace.
+fn frame_text(buffer: &Buffer) -> String {
+    let area = buffer.area;
+    let mut text = String::new();
+    for y in area.top()..area.bottom() {
+        let line = (area.left()..area.right())
+            .map(|x| buffer[(x, y)].symbol())
+            .collect::<String>();
+        text.push_str(line.trim_end());
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::backend::TestBackend;
+    use ratatui::widgets::Paragraph;
+    use ratatui::Terminal;
+
+    use super::*;
+
+    #[test]
+    fn every_drawn_frame_is_dumped() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("frames-{}", std::process::id()));
+        let mut dump = FrameDump::create(&dir)?;
+        let mut terminal = Terminal::new(TestBackend::new(20, 3))?;
+        for tick in 1..=3 {
+            let frame = terminal
+                .draw(|f| f.render_widget(Paragraph::new(format!("tick {}  ", tick)), f.area()))?;
+            dump.dump(frame.buffer)?;
+        }
+        let mut files = fs::read_dir(&dir)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        files.sort();
+        assert_eq!(
+            files,
+            ["frame-000001.txt", "frame-000002.txt", "frame-000003.txt"]
+        );
+        // Trailing whitespace is dropped, but the lines are all kept.
+        assert_eq!(
+            fs::read_to_string(dir.join("frame-000002.txt"))?,
+            "tick 2\n\n\n"
+        );
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+//! History keeps the bucket quantities recorded on the most recent fill ticks, along with when
+//! they were recorded, so that past quantities can be looked up after the fact. It's bounded to a
+//! fixed number of samples, forgetting the oldest once full.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+
+use chrono::{DateTime, Local};
+
+use crate::buckets::ids::BucketId;
+use crate::clock::Clock;
+use crate::sensor::Sensor;
+
+pub struct History {
+    // Samples ordered by when they were recorded, oldest first.
+    samples: VecDeque<(DateTime<Local>, HashMap<BucketId, u64>)>,
+    size: usize,
+    // How many samples were ever recorded, including those since forgotten.
+    recorded: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl History {
+    pub fn new(size: usize, clock: Arc<dyn Clock>) -> Self {
+        History {
+            samples: VecDeque::with_capacity(size),
+            size,
+            recorded: 0,
+            clock,
+        }
+    }
+
+    /// Records the current quantities of the buckets.
+    pub fn record<S: Sensor>(&mut self, sensor: &S) {
+        if self.samples.len() == self.size {
+            self.samples.pop_front();
+        }
+        self.samples
+            .push_back((self.clock.now(), sensor.buckets().clone()));
+        self.recorded += 1;
+    }
+
+    /// Returns, for every bucket in the latest sample, the tick on which it came within
+    /// `tolerance` of the equilibrium (the mean quantity at the time) and stayed there through the
+    /// latest sample, or None if it isn't there now. Ticks count every recorded sample from 1, but
+    /// only the samples still held are looked at, so a bucket that settled before them is reported
+    /// as settling on the oldest one held.
+    pub fn time_to_equilibrium(&self, tolerance: u64) -> BTreeMap<BucketId, Option<u64>> {
+        let Some((_, latest)) = self.samples.back() else {
+            return BTreeMap::new();
+        };
+        let first_tick = self.recorded - self.samples.len() as u64 + 1;
+        latest
+            .keys()
+            .map(|bucket| {
+                // Walk back from the latest sample for as long as the bucket stays settled.
+                let settled = self
+                    .samples
+                    .iter()
+                    .rev()
+                    .take_while(|(_, quantities)| {
+                        let total = quantities
+                            .values()
+                            .fold(0u64, |total, value| total.saturating_add(*value));
+                        let mean = total as f64 / quantities.len() as f64;
+                        quantities.get(bucket).is_some_and(|quantity| {
+                            (*quantity as f64 - mean).abs() <= tolerance as f64
+                        })
+                    })
+                    .count() as u64;
+                let ticks = (settled > 0).then(|| first_tick + self.samples.len() as u64 - settled);
+                (*bucket, ticks)
+            })
+            .collect()
+    }
+
+    /// Returns the quantities of the bucket over the latest `window` samples, oldest first. Samples
+    /// from before the bucket existed are left out.
+    pub fn series(&self, bucket: BucketId, window: usize) -> Vec<u64> {
+        self.samples
+            .iter()
+            .skip(self.samples.len().saturating_sub(window))
+            .filter_map(|(_, quantities)| quantities.get(&bucket).copied())
+            .collect()
+    }
+
+    /// Returns the quantity of the bucket as recorded at or just before `time`. Returns None if
+    /// `time` is before the earliest sample, or if the bucket didn't exist at the time. Times after
+    /// the latest sample get the latest quantity.
+    #[allow(dead_code)]
+    pub fn value_at(&self, bucket: BucketId, time: DateTime<Local>) -> Option<u64> {
+        // Samples are ordered by time, so the one we want is the last one not after `time`.
+        let recorded = self.samples.partition_point(|(at, _)| *at <= time);
+        let (_, quantities) = self.samples.get(recorded.checked_sub(1)?)?;
+        quantities.get(&bucket).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::sensor::Readings;
+
+    // Records bucket 1 holding 10, 20 and 30, ten seconds apart, starting at `start`.
+    fn history(start: DateTime<Local>) -> History {
+        let clock = Arc::new(ManualClock::new(start));
+        let mut history = History::new(10, clock.clone());
+        for quantity in [10, 20, 30] {
+            history.record(&Readings::new(
+                HashMap::from([(BucketId(1), quantity)]),
+                100,
+            ));
+            clock.advance(TimeDelta::seconds(10));
+        }
+        history
+    }
+
+    #[test]
+    fn value_at() {
+        let start = DateTime::<Local>::from(DateTime::UNIX_EPOCH);
+        let history = history(start);
+        let at = |seconds| history.value_at(BucketId(1), start + TimeDelta::seconds(seconds));
+        // Exactly on a sample.
+        assert_eq!(at(0), Some(10));
+        assert_eq!(at(10), Some(20));
+        assert_eq!(at(20), Some(30));
+        // Between samples, the one just before.
+        assert_eq!(at(5), Some(10));
+        assert_eq!(at(19), Some(20));
+        // Before the earliest sample, and after the latest.
+        assert_eq!(at(-1), None);
+        assert_eq!(at(1000), Some(30));
+        // A bucket that wasn't recorded.
+        assert_eq!(history.value_at(BucketId(2), start), None);
+    }
+
+    #[test]
+    fn time_to_equilibrium() {
+        let start = DateTime::<Local>::from(DateTime::UNIX_EPOCH);
+        let mut history = History::new(10, Arc::new(ManualClock::new(start)));
+        // Every tick averages 50.
+        for quantities in [
+            [10, 50, 90],
+            [30, 66, 54],
+            [47, 50, 53],
+            [49, 50, 51],
+            [45, 56, 49],
+        ] {
+            let data = (1..).map(BucketId).zip(quantities).collect();
+            history.record(&Readings::new(data, 100));
+        }
+        assert_eq!(
+            history.time_to_equilibrium(5),
+            BTreeMap::from([
+                (BucketId(1), Some(3)),
+                // Bucket 2 settled at the start, but drifted off again at the end.
+                (BucketId(2), None),
+                (BucketId(3), Some(2)),
+            ])
+        );
+        assert_eq!(
+            History::new(10, Arc::new(ManualClock::new(start))).time_to_equilibrium(5),
+            BTreeMap::new()
+        );
+
+        // Totals past u64::MAX saturate rather than overflow.
+        let mut history = History::new(10, Arc::new(ManualClock::new(start)));
+        let data = HashMap::from([(BucketId(1), u64::MAX), (BucketId(2), u64::MAX)]);
+        history.record(&Readings::new(data, u64::MAX));
+        assert_eq!(history.time_to_equilibrium(0).len(), 2);
+    }
+
+    #[test]
+    fn series() {
+        let start = DateTime::<Local>::from(DateTime::UNIX_EPOCH);
+        let mut history = History::new(4, Arc::new(ManualClock::new(start)));
+        for (first, second) in [
+            (10, None),
+            (20, Some(1)),
+            (30, Some(2)),
+            (40, Some(3)),
+            (50, None),
+        ] {
+            let mut data = HashMap::from([(BucketId(1), first)]);
+            data.extend(second.map(|quantity| (BucketId(2), quantity)));
+            history.record(&Readings::new(data, 100));
+        }
+        // The first sample was evicted, since the history only holds 4.
+        assert_eq!(history.series(BucketId(1), 10), [20, 30, 40, 50]);
+        assert_eq!(history.series(BucketId(1), 2), [40, 50]);
+        assert_eq!(history.series(BucketId(1), 0), Vec::<u64>::new());
+        // Bucket 2 is left out of the samples it wasn't in.
+        assert_eq!(history.series(BucketId(2), 10), [1, 2, 3]);
+        assert_eq!(history.series(BucketId(2), 2), [3]);
+        assert_eq!(history.series(BucketId(3), 10), Vec::<u64>::new());
+    }
+}
@@ -0,0 +1,75 @@
+//! Labels decide what each bucket is called in the chart. Buckets can be given names with
+//! `--bucket-names`, and the TUI can toggle back to their raw `B{id}` labels, which is handy for
+//! matching the chart up with the event log and the command line.
+
+use std::collections::HashMap;
+
+use crate::buckets::ids::BucketId;
+use crate::buckets::Datum;
+
+pub struct Labels {
+    names: HashMap<BucketId, String>,
+    show_names: bool,
+}
+
+impl Labels {
+    pub fn new(names: HashMap<BucketId, String>) -> Self {
+        Labels {
+            names,
+            show_names: true,
+        }
+    }
+
+    /// Returns whether any bucket has a name, i.e. whether toggling does anything.
+    pub fn has_names(&self) -> bool {
+        !self.names.is_empty()
+    }
+
+    pub fn showing_names(&self) -> bool {
+        self.show_names
+    }
+
+    /// Switches between showing bucket names and raw bucket labels.
+    pub fn toggle(&mut self) {
+        self.show_names = !self.show_names;
+    }
+
+    /// Returns the label to draw for the given bucket. Buckets without a name always use their raw
+    /// label.
+    pub fn label(&self, datum: &Datum) -> String {
+        match self.names.get(&datum.bucket) {
+            Some(name) if self.show_names => name.clone(),
+            _ => datum.label.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datum(bucket: u64) -> Datum {
+        Datum {
+            bucket: BucketId(bucket),
+            label: format!("B{}", bucket),
+            quantity: 0,
+            tag: None,
+        }
+    }
+
+    #[test]
+    fn toggling_switches_between_names_and_raw_labels() {
+        let mut labels = Labels::new(HashMap::from([(BucketId(1), String::from("intake"))]));
+        assert!(labels.has_names());
+        assert!(labels.showing_names());
+        assert_eq!(labels.label(&datum(1)), "intake");
+        assert_eq!(labels.label(&datum(2)), "B2");
+        labels.toggle();
+        assert!(!labels.showing_names());
+        assert_eq!(labels.label(&datum(1)), "B1");
+        assert_eq!(labels.label(&datum(2)), "B2");
+        labels.toggle();
+        assert_eq!(labels.label(&datum(1)), "intake");
+        assert!(!Labels::new(HashMap::new()).has_names());
+    }
+}
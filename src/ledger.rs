@@ -0,0 +1,117 @@
+//! The ledger accounts for all of the fluid entering and leaving the system, independently of the
+//! buckets themselves: fills add to it, and removed buckets and capacity changes spill out of it.
+//! Transfers only move fluid around, so at any point the buckets must hold exactly
+//! `initial + filled - spilled` between them. Anything else means fluid was created or destroyed
+//! somewhere it shouldn't have been, i.e. a bug.
+
+use crate::sensor::Sensor;
+
+pub struct Ledger {
+    initial: u64,
+    filled: u64,
+    spilled: u64,
+    // Accumulated discrepancies that were already reported, so each one is only reported once.
+    discrepancy: i128,
+}
+
+impl Ledger {
+    /// Opens the ledger with the total the buckets start out with.
+    pub fn new<S: Sensor>(sensor: &S) -> Self {
+        Ledger {
+            initial: sensor.total(),
+            filled: 0,
+            spilled: 0,
+            discrepancy: 0,
+        }
+    }
+
+    pub fn fill(&mut self, amount: u64) {
+        self.filled = self.filled.saturating_add(amount);
+    }
+
+    pub fn spill(&mut self, amount: u64) {
+        self.spilled = self.spilled.saturating_add(amount);
+    }
+
+    /// Checks that the buckets hold what the ledger expects, returning a description of the
+    /// discrepancy if they don't. A discrepancy is only reported once, after which the ledger
+    /// expects the buckets to hold what they do now.
+    pub fn check<S: Sensor>(&mut self, sensor: &S) -> Option<String> {
+        let expected = self.initial as i128 + self.filled as i128 - self.spilled as i128;
+        let actual = sensor.total() as i128;
+        let discrepancy = actual - expected;
+        if discrepancy == self.discrepancy {
+            return None;
+        }
+        self.discrepancy = discrepancy;
+        Some(format!(
+            "fluid not conserved: buckets hold {}, but the ledger expects {} (initial {} + filled \
+             {} - spilled {})",
+            actual, expected, self.initial, self.filled, self.spilled
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::actuator::FinalControlElement;
+    use crate::buckets::ids::BucketId;
+    use crate::buckets::n_buckets::NBuckets;
+    use crate::buckets::{Buckets, FillStrategy};
+
+    // Buckets 1, 2 and 3 hold 95, 10 and 50 of 100.
+    fn buckets() -> NBuckets {
+        let data = HashMap::from([(BucketId(1), 95), (BucketId(2), 10), (BucketId(3), 50)]);
+        NBuckets::new(
+            data,
+            100,
+            FillStrategy::Steady,
+            None,
+            None,
+            HashMap::new(),
+            Some(0),
+        )
+    }
+
+    #[test]
+    fn ledger_balances_through_every_mutation() -> Result<()> {
+        let mut buckets = buckets();
+        let mut ledger = Ledger::new(&buckets);
+        for _ in 0..20 {
+            for fill in buckets.fill() {
+                ledger.fill(fill.added + fill.spilled);
+                ledger.spill(fill.spilled);
+            }
+            assert_eq!(ledger.check(&buckets), None);
+        }
+        buckets.transfer(BucketId(1), BucketId(2), 30)?;
+        assert_eq!(ledger.check(&buckets), None);
+        ledger.spill(buckets.drain_each(5));
+        assert_eq!(ledger.check(&buckets), None);
+        ledger.spill(buckets.remove_bucket(BucketId(3))?);
+        assert_eq!(ledger.check(&buckets), None);
+        Ok(())
+    }
+
+    #[test]
+    fn discrepancies_are_flagged_once() -> Result<()> {
+        let mut buckets = buckets();
+        let mut ledger = Ledger::new(&buckets);
+        // A fill the ledger never hears of creates fluid out of nowhere.
+        buckets.pour(BucketId(2), 7)?;
+        assert_eq!(
+            ledger.check(&buckets).as_deref(),
+            Some(
+                "fluid not conserved: buckets hold 162, but the ledger expects 155 (initial 155 + \
+                 filled 0 - spilled 0)"
+            )
+        );
+        assert_eq!(ledger.check(&buckets), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+//! The buckets simulator: bucket models, the sensors reading them, the policies and controller that
+//! rebalance them, and the pieces `main.rs` assembles into the TUI and the headless modes.
+
+pub mod actuator;
+pub mod animation;
+pub mod atomic_write;
+pub mod bench;
+pub mod buckets;
+pub mod cli;
+pub mod clipboard;
+pub mod clock;
+pub mod controller;
+pub mod convergence;
+pub mod cooldown;
+pub mod determinism;
+pub mod events;
+pub mod explain;
+pub mod exporter;
+pub mod fairness;
+pub mod fixture;
+pub mod flow;
+pub mod frames;
+pub mod history;
+pub mod labels;
+pub mod ledger;
+pub mod metrics;
+pub mod monte_carlo;
+pub mod overflow;
+pub mod pause;
+pub mod policy;
+pub mod policy_file;
+pub mod scheduler;
+pub mod script;
+pub mod scroll;
+pub mod sensor;
+pub mod simulation;
+#[cfg(unix)]
+pub mod state_dump;
+pub mod state_store;
+pub mod theme;
@@ -1,166 +1,693 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use chrono::{Local, TimeDelta};
+use clap::{CommandFactory, FromArgMatches};
 use crossterm::{
     event::{DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use futures::{FutureExt, StreamExt};
+use itertools::Itertools;
 use ratatui::{
     backend::{Backend, CrosstermBackend},
     layout::{Constraint, Direction, Layout, Rect},
-    style::{Color, Modifier, Style},
+    style::{Modifier, Style},
     text::{Line, Span},
-    widgets::{BarChart, Block, Borders, List, ListItem, ListState},
+    widgets::{
+        Bar, BarChart, BarGroup, Block, Borders, List, ListItem, ListState, Paragraph, Sparkline,
+        Wrap,
+    },
     Frame, Terminal,
 };
 use std::{
+    collections::BTreeMap,
     error::Error,
     io::{self, Stdout},
+    path::Path,
     sync::Arc,
     time::Duration,
 };
 use tokio::{
-    sync::{mpsc, Mutex},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Mutex,
+    },
     time::sleep,
 };
 use tokio_util::sync::CancellationToken;
 
-use self::{
-    actuator::{Actuator, FinalControlElement},
-    buckets::{n_buckets::NBuckets, BucketType, Buckets},
-    cli::Args,
-    controller::Controller,
-    events::Events,
-    sensor::Sensor,
+use buckets::{
+    actuator::{Action, Actuator, FinalControlElement},
+    animation::{Highlight, Settling, TransferAnimations},
+    atomic_write, bench,
+    buckets::{any_buckets::AnyBuckets, BucketType, Buckets, Datum},
+    cli::{self, check_capacity, format_initial_data, Args, Setting},
+    clipboard::Clipboard,
+    clock::SystemClock,
+    controller::{Controller, ManualOverride, Schedule},
+    convergence::{Convergence, Tolerance},
+    determinism,
+    events::{self, EventSource, Events, Severity},
+    explain,
+    exporter::EventLogExporter,
+    fixture::Fixture,
+    flow::NetFlow,
+    frames::FrameDump,
+    history::History,
+    labels::Labels,
+    ledger::Ledger,
+    metrics::{self, Metrics},
+    monte_carlo,
+    overflow::{AutoGrow, Overflows},
+    pause::Pause,
+    policy::Policy,
+    policy_file,
+    script::{Director, Script, Step},
+    scroll::EventScroll,
+    sensor::{Calibration, Sensor, SensorNoise},
+    simulation::Simulation,
+    state_dump,
+    state_store::{FileStateStore, SavedState, StateStore},
+    theme::Theme,
 };
 
-mod actuator;
-mod buckets;
-mod cli;
-mod controller;
-mod events;
-mod policy;
-mod sensor;
-
 // Updated main function
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // Parse command line arguments
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let config = cli::effective_config(&matches);
+    if args.dump_config {
+        for setting in &config {
+            let source = if setting.defaulted { " (default)" } else { "" };
+            eprintln!("{}={}{}", setting.flag, setting.value, source);
+        }
+    }
+
+    if args.list_policies {
+        println!("{}", cli::list_variants::<Policy>());
+        return Ok(());
+    }
+    if args.list_bucket_types {
+        println!("{}", cli::list_variants::<BucketType>());
+        return Ok(());
+    }
+
+    if args.bench_policies {
+        println!(
+            "{:<16} {:>6} {:>10} {:>14}",
+            "policy", "size", "iterations", "mean"
+        );
+        for measurement in bench::run(&args.bench_sizes, &args.policy_params())? {
+            println!(
+                "{:<16} {:>6} {:>10} {:>14?}",
+                measurement.policy.to_string(),
+                measurement.size,
+                measurement.iterations,
+                measurement.mean
+            );
+        }
+        return Ok(());
+    }
+
+    // Fixtures are recorded and verified headless, so handle them before touching the terminal.
+    if let Some(path) = &args.record_fixture {
+        let seed = args.seed.unwrap_or_else(rand::random);
+        let fixture = Fixture::record(seed, std::env::args().skip(1), args.fixture_ticks)?;
+        fixture.save(path)?;
+        println!("recorded {} ticks to {}", fixture.ticks(), path.display());
+        return Ok(());
+    }
+    if let Some(path) = &args.verify_fixture {
+        let fixture = Fixture::load(path)?;
+        fixture.verify()?;
+        println!(
+            "reproduced {} ticks from {}",
+            fixture.ticks(),
+            path.display()
+        );
+        return Ok(());
+    }
+
+    if args.verify_determinism {
+        let seed = args.seed.unwrap_or_else(rand::random);
+        determinism::verify(&args, seed, args.fixture_ticks)?;
+        println!(
+            "seed {}: {} ticks ran identically twice, and resumed halfway without diverging",
+            seed, args.fixture_ticks
+        );
+        return Ok(());
+    }
+
+    if let Some(tick) = args.explain {
+        let seed = args.seed.unwrap_or_else(rand::random);
+        for line in explain::explain(&args, seed, tick)? {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+
+    if let Some(runs) = args.runs {
+        let seed = args.seed.unwrap_or_else(rand::random);
+        let summary = monte_carlo::run(&args, seed, runs, args.fixture_ticks)?;
+        println!(
+            "{} runs of {} ticks, seeded from {}",
+            summary.runs, args.fixture_ticks, seed
+        );
+        println!(
+            "{:<18} {:>5} {:>10} {:>10}",
+            "metric", "runs", "mean", "stddev"
+        );
+        for (metric, stats) in [
+            ("convergence ticks", &summary.convergence_ticks),
+            ("transfers", &summary.transfers),
+            ("final variance", &summary.final_variance),
+        ] {
+            println!(
+                "{:<18} {:>5} {:>10.2} {:>10.2}",
+                metric, stats.count, stats.mean, stats.stddev
+            );
+        }
+        return Ok(());
+    }
+
+    // Load the theme before taking over the terminal, so a bad theme file is reported plainly.
+    let theme = match &args.theme_from_file {
+        Some(path) => Theme::from_file(path)?,
+        None => Theme::default(),
+    };
+
+    // Likewise, load any saved state up front. It takes the place of the initial data.
+    let mut state_store = args
+        .state_file
+        .as_ref()
+        .map(|path| Box::new(FileStateStore::new(path)) as Box<dyn StateStore>);
+    let saved_state = match &state_store {
+        Some(store) => store.load_state()?,
+        None => None,
+    };
+
+    // Use the saved state if there is one, and the parsed initial data otherwise
+    let (initial_data, fill_rng) = match saved_state {
+        Some(state) => {
+            // The state was saved with whatever capacity that run had, which may have been more.
+            check_capacity(&state.buckets, args.max_quantity)
+                .context("the saved state doesn't fit in --max-quantity")?;
+            (state.buckets, state.fill_rng)
+        }
+        None => (args.initial_state()?, None),
+    };
+
+    let script = args.script.as_deref().map(Script::load).transpose()?;
+
+    let frames = match &args.record_frames {
+        Some(dir) => Some(FrameDump::create(dir)?),
+        None => None,
+    };
 
     // Setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    execute!(stdout, EnterAlternateScreen)?;
+    // Nothing handles mouse events yet, but capturing them takes over text selection, so leave
+    // that to the terminal when asked to.
+    let capture_mouse = !args.no_mouse;
+    if capture_mouse {
+        execute!(stdout, EnableMouseCapture)?;
+    }
     let backend = CrosstermBackend::new(stdout);
     let terminal = Arc::new(Mutex::new(Terminal::new(backend)?));
 
-    // Use the parsed initial data
-    let initial_data = args.initial_data.clone();
+    let mut events = Events::new_with_capacity(args.event_log_capacity as usize);
+    if let Some(path) = &args.event_log_file {
+        events.add_exporter(Box::new(EventLogExporter::create(
+            path,
+            args.timestamp_format.clone(),
+        )?));
+    }
+    add_banner(&mut events, &args.banner, &config);
+    let events = Arc::new(Mutex::new(events));
 
-    let events = Arc::new(Mutex::new(Events::new()));
+    // Create the buckets based on args
+    let mut buckets = Simulation::buckets_from_args(&args, initial_data, args.seed);
+    // A saved RNG carries on from where the saved run left off, in place of the seed.
+    if let Some(rng) = fill_rng {
+        buckets.set_rng(rng);
+    }
+    let buckets = Arc::new(Mutex::new(buckets));
 
-    // Create the appropriate bucket type based on args
-    let buckets = match args.bucket_type {
-        BucketType::NBuckets => Arc::new(Mutex::new(NBuckets::new(initial_data))),
-    };
+    let ledger = Arc::new(Mutex::new(Ledger::new(&*buckets.lock().await)));
 
     const CONTROL_SIGNAL_BUFFER_SIZE: usize = 10;
     let (control_signal_tx, control_signal_rx) = mpsc::channel(CONTROL_SIGNAL_BUFFER_SIZE);
 
     // Use the selected policy
-    let controller = Arc::new(Controller::new(
+    let sensor_noise =
+        (args.sensor_noise > 0.0).then(|| SensorNoise::new(args.sensor_noise, args.seed));
+    let calibration =
+        (!args.calibration.is_empty()).then(|| Calibration::new(args.calibration_offsets()));
+    let manual = ManualOverride::new(args.manual);
+    let tolerance = Tolerance::new(args.balance_tolerance);
+    let controller = Arc::new(Mutex::new(Controller::new(
         args.policy,
+        args.policy_params(),
         buckets.clone(),
+        sensor_noise,
+        calibration,
+        args.readonly(),
+        manual.clone(),
+        tolerance.clone(),
+        args.seed,
         events.clone(),
-        control_signal_tx,
-    ));
+        control_signal_tx.clone(),
+    )));
 
+    // Both the filler and the actuator record into this, so it's created ahead of either.
+    let flow = Arc::new(Mutex::new(NetFlow::new(FLOW_WINDOW, Arc::new(SystemClock))));
+    let animations = args
+        .animate_transfers
+        .then(|| Arc::new(Mutex::new(TransferAnimations::new())));
     let actuator = Arc::new(Mutex::new(Actuator::new(
         buckets.clone(),
         events.clone(),
         control_signal_rx,
+        args.min_buckets as usize,
+        animations.clone(),
+        ledger.clone(),
+        flow.clone(),
     )));
 
+    let history = Arc::new(Mutex::new(History::new(
+        args.history_size as usize,
+        Arc::new(SystemClock),
+    )));
+    let summary = args.summary_file.clone();
+    let max_ticks = args.max_ticks;
+    let active_policy = Arc::new(std::sync::Mutex::new(args.policy));
+    let script = script.map(|script| {
+        let director = Director::new(
+            controller.clone(),
+            args.policy_params(),
+            active_policy.clone(),
+        );
+        (script, director)
+    });
+    let tui = Tui {
+        terminal: terminal.clone(),
+        theme,
+        animations,
+        settling: args.settle.then(Settling::new),
+        labels: Labels::new(args.bucket_names()),
+        timestamp_format: args
+            .timestamp_format
+            .clone()
+            .unwrap_or_else(|| DEFAULT_TIMESTAMP_FORMAT.to_string()),
+        manual,
+        tolerance: tolerance.clone(),
+        pause: Pause::new(),
+        status: Status {
+            policy: active_policy,
+            bucket_type: args.bucket_type,
+        },
+        frames,
+        convergence: Arc::new(Mutex::new(Convergence::new(
+            args.convergence_window as usize,
+            tolerance.clone(),
+        ))),
+        ledger,
+        history: history.clone(),
+        flow,
+    };
     let res = run(
         args,
-        terminal.clone(),
+        tui,
         events.clone(),
-        buckets,
+        buckets.clone(),
         controller,
-        actuator,
+        actuator.clone(),
+        control_signal_tx,
+        script,
     )
     .await;
 
     // Restore terminal
     let mut terminal = terminal.lock().await;
     disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    if capture_mouse {
+        execute!(terminal.backend_mut(), DisableMouseCapture)?;
+    }
     terminal.show_cursor()?;
 
+    if let Some(store) = &mut state_store {
+        let buckets = buckets.lock().await;
+        store.save_state(&SavedState {
+            buckets: buckets.buckets().clone(),
+            fill_rng: Some(buckets.rng().clone()),
+        })?;
+    }
+    // A run with a tick limit is meant to be looked at once it's over.
+    if max_ticks.is_some() {
+        println!(
+            "final state: {}",
+            format_initial_data(buckets.lock().await.buckets())
+        );
+    }
+    // Buckets that were removed along the way don't count towards fairness.
+    let fairness = actuator
+        .lock()
+        .await
+        .participation()
+        .fairness(buckets.lock().await.buckets().keys().copied());
+    if let Some(fairness) = fairness {
+        println!("transfer fairness: {:.2}", fairness);
+    }
+    if let Some(path) = summary {
+        write_summary(&path, &*history.lock().await, tolerance.get(), fairness)?;
+    }
     if let Err(err) = res {
         println!("failed to run simulation: {:?}", err);
     }
+    if let Some(fatal) = events.lock().await.fatal() {
+        return Err(anyhow::anyhow!("strict mode: aborted on {}", fatal).into());
+    }
 
     Ok(())
 }
 
+/// Writes the JSON summary of the run to `path`. Buckets are keyed by their ID.
+fn write_summary(
+    path: &Path,
+    history: &History,
+    tolerance: u64,
+    fairness: Option<f64>,
+) -> Result<()> {
+    let time_to_equilibrium = history
+        .time_to_equilibrium(tolerance)
+        .into_iter()
+        .map(|(bucket, ticks)| (bucket.to_string(), ticks))
+        .collect::<BTreeMap<_, _>>();
+    let summary = serde_json::json!({
+        "time_to_equilibrium": time_to_equilibrium,
+        "transfer_fairness": fairness,
+    });
+    let contents = serde_json::to_string_pretty(&summary)? + "\n";
+    atomic_write::write(path, contents.as_bytes())
+}
+
+/// Opens the log with a banner identifying the run and everything it was configured with, so that
+/// exported logs describe themselves.
+fn add_banner(events: &mut Events, banner: &str, config: &[Setting]) {
+    let run_id = format!("{:016x}", rand::random::<u64>());
+    let mut fields = vec![("run-id".to_string(), run_id)];
+    fields.extend(config.iter().map(|setting| {
+        let name = setting.flag.trim_start_matches('-');
+        (name.to_string(), setting.value.clone())
+    }));
+    events.add_with_fields(
+        EventSource::System,
+        Severity::Info,
+        banner.to_string(),
+        fields,
+    );
+}
+
+/// How far back the net flow shown next to each bucket looks.
+const FLOW_WINDOW: TimeDelta = TimeDelta::seconds(10);
+
+/// How tall the event log is, borders included.
+const EVENT_LOG_HEIGHT: usize = 15;
+
+/// How many events fit in the event log at once, inside its borders.
+const EVENT_LOG_ROWS: usize = EVENT_LOG_HEIGHT - 2;
+
+/// How event timestamps are shown in the TUI without --timestamp-format.
+const DEFAULT_TIMESTAMP_FORMAT: &str = "%H:%M:%S";
+
+/// Tui holds what the TUI task needs beyond the state it shares with the simulation.
+struct Tui<B: Backend> {
+    terminal: Arc<Mutex<Terminal<B>>>,
+    theme: Theme,
+    animations: Option<Arc<Mutex<TransferAnimations>>>,
+    // Plays on startup, if enabled, and is dropped once it's done.
+    settling: Option<Settling>,
+    labels: Labels,
+    // The strftime format of event timestamps in the event log.
+    timestamp_format: String,
+    // Shared with the controller; toggled from the TUI.
+    manual: ManualOverride,
+    // Shared with the controller and convergence tracking; adjusted from the TUI.
+    tolerance: Tolerance,
+    // Shared with the filler, controller and actuator loops; toggled from the TUI.
+    pause: Pause,
+    status: Status,
+    // When set, every drawn frame is also saved to a file.
+    frames: Option<FrameDump>,
+    convergence: Arc<Mutex<Convergence>>,
+    // Recorded by the filler on every fill tick.
+    history: Arc<Mutex<History>>,
+    // Accounts for what fills add and what spills out of the system.
+    ledger: Arc<Mutex<Ledger>>,
+    // Recorded by the filler and the actuator.
+    flow: Arc<Mutex<NetFlow>>,
+}
+
+/// Status is the runtime configuration summarized in the status bar.
+struct Status {
+    // Kept up to date by the policy file reloader, if any.
+    policy: Arc<std::sync::Mutex<Policy>>,
+    bucket_type: BucketType,
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn run<S: Buckets + Sensor + FinalControlElement + Send + 'static>(
     args: Args,
-    terminal: Arc<Mutex<Terminal<CrosstermBackend<Stdout>>>>,
+    tui: Tui<CrosstermBackend<Stdout>>,
     events: Arc<Mutex<Events>>,
-    buckets: Arc<Mutex<NBuckets>>,
-    controller: Arc<Controller<S>>,
+    buckets: Arc<Mutex<AnyBuckets>>,
+    controller: Arc<Mutex<Controller<S>>>,
     actuator: Arc<Mutex<Actuator<S>>>,
+    control_signal_tx: mpsc::Sender<Action>,
+    script: Option<(Script, Director<S>)>,
 ) -> Result<()> {
+    // Bind the metrics port before spawning anything, so that a taken port has nothing to stop.
+    let metrics_listener = match args.metrics_port {
+        Some(port) => Some(metrics::bind(port).await?),
+        None => None,
+    };
     let ct = CancellationToken::new();
+    if args.strict {
+        events.lock().await.set_strict(ct.clone());
+    }
+    // On a change-driven schedule, the filler tells the controller how much every fill added.
+    let (change_tx, change_rx) = match args.controller_schedule {
+        Schedule::Periodic => (None, None),
+        Schedule::OnChange => {
+            let (tx, rx) = mpsc::unbounded_channel();
+            (Some(tx), Some(rx))
+        }
+    };
     let fill_handle = tokio::spawn(run_fill(
         ct.clone(),
         args.fill_latency,
         events.clone(),
         buckets.clone(),
+        change_tx,
+        tui.convergence.clone(),
+        tui.history.clone(),
+        tui.ledger.clone(),
+        tui.flow.clone(),
+        args.auto_grow(),
+        script,
+        tui.pause.clone(),
+        args.max_ticks,
     ));
+    let reload_handle = args.policy_file.clone().map(|path| {
+        tokio::spawn(policy_file::run(
+            ct.clone(),
+            path,
+            args.policy_reload_interval,
+            controller.clone(),
+            tui.status.policy.clone(),
+            events.clone(),
+        ))
+    });
+    let pause = tui.pause.clone();
+    let metrics_handle = match metrics_listener {
+        Some(listener) => {
+            let metrics = Arc::new(Metrics::default());
+            controller.lock().await.set_metrics(metrics.clone());
+            actuator.lock().await.set_metrics(metrics.clone());
+            Some(tokio::spawn(metrics::run(
+                ct.clone(),
+                listener,
+                metrics,
+                buckets.clone(),
+                events.clone(),
+            )))
+        }
+        None => None,
+    };
     let tui_handle = tokio::spawn(run_tui(
         ct.clone(),
-        events,
-        terminal.clone(),
+        events.clone(),
+        tui,
         buckets.clone(),
+        control_signal_tx,
     ));
-    let controller_handle = tokio::spawn(run_control_loop(
-        ct.clone(),
-        args.controller_latency,
-        controller.clone(),
-    ));
+    let controller_handle = match change_rx {
+        Some(changes) => tokio::spawn(run_change_driven_control_loop(
+            ct.clone(),
+            changes,
+            args.change_threshold,
+            controller.clone(),
+            pause.clone(),
+        )),
+        None => tokio::spawn(run_control_loop(
+            ct.clone(),
+            args.controller_latency,
+            controller.clone(),
+            pause.clone(),
+        )),
+    };
     let actuator_handle = tokio::spawn(run_actuator_loop(
         ct.clone(),
         args.actuator_latency,
         actuator.clone(),
+        pause,
     ));
-    let (tui_res, fill_res, controller_res, actuator_res) =
-        tokio::try_join!(tui_handle, fill_handle, controller_handle, actuator_handle)?;
+    #[cfg(unix)]
+    let dump_handle = args
+        .dump_state_on_signal
+        .then(|| tokio::spawn(state_dump::run(ct.clone(), events.clone(), buckets.clone())));
+    let joined = tokio::try_join!(tui_handle, fill_handle, controller_handle, actuator_handle);
+    // Flush the exporters no matter how the run ended, so that nothing they buffered is lost.
+    let flushed = events.lock().await.close_exporters();
+    let (tui_res, fill_res, controller_res, actuator_res) = joined?;
     tui_res?;
     fill_res?;
     controller_res?;
     actuator_res?;
-    Ok(())
+    if let Some(reload_handle) = reload_handle {
+        reload_handle.await??;
+    }
+    if let Some(metrics_handle) = metrics_handle {
+        metrics_handle.await??;
+    }
+    #[cfg(unix)]
+    if let Some(dump_handle) = dump_handle {
+        dump_handle.await??;
+    }
+    flushed
 }
 
-async fn run_fill<B: Buckets>(
+#[allow(clippy::too_many_arguments)]
+async fn run_fill<B: Buckets + Sensor, S: Sensor>(
     ct: CancellationToken,
     fill_latency_ms: u64,
     events: Arc<Mutex<Events>>,
     buckets: Arc<Mutex<B>>,
+    change_tx: Option<mpsc::UnboundedSender<u64>>,
+    convergence: Arc<Mutex<Convergence>>,
+    history: Arc<Mutex<History>>,
+    ledger: Arc<Mutex<Ledger>>,
+    flow: Arc<Mutex<NetFlow>>,
+    auto_grow: Option<AutoGrow>,
+    script: Option<(Script, Director<S>)>,
+    pause: Pause,
+    max_ticks: Option<u64>,
 ) -> Result<()> {
+    let mut overflows = Overflows::new();
+    let mut ticks = 0;
     loop {
         tokio::select! {
             _ = sleep(Duration::from_millis(fill_latency_ms)) => {
-                let (bucket, change, new_val) = buckets.lock().await.fill();
-                events.lock().await.add(events::EventSource::Filler, format!("filled +{} to bucket {} => {}", change, bucket, new_val));
+                // A paused tick doesn't count, so scripted steps keep their place in the run.
+                if pause.is_paused() {
+                    continue;
+                }
+                ticks += 1;
+                let steps = script.as_ref().map(|(script, _)| script.at(ticks)).unwrap_or_default();
+                let mut buckets = buckets.lock().await;
+                let mut filled = buckets.fill();
+                let mut failed = Vec::new();
+                for step in steps {
+                    if let Step::Fill { bucket, amount } = *step {
+                        match buckets.pour(bucket, amount) {
+                            Ok(fill) => filled.push(fill),
+                            Err(e) => failed.push(e),
+                        }
+                    }
+                }
+                let mut ledger = ledger.lock().await;
+                for fill in &filled {
+                    ledger.fill(fill.added + fill.spilled);
+                    ledger.spill(fill.spilled);
+                }
+                let leaked = buckets.take_leaked();
+                ledger.spill(leaked);
+                let violation = ledger.check(&*buckets);
+                drop(ledger);
+                let mut flow = flow.lock().await;
+                for fill in &filled {
+                    flow.record_fill(fill.bucket, fill.added);
+                }
+                drop(flow);
+                let alarms = overflows.check(&filled, &*buckets);
+                let grew = auto_grow.and_then(|auto_grow| auto_grow.apply(&mut *buckets, &alarms));
+                // Every fill is a tick, whether or not there was anything to fill.
+                convergence.lock().await.observe(&*buckets);
+                history.lock().await.record(&*buckets);
+                drop(buckets);
+                // The controller locks the buckets while it holds its own lock, so switch policies
+                // only once the buckets are released.
+                if let Some((_, director)) = &script {
+                    for step in steps {
+                        if let Step::Policy(policy) = *step {
+                            director.set_policy(policy).await;
+                        }
+                    }
+                }
+                let mut events = events.lock().await;
+                for step in steps {
+                    events.add(EventSource::System, format!("script step at tick {}: {}", ticks, step));
+                }
+                for e in failed {
+                    events.add_with_severity(
+                        EventSource::System,
+                        Severity::Warn,
+                        format!("script step at tick {} failed: {}", ticks, e),
+                    );
+                }
+                if let Some(violation) = violation {
+                    events.add_with_severity(EventSource::System, Severity::Critical, violation);
+                }
+                for fill in filled {
+                    events.add(EventSource::Filler, fill.to_string());
+                    if let Some(change_tx) = &change_tx {
+                        // The controller only stops listening once the run is over.
+                        let _ = change_tx.send(fill.added);
+                    }
+                }
+                if leaked > 0 {
+                    events.add(EventSource::Filler, format!("leaked {} in all", leaked));
+                }
+                for alarm in alarms {
+                    events.add_with_severity(EventSource::Filler, Severity::Critical, alarm);
+                }
+                if let Some(grew) = grew {
+                    events.add(EventSource::System, grew);
+                }
+                if max_ticks == Some(ticks) {
+                    events.add(
+                        EventSource::System,
+                        format!("reached the limit of {} ticks, shutting down", ticks),
+                    );
+                    // Whatever the controller and actuator are still working on is dropped along
+                    // with them.
+                    ct.cancel();
+                }
             },
             _ = ct.cancelled() => return Ok(())
         }
@@ -170,10 +697,32 @@ async fn run_fill<B: Buckets>(
 async fn run_tui<B: Backend + Send>(
     ct: CancellationToken,
     events: Arc<Mutex<Events>>,
-    terminal: Arc<Mutex<Terminal<B>>>,
-    app: Arc<Mutex<NBuckets>>,
+    tui: Tui<B>,
+    app: Arc<Mutex<AnyBuckets>>,
+    control_signal_tx: mpsc::Sender<Action>,
 ) -> io::Result<()> {
+    let Tui {
+        terminal,
+        theme,
+        animations,
+        mut settling,
+        mut labels,
+        timestamp_format,
+        manual,
+        tolerance,
+        pause,
+        status,
+        mut frames,
+        convergence,
+        history,
+        ledger,
+        flow,
+    } = tui;
+    // Whether to show a sparkline per bucket instead of the bar chart.
+    let mut dense = false;
+    let mut scroll = EventScroll::new(EVENT_LOG_ROWS);
     let mut reader = crossterm::event::EventStream::new();
+    let mut clipboard = Clipboard::new();
     // Start draw_latency at 0 so that we paint the first frame immediately. We then set it to 1 so
     // we draw every second afterwards.
     const DRAW_LATENCY_MS: u64 = 200;
@@ -182,83 +731,554 @@ async fn run_tui<B: Backend + Send>(
         tokio::select! {
             _ = ct.cancelled() => return Ok(()),
             _ = sleep(Duration::from_millis(draw_latency_ms)) => {
-                let data = app.clone().lock().await.data();
-                let lines = events
-                    .lock()
-                    .await
+                let app_state = app.lock().await;
+                let mut data = app_state.data();
+                let gini = app_state.gini_coefficient();
+                let capacity = app_state.capacity();
+                let total_capacity = app_state.total_capacity();
+                let fullness = (total_capacity > 0)
+                    .then(|| app_state.total() as f64 / total_capacity as f64 * 100.0);
+                drop(app_state);
+                let mut chart_title = match gini {
+                    Some(gini) => format!("Buckets (Gini {:.2})", gini),
+                    None => "Buckets".to_string(),
+                };
+                if pause.is_paused() {
+                    chart_title.push_str(" [PAUSED]");
+                }
+                let balanced_ticks = convergence.lock().await.balanced_ticks();
+                let log = events.lock().await;
+                let legend = event_rate_legend(&log, &theme);
+                let event_offset = scroll.offset(&log);
+                let lines = log
                     .get_all()
                     .iter()
                     .map(|event| {
                         Line::from(vec![Span::styled(
-                            format!("{} | ", event.timestamp.format("%H:%M:%S")),
-                            Style::default().fg(Color::DarkGray).add_modifier(Modifier::BOLD),
+                            format!("{} | ", event.timestamp.format(&timestamp_format)),
+                            Style::default().fg(theme.timestamp).add_modifier(Modifier::BOLD),
                         ),
                             Span::styled(
                             format!("{} ", event.source),
-                            Style::default().fg(event.source.color()).add_modifier(Modifier::BOLD),
+                            Style::default().fg(theme.source(event.source)).add_modifier(Modifier::BOLD),
                         ),
                         Span::styled(
-                            format!("{}", event.message),
-                            Style::default().fg(Color::White).add_modifier(Modifier::ITALIC),
+                            message_text(event),
+                            Style::default().fg(theme.severity(event.severity)).add_modifier(Modifier::ITALIC),
                         )])
                     })
                     .collect();
-                terminal.lock().await.draw(|f| ui(f, data, &lines))?;
+                drop(log);
+                if let Some(animation) = &mut settling {
+                    for datum in data.iter_mut() {
+                        datum.quantity = animation.interpolate(datum.quantity);
+                    }
+                    if !animation.advance() {
+                        settling = None;
+                    }
+                }
+                let flow = flow.lock().await;
+                let bars = match &animations {
+                    Some(animations) => {
+                        let mut animations = animations.lock().await;
+                        let bars = bars(&data, &theme, &labels, &flow, Some(&animations));
+                        animations.advance();
+                        bars
+                    }
+                    None => bars(&data, &theme, &labels, &flow, None),
+                };
+                drop(flow);
+                let sparklines = if dense {
+                    let history = history.lock().await;
+                    let sparklines = data
+                        .iter()
+                        .map(|datum| (labels.label(datum), history.series(datum.bucket, SPARKLINE_WINDOW)))
+                        .collect();
+                    Some(sparklines)
+                } else {
+                    None
+                };
+                let mut terminal = terminal.lock().await;
+                let frame = terminal.draw(|f| {
+                    let view = View {
+                        chart_title,
+                        capacity,
+                        bars,
+                        sparklines,
+                        events: lines,
+                        event_offset,
+                        following: scroll.is_following(),
+                        legend,
+                        status: status_line(&status, &labels, &manual, &pause, (capacity, fullness), tolerance.get(), balanced_ticks),
+                    };
+                    ui(f, view, &theme)
+                })?;
+                if let Some(frames) = &mut frames {
+                    frames.dump(frame.buffer)?;
+                }
                 draw_latency_ms = DRAW_LATENCY_MS;
             },
             maybe_event = reader.next().fuse() => {
                 if let Some(event) = maybe_event {
-                    handle_event(ct.clone(), event?).await?
+                    handle_event(ct.clone(), event?, &app, &events, &ledger, &control_signal_tx, &mut labels, &mut dense, &mut scroll, &manual, &tolerance, &pause, &mut clipboard).await?
                 }
             },
         }
     }
 }
 
-async fn handle_event(ct: CancellationToken, event: Event) -> io::Result<()> {
+#[allow(clippy::too_many_arguments)]
+async fn handle_event(
+    ct: CancellationToken,
+    event: Event,
+    app: &Arc<Mutex<AnyBuckets>>,
+    events: &Arc<Mutex<Events>>,
+    ledger: &Arc<Mutex<Ledger>>,
+    control_signal_tx: &mpsc::Sender<Action>,
+    labels: &mut Labels,
+    dense: &mut bool,
+    scroll: &mut EventScroll,
+    manual: &ManualOverride,
+    tolerance: &Tolerance,
+    pause: &Pause,
+    clipboard: &mut Clipboard,
+) -> io::Result<()> {
     if let Event::Key(key) = event {
-        if let KeyCode::Char('q') = key.code {
-            ct.cancel();
+        match key.code {
+            KeyCode::Char('q') => ct.cancel(),
+            KeyCode::Char('n') => labels.toggle(),
+            KeyCode::Char('v') => *dense = !*dense,
+            KeyCode::Char('m') => manual.toggle(),
+            KeyCode::Char(' ') => {
+                let message = if pause.toggle() {
+                    "paused the simulation"
+                } else {
+                    "resumed the simulation"
+                };
+                events
+                    .lock()
+                    .await
+                    .add(EventSource::System, message.to_string());
+            }
+            KeyCode::Char('[') => {
+                change_tolerance(events, tolerance, |tolerance| tolerance.saturating_sub(1)).await
+            }
+            KeyCode::Char(']') => {
+                change_tolerance(events, tolerance, |tolerance| tolerance.saturating_add(1)).await
+            }
+            KeyCode::Char('c') => copy_state(app, events, clipboard).await,
+            KeyCode::Char('e') => export_state(app, events).await,
+            KeyCode::Char('<') => {
+                change_capacity(app, events, ledger, |capacity| {
+                    capacity.saturating_sub(CAPACITY_STEP).max(CAPACITY_STEP)
+                })
+                .await
+            }
+            KeyCode::Char('>') => {
+                change_capacity(app, events, ledger, |capacity| {
+                    capacity.saturating_add(CAPACITY_STEP)
+                })
+                .await
+            }
+            KeyCode::Up => scroll.up(&*events.lock().await, 1),
+            KeyCode::Down => scroll.down(&*events.lock().await, 1),
+            KeyCode::PageUp => scroll.page_up(&*events.lock().await),
+            KeyCode::PageDown => scroll.page_down(&*events.lock().await),
+            KeyCode::Home => scroll.home(&*events.lock().await),
+            KeyCode::End => scroll.end(),
+            KeyCode::Char('+') => send_action(events, control_signal_tx, Action::AddBucket).await?,
+            KeyCode::Char('-') => {
+                // Ask the actuator to remove the newest bucket. Whether that's allowed is up to it.
+                let newest = app.lock().await.buckets().keys().max().copied();
+                if let Some(bucket) = newest {
+                    send_action(events, control_signal_tx, Action::RemoveBucket { bucket }).await?;
+                }
+            }
+            _ => {}
         }
     }
 
     Ok(())
 }
 
+/// Sends an action from the TUI to the actuator without waiting for room in the channel. The
+/// actuator stops draining it while paused, and waiting then would leave the TUI stuck, unable to
+/// resume or quit, so an action that doesn't fit is dropped with a warning instead.
+async fn send_action(
+    events: &Arc<Mutex<Events>>,
+    control_signal_tx: &mpsc::Sender<Action>,
+    action: Action,
+) -> io::Result<()> {
+    match control_signal_tx.try_send(action) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(action)) => {
+            events.lock().await.add_with_severity(
+                EventSource::System,
+                Severity::Warn,
+                format!("action queue is full, dropped {}", action),
+            );
+            Ok(())
+        }
+        Err(e @ TrySendError::Closed(_)) => Err(io::Error::other(e.to_string())),
+    }
+}
+
+/// Changes the balance tolerance as given by `change`, logging the change.
+async fn change_tolerance(
+    events: &Arc<Mutex<Events>>,
+    tolerance: &Tolerance,
+    change: impl FnOnce(u64) -> u64,
+) {
+    let old = tolerance.get();
+    let new = change(old);
+    if new == old {
+        return;
+    }
+    tolerance.set(new);
+    events.lock().await.add(
+        EventSource::System,
+        format!("changed balance tolerance {} => {}", old, new),
+    );
+}
+
+/// Copies the current bucket state to the clipboard in the `--initial-data` format, so that the
+/// scenario can be picked back up from the command line.
+async fn copy_state(
+    app: &Arc<Mutex<AnyBuckets>>,
+    events: &Arc<Mutex<Events>>,
+    clipboard: &mut Clipboard,
+) {
+    let state = format_initial_data(app.lock().await.buckets());
+    let mut events = events.lock().await;
+    match clipboard.copy(state.clone()) {
+        Ok(()) => events.add(
+            EventSource::System,
+            format!("copied state to clipboard: {}", state),
+        ),
+        Err(e) => events.add_with_severity(
+            EventSource::System,
+            Severity::Warn,
+            format!("couldn't copy state to clipboard: {}", e),
+        ),
+    }
+}
+
+/// Writes the current bucket state to a new, timestamped JSON file in the working directory, with
+/// each bucket's quantity keyed by its ID, for other tools to pick up.
+async fn export_state(app: &Arc<Mutex<AnyBuckets>>, events: &Arc<Mutex<Events>>) {
+    let state = app
+        .lock()
+        .await
+        .buckets()
+        .iter()
+        .map(|(bucket, quantity)| (bucket.to_string(), *quantity))
+        .collect::<BTreeMap<_, _>>();
+    let path = format!("buckets-{}.json", Local::now().format("%Y%m%d-%H%M%S-%3f"));
+    let written = serde_json::to_string_pretty(&state)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| atomic_write::write(Path::new(&path), (contents + "\n").as_bytes()));
+    let mut events = events.lock().await;
+    match written {
+        Ok(()) => events.add(EventSource::System, format!("exported state to {}", path)),
+        Err(e) => events.add_with_severity(
+            EventSource::System,
+            Severity::Warn,
+            format!("couldn't export state to {}: {}", path, e),
+        ),
+    }
+}
+
+/// How much the capacity changes by with every '<' or '>' press.
+const CAPACITY_STEP: u64 = 10;
+
+/// Changes the capacity of the buckets as given by `change`, logging the change and anything the
+/// buckets spilled because of it.
+async fn change_capacity(
+    app: &Arc<Mutex<AnyBuckets>>,
+    events: &Arc<Mutex<Events>>,
+    ledger: &Arc<Mutex<Ledger>>,
+    change: impl FnOnce(u64) -> u64,
+) {
+    let mut buckets = app.lock().await;
+    let old = buckets.capacity();
+    let new = change(old);
+    if new == old {
+        return;
+    }
+    let spills = buckets.set_capacity(new);
+    let mut ledger = ledger.lock().await;
+    for (_, spilled) in &spills {
+        ledger.spill(*spilled);
+    }
+    drop(ledger);
+    drop(buckets);
+    let mut message = format!("changed capacity {} => {}", old, new);
+    if !spills.is_empty() {
+        let spills = spills
+            .iter()
+            .map(|(bucket, spilled)| format!("{} from bucket {}", spilled, bucket))
+            .join(", ");
+        message.push_str(&format!(", spilled {}", spills));
+    }
+    events.lock().await.add(EventSource::System, message);
+}
+
 async fn run_control_loop<S: Sensor + Send + 'static>(
     ct: CancellationToken,
     controller_latency_ms: u64,
-    controller: Arc<Controller<S>>,
+    controller: Arc<Mutex<Controller<S>>>,
+    pause: Pause,
 ) -> Result<()> {
     loop {
         tokio::select! {
-            _ = sleep(Duration::from_millis(controller_latency_ms)) => controller.run(ct.clone()).await?,
+            _ = sleep(Duration::from_millis(controller_latency_ms)) => {
+                if !pause.is_paused() {
+                    controller.lock().await.run(ct.clone()).await?
+                }
+            },
             _ = ct.cancelled() => return Ok(()),
         }
     }
 }
 
-async fn run_actuator_loop<B: FinalControlElement + Send + 'static>(
+/// Runs the controller whenever the fills reported on `changes` since its last run add up to at
+/// least `threshold`.
+async fn run_change_driven_control_loop<S: Sensor + Send + 'static>(
+    ct: CancellationToken,
+    mut changes: mpsc::UnboundedReceiver<u64>,
+    threshold: u64,
+    controller: Arc<Mutex<Controller<S>>>,
+    pause: Pause,
+) -> Result<()> {
+    let mut pending = 0u64;
+    loop {
+        tokio::select! {
+            change = changes.recv() => {
+                let Some(change) = change else {
+                    return Ok(());
+                };
+                pending = pending.saturating_add(change);
+                // Nothing fills while paused, but a change can still arrive from just before.
+                if pending >= threshold && !pause.is_paused() {
+                    pending = 0;
+                    controller.lock().await.run(ct.clone()).await?;
+                }
+            },
+            _ = ct.cancelled() => return Ok(()),
+        }
+    }
+}
+
+async fn run_actuator_loop<B: FinalControlElement + Sensor + Send + 'static>(
     ct: CancellationToken,
     actuator_latency_ms: u64,
     actuator: Arc<Mutex<Actuator<B>>>,
+    pause: Pause,
 ) -> Result<()> {
     loop {
         tokio::select! {
-            _ = sleep(Duration::from_millis(actuator_latency_ms)) => actuator.lock().await.run(ct.clone()).await?,
+            _ = sleep(Duration::from_millis(actuator_latency_ms)) => {
+                if !pause.is_paused() {
+                    actuator.lock().await.run(ct.clone()).await?
+                }
+            },
             _ = ct.cancelled() => return Ok(()),
         }
     }
 }
 
-fn ui(f: &mut Frame, data: Vec<(String, u64)>, events: &Vec<Line<'_>>) {
+/// Returns the text to show for an event: its message, followed by its fields if it has any.
+fn message_text(event: &events::Event) -> String {
+    if event.fields.is_empty() {
+        return event.message.clone();
+    }
+    let fields = event
+        .fields
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .join(" ");
+    format!("{} {}", event.message, fields)
+}
+
+/// Builds a legend summarizing how many events each source has produced and how quickly it's
+/// producing them at the moment.
+fn event_rate_legend(events: &Events, theme: &Theme) -> Line<'static> {
+    const RATE_WINDOW: TimeDelta = TimeDelta::seconds(10);
+    let spans = EventSource::ALL
+        .iter()
+        .flat_map(|source| {
+            [
+                Span::styled(
+                    format!(" {} ", source),
+                    Style::default()
+                        .fg(theme.source(*source))
+                        .add_modifier(Modifier::BOLD),
+                ),
+                Span::raw(format!(
+                    "{} ({:.1}/s) ",
+                    events.count(*source),
+                    events.rate(*source, RATE_WINDOW)
+                )),
+            ]
+        })
+        .collect::<Vec<_>>();
+    Line::from(spans)
+}
+
+/// Builds the bars for the bar chart, highlighting the buckets of any transfers being animated.
+fn bars(
+    data: &[Datum],
+    theme: &Theme,
+    labels: &Labels,
+    flow: &NetFlow,
+    animations: Option<&TransferAnimations>,
+) -> Vec<Bar<'static>> {
+    data.iter()
+        .map(|datum| {
+            let mut label = match &datum.tag {
+                Some(tag) => format!("{} {}", labels.label(datum), tag),
+                None => labels.label(datum),
+            };
+            // Show which way the bucket's fluid has been going lately, if anywhere.
+            let net = flow.net(datum.bucket);
+            match net.signum() {
+                1 => label.push_str(&format!(" ↑{}", net)),
+                -1 => label.push_str(&format!(" ↓{}", -net)),
+                _ => {}
+            }
+            let bar = Bar::default()
+                .value(datum.quantity)
+                .label(Line::from(label.clone()));
+            match animations.and_then(|animations| animations.highlight(datum.bucket)) {
+                Some((highlight, marker)) => {
+                    let color = match highlight {
+                        Highlight::Source => theme.transfer_source,
+                        Highlight::Destination => theme.transfer_destination,
+                    };
+                    bar.label(Line::from(format!("{} {}", label, marker)))
+                        .style(Style::default().fg(color))
+                }
+                None => bar,
+            }
+        })
+        .collect()
+}
+
+/// Builds the status bar: how the simulation is running and how steadily it's balanced, followed by
+/// the keys that do something.
+fn status_line(
+    status: &Status,
+    labels: &Labels,
+    manual: &ManualOverride,
+    pause: &Pause,
+    capacity: (u64, Option<f64>),
+    tolerance: u64,
+    balanced_ticks: (usize, usize),
+) -> Line<'static> {
+    let (balanced, ticks) = balanced_ticks;
+    let (capacity, fullness) = capacity;
+    let mode = if pause.is_paused() {
+        "PAUSED"
+    } else if manual.is_enabled() {
+        "MANUAL OVERRIDE"
+    } else {
+        "Running"
+    };
+    let mut fields = vec![
+        format!(
+            "Policy: {}",
+            status.policy.lock().expect("policy lock poisoned")
+        ),
+        format!("Buckets: {}", status.bucket_type),
+        mode.to_string(),
+        match fullness {
+            Some(fullness) => format!("Capacity: {} ({:.0}% full)", capacity, fullness),
+            None => format!("Capacity: {}", capacity),
+        },
+        format!("Balanced {}/{} ticks (±{})", balanced, ticks, tolerance),
+    ];
+    let mut keys = vec![
+        "q quit",
+        "space pause",
+        "+ add bucket",
+        "- remove bucket",
+        "m manual override",
+        "c copy state",
+        "e export state",
+        "v sparklines",
+        "↑/↓ scroll log",
+        "End follow log",
+        "</> capacity",
+        "[/] tolerance",
+    ];
+    if labels.has_names() {
+        let shown = if labels.showing_names() {
+            "names"
+        } else {
+            "IDs"
+        };
+        fields.push(format!("Labels: {}", shown));
+        keys.push("n toggle names");
+    }
+    Line::from(format!(" {} │ {}", fields.join(" │ "), keys.join("  ")))
+}
+
+/// View is everything that's drawn in a single frame.
+struct View<'a> {
+    chart_title: String,
+    // The largest value the chart has room for.
+    capacity: u64,
+    bars: Vec<Bar<'a>>,
+    // When set, drawn instead of the bars: every bucket's label and its recent quantities.
+    sparklines: Option<Vec<(String, Vec<u64>)>>,
+    events: Vec<Line<'a>>,
+    // The index of the top event shown, and whether the log is following the newest events.
+    event_offset: usize,
+    following: bool,
+    legend: Line<'a>,
+    status: Line<'a>,
+}
+
+fn ui(f: &mut Frame, view: View<'_>, theme: &Theme) {
+    let View {
+        chart_title,
+        capacity,
+        bars,
+        sparklines,
+        events,
+        event_offset,
+        following,
+        legend,
+        status,
+    } = view;
+
     // Calculate the width needed for the chart
     // For each bar: width + gap = 9 + 3 = 12 units
     // Last bar doesn't need a gap, plus add some padding and borders
     let bar_width = 9;
     let bar_gap = 3;
-    let num_bars = data.len();
-    let chart_width = (bar_width + bar_gap) * (num_bars - 1) + bar_width + 2; // +2 for borders
+    let num_bars = bars.len();
+    let chart_width = match &sparklines {
+        // Sparklines stack vertically, so they fit in any width that leaves room for a few samples.
+        Some(_) => SPARKLINE_LABEL_WIDTH + 12,
+        None => (bar_width + bar_gap) * num_bars.saturating_sub(1) + bar_width + 2, // +2 for borders
+    };
+
+    if too_small(f.area(), chart_width) {
+        let (min_width, min_height) = min_size(chart_width);
+        let message = format!(
+            "terminal too small: need {}x{}, have {}x{}",
+            min_width,
+            min_height,
+            f.area().width,
+            f.area().height
+        );
+        f.render_widget(
+            Paragraph::new(message).centered().wrap(Wrap { trim: true }),
+            f.area(),
+        );
+        return;
+    }
 
     // Calculate the full width for the layout (wider for event log)
     let total_layout_width = (chart_width + 20).max((f.area().width - 10) as usize); // At least 20 units wider than chart, but respect screen size
@@ -267,7 +1287,6 @@ fn ui(f: &mut Frame, data: Vec<(String, u64)>, events: &Vec<Line<'_>>) {
     let main_area = centered_rect(total_layout_width as u16, 30, f.area());
 
     // Split the main area into two chunks vertically - top for chart, bottom for events
-    const EVENT_LOG_HEIGHT: usize = 15;
     let vertical_chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
@@ -276,32 +1295,30 @@ fn ui(f: &mut Frame, data: Vec<(String, u64)>, events: &Vec<Line<'_>>) {
         ])
         .split(main_area);
 
-    // For the top chunk, create a centered area for the bar chart with its specific width
-    let chart_area = centered_rect_horizontal(
-        chart_width as u16,
-        vertical_chunks[0].height,
-        vertical_chunks[0],
-    );
+    // For the top chunk, create a centered area for the bar chart with its specific width. The
+    // sparklines get the whole width, since more of it means more history.
+    let chart_area = match &sparklines {
+        Some(_) => vertical_chunks[0],
+        None => centered_rect_horizontal(
+            chart_width as u16,
+            vertical_chunks[0].height,
+            vertical_chunks[0],
+        ),
+    };
 
     // Event log uses the full width of the bottom chunk
     let event_log_area = vertical_chunks[1];
 
-    // Create the bars for the bar chart:
-    let bars = data
-        .iter()
-        .map(|datum| (datum.0.as_str(), datum.1))
-        .collect::<Vec<(&str, u64)>>();
-
     // Create bar chart
+    let chart_block = Block::default().title(chart_title).borders(Borders::ALL);
     let bar_chart = BarChart::default()
-        .block(Block::default().title("Buckets").borders(Borders::ALL))
-        .data(&bars)
-        .max(100)
+        .data(BarGroup::default().bars(&bars))
+        .max(capacity)
         .bar_width(bar_width as u16)
         .bar_gap(bar_gap as u16)
-        .bar_style(Style::default().fg(Color::LightBlue))
-        .value_style(Style::default().fg(Color::White))
-        .label_style(Style::default().fg(Color::Yellow));
+        .bar_style(Style::default().fg(theme.bar))
+        .value_style(Style::default().fg(theme.value))
+        .label_style(Style::default().fg(theme.label));
 
     // Create the event log widget with styled text
     let events_list = List::new(
@@ -310,18 +1327,81 @@ fn ui(f: &mut Frame, data: Vec<(String, u64)>, events: &Vec<Line<'_>>) {
             .map(|spans| ListItem::new(spans.clone()))
             .collect::<Vec<ListItem>>(),
     )
-    .block(Block::default().title("Event Log").borders(Borders::ALL));
-    let mut list_state = ListState::default();
-    // Basically, if we reach a length of events that exceeds the available vertical space, start
-    // automatically setting the list's offset to the 8th last item and render from there,
-    // effectively scrolling.
-    if events.len() >= EVENT_LOG_HEIGHT {
-        *list_state.offset_mut() = events.len() - EVENT_LOG_HEIGHT;
-    }
+    .block(
+        Block::default()
+            .title(if following {
+                "Event Log"
+            } else {
+                "Event Log (scrolled, End to follow)"
+            })
+            .title(legend.right_aligned())
+            .borders(Borders::ALL),
+    );
+    let mut list_state = ListState::default().with_offset(event_offset);
 
     // Render both widgets
-    f.render_widget(bar_chart, chart_area);
+    if bars.is_empty() {
+        // The last bucket was removed, or there never were any.
+        f.render_widget(
+            Paragraph::new("empty").centered().block(chart_block),
+            chart_area,
+        );
+    } else if let Some(sparklines) = sparklines {
+        let inner = chart_block.inner(chart_area);
+        f.render_widget(chart_block, chart_area);
+        render_sparklines(f, inner, &sparklines, capacity, theme);
+    } else {
+        f.render_widget(bar_chart.block(chart_block), chart_area);
+    }
     f.render_stateful_widget(events_list, event_log_area, &mut list_state);
+
+    // The status bar always takes the bottom line of the screen.
+    let area = f.area();
+    let status_area = Rect::new(
+        area.x,
+        area.y + area.height.saturating_sub(1),
+        area.width,
+        area.height.min(1),
+    );
+    f.render_widget(
+        Paragraph::new(status).style(Style::default().add_modifier(Modifier::REVERSED)),
+        status_area,
+    );
+}
+
+/// How many of the latest history samples the sparklines are drawn from, at most. Only as many as
+/// fit in the chart are drawn.
+const SPARKLINE_WINDOW: usize = 256;
+
+/// How many columns the bucket labels next to the sparklines take up.
+const SPARKLINE_LABEL_WIDTH: usize = 12;
+
+/// Draws a row per bucket, each the bucket's label followed by a sparkline of its recent
+/// quantities, scaled to `capacity`. Buckets that don't fit in the area are left out.
+fn render_sparklines(
+    f: &mut Frame,
+    area: Rect,
+    sparklines: &[(String, Vec<u64>)],
+    capacity: u64,
+    theme: &Theme,
+) {
+    let sparkline_width = area.width.saturating_sub(SPARKLINE_LABEL_WIDTH as u16);
+    for (row, (label, series)) in sparklines.iter().take(area.height as usize).enumerate() {
+        let y = area.y + row as u16;
+        f.render_widget(
+            Paragraph::new(label.clone()).style(Style::default().fg(theme.label)),
+            Rect::new(area.x, y, SPARKLINE_LABEL_WIDTH as u16, 1),
+        );
+        // Only the latest samples fit, and the sparkline draws from its first sample on.
+        let latest = &series[series.len().saturating_sub(sparkline_width as usize)..];
+        f.render_widget(
+            Sparkline::default()
+                .data(latest)
+                .max(capacity)
+                .style(Style::default().fg(theme.bar)),
+            Rect::new(area.x + SPARKLINE_LABEL_WIDTH as u16, y, sparkline_width, 1),
+        );
+    }
 }
 
 // Helper function for horizontal centering with specific width
@@ -335,6 +1415,21 @@ fn centered_rect_horizontal(width: u16, height: u16, r: Rect) -> Rect {
     )
 }
 
+/// Returns the smallest width and height the layout fits in, given the width of the chart. Below
+/// that, the TUI only says that the terminal is too small.
+fn min_size(chart_width: usize) -> (u16, u16) {
+    // The chart is 20 lines tall, and the event log needs a few lines to be of any use, on top of
+    // the status bar.
+    const MIN_HEIGHT: u16 = 26;
+    (u16::try_from(chart_width).unwrap_or(u16::MAX), MIN_HEIGHT)
+}
+
+/// Returns whether the area is too small to lay out a chart of the given width in.
+fn too_small(area: Rect, chart_width: usize) -> bool {
+    let (min_width, min_height) = min_size(chart_width);
+    area.width < min_width || area.height < min_height
+}
+
 // Helper function to create a centered rect using fixed width/height
 fn centered_rect(width: u16, height: u16, r: Rect) -> Rect {
     let x = (r.width.saturating_sub(width)) / 2;
@@ -347,3 +1442,192 @@ fn centered_rect(width: u16, height: u16, r: Rect) -> Rect {
         height: height.min(r.height),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use ::buckets::buckets::ids::BucketId;
+    use ::buckets::buckets::n_buckets::NBuckets;
+    use ::buckets::buckets::FillStrategy;
+    use ::buckets::sensor::Readings;
+    use clap::Parser;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn lowering_the_capacity_spills_over_cap_buckets() {
+        let data = HashMap::from([(BucketId(1), 95), (BucketId(2), 10), (BucketId(3), 60)]);
+        let buckets = NBuckets::new(
+            data,
+            100,
+            FillStrategy::Steady,
+            None,
+            None,
+            HashMap::new(),
+            Some(0),
+        );
+        let ledger = Arc::new(Mutex::new(Ledger::new(&buckets)));
+        let app = Arc::new(Mutex::new(AnyBuckets::NBuckets(buckets)));
+        let events = Arc::new(Mutex::new(Events::new()));
+        change_capacity(&app, &events, &ledger, |_| 50).await;
+
+        let app = app.lock().await;
+        assert_eq!(app.capacity(), 50);
+        assert_eq!(
+            *app.buckets(),
+            HashMap::from([(BucketId(1), 50), (BucketId(2), 10), (BucketId(3), 50)])
+        );
+        // What was spilled left the system, so the ledger still balances.
+        assert_eq!(ledger.lock().await.check(&*app), None);
+        let events = events.lock().await;
+        let event = events.get_all().back().expect("change logged");
+        assert!(event.source == EventSource::System);
+        assert_eq!(
+            event.message,
+            "changed capacity 100 => 50, spilled 45 from bucket 1, 10 from bucket 3"
+        );
+    }
+
+    #[test]
+    fn small_terminals_fall_back() {
+        // Three bars, as in the default configuration.
+        let chart_width = 3 * 9 + 2 * 3 + 2;
+        let (min_width, min_height) = min_size(chart_width);
+        assert!(!too_small(Rect::new(0, 0, 200, 60), chart_width));
+        assert!(!too_small(
+            Rect::new(0, 0, min_width, min_height),
+            chart_width
+        ));
+        assert!(too_small(
+            Rect::new(0, 0, min_width - 1, min_height),
+            chart_width
+        ));
+        assert!(too_small(
+            Rect::new(0, 0, min_width, min_height - 1),
+            chart_width
+        ));
+        assert!(too_small(Rect::new(0, 0, 0, 0), chart_width));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn change_driven_controller_waits_for_the_threshold() -> Result<()> {
+        let args = Args::try_parse_from(["buckets"])?;
+        let events = Arc::new(Mutex::new(Events::new()));
+        let (control_signal_tx, mut control_signal_rx) = mpsc::channel(10);
+        let sensor = Readings::new(HashMap::from([(BucketId(1), 10)]), 100);
+        let controller = Arc::new(Mutex::new(Controller::new(
+            Policy::NoOp,
+            args.policy_params(),
+            Arc::new(Mutex::new(sensor)),
+            None,
+            None,
+            HashSet::new(),
+            ManualOverride::new(false),
+            Tolerance::new(0),
+            Some(0),
+            events.clone(),
+            control_signal_tx,
+        )));
+        let (change_tx, change_rx) = mpsc::unbounded_channel();
+        let control_loop = tokio::spawn(run_change_driven_control_loop(
+            CancellationToken::new(),
+            change_rx,
+            5,
+            controller,
+            Pause::new(),
+        ));
+        // Every run of the controller logs its decision, whether it analyzed or reused the last.
+        let runs = || async {
+            events
+                .lock()
+                .await
+                .get_all()
+                .iter()
+                .filter(|event| event.source == EventSource::Controller)
+                .count()
+        };
+
+        // Changes are handled in order, so by the time the third one has triggered a run,
+        // the first two would have too if they had added up to the threshold.
+        for change in [2, 2, 1] {
+            change_tx.send(change)?;
+        }
+        assert_eq!(control_signal_rx.recv().await, Some(Action::NoAction));
+        assert_eq!(runs().await, 1);
+        // What counts toward the threshold starts over after every run.
+        for change in [4, 1] {
+            change_tx.send(change)?;
+        }
+        assert_eq!(control_signal_rx.recv().await, Some(Action::NoAction));
+        assert_eq!(runs().await, 2);
+
+        drop(change_tx);
+        control_loop.await??;
+        assert_eq!(runs().await, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn status_line_summarizes_the_state() {
+        let status = Status {
+            policy: Arc::new(std::sync::Mutex::new(Policy::Spread)),
+            bucket_type: BucketType::NBuckets,
+        };
+        let manual = ManualOverride::new(false);
+        let pause = Pause::new();
+        let line = |labels: &Labels, capacity| {
+            status_line(&status, labels, &manual, &pause, capacity, 2, (3, 20)).to_string()
+        };
+        let unnamed = Labels::new(HashMap::new());
+        let keys = "q quit  space pause  + add bucket  - remove bucket  m manual override  \
+            c copy state  e export state  v sparklines  ↑/↓ scroll log  End follow log  \
+            </> capacity  [/] tolerance";
+        assert_eq!(
+            line(&unnamed, (100, Some(42.4))),
+            format!(
+                " Policy: Spread │ Buckets: NBuckets │ Running │ Capacity: 100 (42% full) │ \
+                 Balanced 3/20 ticks (±2) │ {}",
+                keys
+            )
+        );
+
+        manual.toggle();
+        let manual_line = line(&unnamed, (100, None));
+        assert!(manual_line.contains("│ MANUAL OVERRIDE │ Capacity: 100 │"));
+        // Pausing trumps the manual override.
+        pause.toggle();
+        assert!(line(&unnamed, (100, None)).contains("│ PAUSED │"));
+
+        let mut named = Labels::new(HashMap::from([(BucketId(1), String::from("intake"))]));
+        assert!(line(&named, (100, None)).contains("│ Labels: names │"));
+        named.toggle();
+        let named_line = line(&named, (100, None));
+        assert!(named_line.contains("│ Labels: IDs │"));
+        assert!(named_line.ends_with("[/] tolerance  n toggle names"));
+    }
+
+    #[test]
+    fn banner_opens_the_log() -> Result<()> {
+        let matches = Args::command().try_get_matches_from([
+            "buckets",
+            "--policy",
+            "sort",
+            "--banner",
+            "nightly run",
+        ])?;
+        let mut events = Events::new();
+        add_banner(&mut events, "nightly run", &cli::effective_config(&matches));
+        events.add(EventSource::Controller, String::from("later"));
+
+        let banner = &events.get_all()[0];
+        assert!(banner.source == EventSource::System);
+        assert_eq!(banner.message, "nightly run");
+        let fields = banner.fields.iter().cloned().collect::<HashMap<_, _>>();
+        assert_eq!(fields["run-id"].len(), 16);
+        assert_eq!(fields["policy"], "sort");
+        // Defaulted settings are recorded too.
+        assert_eq!(fields["critical-level"], "90");
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+//! With `--metrics-port`, the simulation serves Prometheus-style metrics over HTTP on that port, on
+//! localhost, for scraping into a monitoring system. Every request gets the metrics, as of when
+//! it's made, in the text exposition format:
+//!
+//! ```text
+//! bucket_quantity{id="1"} 45
+//! transfers_total 12
+//! policy_noaction_total 30
+//! events_total{source="Filler"} 42
+//! ```
+//!
+//! The request itself is only read as far as its headers, and otherwise ignored, so any path will
+//! do.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use itertools::Itertools;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use crate::events::{EventSource, Events};
+use crate::sensor::Sensor;
+
+/// The most of a request that's read before responding anyway.
+const MAX_REQUEST_SIZE: usize = 8192;
+/// How long a client gets to send its request before it's dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Metrics holds the counters that the controller and the actuator keep as they go.
+#[derive(Default)]
+pub struct Metrics {
+    transfers: AtomicU64,
+    noactions: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts the transfers of an applied action: one for a transfer, one per transfer for a
+    /// multi-transfer.
+    pub fn record_transfers(&self, transfers: u64) {
+        self.transfers.fetch_add(transfers, Ordering::Relaxed);
+    }
+
+    /// Counts a control cycle where the policy decided on no action.
+    pub fn record_noaction(&self) {
+        self.noactions.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Binds the metrics port on localhost, failing if it's taken.
+pub async fn bind(port: u16) -> Result<TcpListener> {
+    Ok(TcpListener::bind(("127.0.0.1", port)).await?)
+}
+
+/// Serves the metrics to every connection on `listener`, until the run is cancelled.
+pub async fn run<S: Sensor + Send + Sync + 'static>(
+    ct: CancellationToken,
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    sensor: Arc<Mutex<S>>,
+    events: Arc<Mutex<Events>>,
+) -> Result<()> {
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                // A slow client only holds up its own response, and only until the run ends.
+                let response = respond(stream, metrics.clone(), sensor.clone(), events.clone());
+                let ct = ct.clone();
+                tokio::spawn(async move {
+                    tokio::select! {
+                        _ = response => {},
+                        _ = ct.cancelled() => {},
+                    }
+                });
+            },
+            _ = ct.cancelled() => return Ok(()),
+        }
+    }
+}
+
+/// Reads the request up to the end of its headers and responds with the metrics. A client that
+/// goes away partway through, or takes too long to send its request, has nothing left to tell, so
+/// errors are dropped.
+async fn respond<S: Sensor + Send + Sync>(
+    mut stream: TcpStream,
+    metrics: Arc<Metrics>,
+    sensor: Arc<Mutex<S>>,
+    events: Arc<Mutex<Events>>,
+) {
+    if !matches!(
+        timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await,
+        Ok(true)
+    ) {
+        return;
+    }
+    let body = format(&metrics, &*sensor.lock().await, &*events.lock().await);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Reads the request up to the end of its headers, or up to MAX_REQUEST_SIZE, returning whether
+/// the client is still there to respond to.
+async fn read_request(stream: &mut TcpStream) -> bool {
+    let mut request = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n")
+        && request.len() < MAX_REQUEST_SIZE
+    {
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return false,
+            Ok(read) => request.extend_from_slice(&chunk[..read]),
+        }
+    }
+    true
+}
+
+/// Formats the metrics in the Prometheus text exposition format. Buckets are listed in order of ID.
+fn format<S: Sensor>(metrics: &Metrics, sensor: &S, events: &Events) -> String {
+    let mut body = String::new();
+    body.push_str("# HELP bucket_quantity How much each bucket holds.\n");
+    body.push_str("# TYPE bucket_quantity gauge\n");
+    for (bucket, quantity) in sensor.buckets().iter().sorted() {
+        body.push_str(&format!(
+            "bucket_quantity{{id=\"{}\"}} {}\n",
+            bucket, quantity
+        ));
+    }
+    body.push_str("# HELP transfers_total Transfers applied by the actuator.\n");
+    body.push_str("# TYPE transfers_total counter\n");
+    body.push_str(&format!(
+        "transfers_total {}\n",
+        metrics.transfers.load(Ordering::Relaxed)
+    ));
+    body.push_str(
+        "# HELP policy_noaction_total Control cycles where the policy decided on no action.\n",
+    );
+    body.push_str("# TYPE policy_noaction_total counter\n");
+    body.push_str(&format!(
+        "policy_noaction_total {}\n",
+        metrics.noactions.load(Ordering::Relaxed)
+    ));
+    body.push_str("# HELP events_total Events logged, by source.\n");
+    body.push_str("# TYPE events_total counter\n");
+    for source in EventSource::ALL {
+        body.push_str(&format!(
+            "events_total{{source=\"{}\"}} {}\n",
+            source,
+            events.count(source)
+        ));
+    }
+    body
+}
@@ -0,0 +1,145 @@
+//! The Monte Carlo mode runs the same configuration many times in lockstep, each run with its own
+//! seed, and aggregates how the runs went. A single run can be lucky or unlucky with its fills, so
+//! this is how to judge how a policy behaves on average.
+
+use anyhow::Result;
+
+use crate::cli::Args;
+use crate::sensor::Sensor;
+use crate::simulation::Simulation;
+
+/// RunMetrics is what a single run is judged by.
+pub struct RunMetrics {
+    /// The first tick after which the buckets were balanced, or None if they never were.
+    pub convergence_ticks: Option<u64>,
+    pub transfers: u64,
+    pub final_variance: f64,
+}
+
+/// Stats summarizes a metric across runs.
+pub struct Stats {
+    /// How many runs the metric was available for.
+    pub count: usize,
+    pub mean: f64,
+    pub stddev: f64,
+}
+
+pub struct Summary {
+    pub runs: usize,
+    pub convergence_ticks: Stats,
+    pub transfers: Stats,
+    pub final_variance: Stats,
+}
+
+/// Runs the configuration given by `args` `runs` times for `ticks` ticks each. Run `i` is seeded
+/// with `seed + i`, so the whole batch is reproducible from `seed`.
+pub fn run(args: &Args, seed: u64, runs: u64, ticks: u64) -> Result<Summary> {
+    let metrics = (0..runs)
+        .map(|run| run_once(args, seed.wrapping_add(run), ticks))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Summary {
+        runs: metrics.len(),
+        convergence_ticks: stats(
+            metrics
+                .iter()
+                .filter_map(|m| m.convergence_ticks.map(|ticks| ticks as f64)),
+        ),
+        transfers: stats(metrics.iter().map(|m| m.transfers as f64)),
+        final_variance: stats(metrics.iter().map(|m| m.final_variance)),
+    })
+}
+
+fn run_once(args: &Args, seed: u64, ticks: u64) -> Result<RunMetrics> {
+    let mut simulation = Simulation::from_args(args, seed)?;
+    let mut convergence_ticks = None;
+    for tick in 1..=ticks {
+        simulation.tick()?;
+        if convergence_ticks.is_none() && simulation.buckets().is_balanced(args.balance_tolerance) {
+            convergence_ticks = Some(tick);
+        }
+    }
+    Ok(RunMetrics {
+        convergence_ticks,
+        transfers: simulation.transfers(),
+        final_variance: simulation.buckets().variance().unwrap_or(0.0),
+    })
+}
+
+/// Computes the mean and population standard deviation of the given values.
+fn stats(values: impl Iterator<Item = f64>) -> Stats {
+    let values = values.collect::<Vec<_>>();
+    if values.is_empty() {
+        return Stats {
+            count: 0,
+            mean: 0.0,
+            stddev: 0.0,
+        };
+    }
+    let count = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / count;
+    let variance = values
+        .iter()
+        .map(|value| (value - mean).powi(2))
+        .sum::<f64>()
+        / count;
+    Stats {
+        count: values.len(),
+        mean,
+        stddev: variance.sqrt(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::buckets::ids::BucketId;
+
+    fn args() -> Args {
+        Args::try_parse_from(["buckets", "--policy", "spread", "-i", "1:10,2:50,3:90"])
+            .expect("arguments parse")
+    }
+
+    /// Returns the bucket states after each of the first `ticks` ticks of a run with the given seed.
+    fn trajectory(seed: u64, ticks: u64) -> Result<Vec<HashMap<BucketId, u64>>> {
+        let mut simulation = Simulation::from_args(&args(), seed)?;
+        (0..ticks)
+            .map(|_| {
+                simulation.tick()?;
+                Ok(simulation.buckets().buckets().clone())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn runs_are_seeded_apart() -> Result<()> {
+        assert_eq!(trajectory(7, 50)?, trajectory(7, 50)?);
+        assert_ne!(trajectory(7, 50)?, trajectory(8, 50)?);
+
+        let summary = run(&args(), 7, 2, 50)?;
+        assert_eq!(summary.runs, 2);
+        assert_eq!(summary.transfers.count, 2);
+        assert_eq!(summary.final_variance.count, 2);
+        assert!(summary.convergence_ticks.count <= 2);
+        for stats in [
+            &summary.convergence_ticks,
+            &summary.transfers,
+            &summary.final_variance,
+        ] {
+            assert!(stats.mean.is_finite() && stats.mean >= 0.0);
+            assert!(stats.stddev.is_finite() && stats.stddev >= 0.0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn stats_are_the_mean_and_population_stddev() {
+        let summary = stats([2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0].into_iter());
+        assert_eq!((summary.count, summary.mean, summary.stddev), (8, 5.0, 2.0));
+        let empty = stats(std::iter::empty());
+        assert_eq!((empty.count, empty.mean, empty.stddev), (0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,180 @@
+//! Overflow alarms tell a bucket that is merely high apart from one that actually hit its ceiling:
+//! a bucket overflows when a fill into it had to be clamped at its capacity, spilling the rest.
+//! Alarms are edge-triggered, so a bucket that keeps being filled while full raises one alarm, and
+//! only raises another once it has dropped below its capacity and overflowed again.
+//!
+//! With auto-grow, overflows also make the capacity grow, modeling elastic storage: every time a
+//! bucket overflows, the capacity of the buckets grows by a factor, up to a maximum.
+
+use std::collections::BTreeSet;
+
+use crate::buckets::ids::BucketId;
+use crate::buckets::{Buckets, Fill};
+use crate::sensor::Sensor;
+
+pub struct Overflows {
+    // Buckets that overflowed and haven't dropped below their capacity since.
+    overflowing: BTreeSet<BucketId>,
+}
+
+impl Default for Overflows {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Overflows {
+    pub fn new() -> Self {
+        Overflows {
+            overflowing: BTreeSet::new(),
+        }
+    }
+
+    /// Checks the latest fills for overflows, returning an alarm for every bucket that newly
+    /// overflowed. Buckets that have since dropped below their capacity are re-armed first.
+    pub fn check<S: Sensor>(&mut self, fills: &[Fill], sensor: &S) -> Vec<String> {
+        let capacity = sensor.capacity();
+        self.overflowing.retain(|bucket| {
+            sensor
+                .buckets()
+                .get(bucket)
+                .is_some_and(|quantity| *quantity >= capacity)
+        });
+        fills
+            .iter()
+            .filter(|fill| fill.spilled > 0 && self.overflowing.insert(fill.bucket))
+            .map(|fill| {
+                format!(
+                    "bucket {} overflowed its capacity of {}, spilling {}",
+                    fill.bucket, capacity, fill.spilled
+                )
+            })
+            .collect()
+    }
+}
+
+/// AutoGrow decides how the capacity grows whenever a bucket overflows.
+#[derive(Clone, Copy)]
+pub struct AutoGrow {
+    // What the capacity is multiplied by on every overflow. Always above 1.
+    pub factor: f64,
+    // The capacity never grows past this.
+    pub max: u64,
+}
+
+impl AutoGrow {
+    /// Returns the capacity to grow to from `capacity`, or None if it's already at the maximum.
+    pub fn grown(&self, capacity: u64) -> Option<u64> {
+        if capacity >= self.max {
+            return None;
+        }
+        // Grow by at least one, so that small capacities still go somewhere.
+        let grown = ((capacity as f64 * self.factor).ceil() as u64).max(capacity + 1);
+        Some(grown.min(self.max))
+    }
+
+    /// Grows the capacity of the buckets if any of the given overflow alarms fired, returning a
+    /// message describing the growth, if any.
+    pub fn apply<B: Buckets + Sensor>(&self, buckets: &mut B, alarms: &[String]) -> Option<String> {
+        if alarms.is_empty() {
+            return None;
+        }
+        let old = buckets.capacity();
+        let new = self.grown(old)?;
+        // Growing never leaves a bucket above the capacity, so nothing spills.
+        buckets.set_capacity(new);
+        Some(format!(
+            "auto-grew capacity {} => {} after an overflow",
+            old, new
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::actuator::FinalControlElement;
+    use crate::buckets::n_buckets::NBuckets;
+    use crate::buckets::FillStrategy;
+
+    #[test]
+    fn overflows_alarm_once_until_refilled() -> anyhow::Result<()> {
+        let mut buckets = NBuckets::new(
+            HashMap::from([(BucketId(1), 95), (BucketId(2), 0)]),
+            100,
+            FillStrategy::Steady,
+            None,
+            None,
+            HashMap::new(),
+            Some(0),
+        );
+        let mut overflows = Overflows::new();
+        let mut pour = |buckets: &mut NBuckets, amount| -> anyhow::Result<usize> {
+            let fill = buckets.pour(BucketId(1), amount)?;
+            Ok(overflows.check(&[fill], &*buckets).len())
+        };
+        // Filling up to the brim doesn't overflow.
+        assert_eq!(pour(&mut buckets, 5)?, 0);
+        assert_eq!(pour(&mut buckets, 10)?, 1);
+        assert_eq!(pour(&mut buckets, 10)?, 0);
+        assert_eq!(pour(&mut buckets, 10)?, 0);
+        buckets.transfer(BucketId(1), BucketId(2), 20)?;
+        assert_eq!(pour(&mut buckets, 10)?, 0);
+        assert_eq!(pour(&mut buckets, 20)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn grows_up_to_the_max_and_no_further() {
+        let mut buckets = NBuckets::new(
+            HashMap::from([(BucketId(1), 10)]),
+            10,
+            FillStrategy::Steady,
+            None,
+            None,
+            HashMap::new(),
+            Some(0),
+        );
+        let auto_grow = AutoGrow {
+            factor: 2.0,
+            max: 50,
+        };
+        let overflowed = ["bucket 1 overflowed".to_string()];
+        let mut capacities = Vec::new();
+        for _ in 0..5 {
+            auto_grow.apply(&mut buckets, &overflowed);
+            capacities.push(buckets.capacity());
+        }
+        assert_eq!(capacities, [20, 40, 50, 50, 50]);
+        // Without an overflow, nothing grows.
+        let auto_grow = AutoGrow {
+            factor: 2.0,
+            max: 100,
+        };
+        assert_eq!(auto_grow.apply(&mut buckets, &[]), None);
+        assert_eq!(buckets.capacity(), 50);
+    }
+
+    #[test]
+    fn grows_by_at_least_one() {
+        let auto_grow = AutoGrow {
+            factor: 1.01,
+            max: 10,
+        };
+        assert_eq!(auto_grow.grown(1), Some(2));
+        assert_eq!(auto_grow.grown(9), Some(10));
+        assert_eq!(auto_grow.grown(10), None);
+    }
+
+    #[test]
+    fn never_grows_past_the_largest_capacity() {
+        let auto_grow = AutoGrow {
+            factor: 2.0,
+            max: u64::MAX,
+        };
+        assert_eq!(auto_grow.grown(u64::MAX), None);
+        assert_eq!(auto_grow.grown(u64::MAX - 1), Some(u64::MAX));
+    }
+}
@@ -0,0 +1,31 @@
+//! Pause is a switch, shared between the TUI and the filler, controller and actuator loops, that
+//! freezes the simulation without ending it. While it's on, the loops keep to their schedules but
+//! skip their work, so nothing fills, moves or gets decided until it's turned back off. The TUI
+//! keeps drawing, and still takes manual commands.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct Pause(Arc<AtomicBool>);
+
+impl Default for Pause {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pause {
+    pub fn new() -> Self {
+        Pause(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Flips the switch, returning whether the simulation is now paused.
+    pub fn toggle(&self) -> bool {
+        !self.0.fetch_xor(true, Ordering::Relaxed)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
@@ -1,26 +1,335 @@
 //! A policy implements a particular control strategy for a controller.
 //! A policy implementation takes signal from a sensor and emits a control signal based on it.
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
 
-use tokio::sync::MutexGuard;
-
+use anyhow::{anyhow, Result};
 use clap::ValueEnum;
+use itertools::Itertools;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio_util::sync::CancellationToken;
 
-use crate::actuator::Action;
-use crate::sensor::Sensor;
+use crate::actuator::{Action, BatchOrder};
+use crate::buckets::ids::BucketId;
+use crate::sensor::{Readings, Sensor};
 
-#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
 pub enum Policy {
+    /// Even out every bucket at once, moving fluid from the fullest buckets into the emptiest.
     Spread,
+    /// Rearrange the quantities so that they increase with bucket ID.
+    Sort,
+    /// Drain any bucket at or above the critical level down to the safe level.
+    EmergencyDrain,
+    /// Pick the transfer that best trades off balance, the soft cap and priority buckets.
+    Weighted,
+    /// Keep the reserve bucket topped up to the reserve level, and spread the others.
+    Reserve,
+    /// Move a random amount between two random buckets, as a baseline to compare the others to.
+    Random,
+    /// Steer every bucket towards the mean with a PID controller on its deviation from it.
+    Pid,
+    /// Drive every bucket to the target level, moving fluid from those above it into those below.
+    Target,
+    /// The opposite of Spread: empty the emptiest non-empty bucket into the fullest, until it's
+    /// full.
+    Concentrate,
+    /// Never do anything.
     NoOp,
 }
 
+/// PolicyParams holds the tunables of the policies that have any.
+#[derive(Clone, Debug)]
+pub struct PolicyParams {
+    /// EmergencyDrain acts once a bucket reaches this quantity.
+    pub critical_level: u64,
+    /// EmergencyDrain drains a critical bucket down to this quantity.
+    pub safe_level: u64,
+    /// The objectives the Weighted policy minimizes, and how much each one counts.
+    pub weights: ObjectiveWeights,
+    /// Quantity above which a bucket counts against the Weighted policy's cap objective.
+    pub soft_cap: u64,
+    /// Buckets the Weighted policy's priority objective tries to keep as empty as possible.
+    pub priority_buckets: HashSet<BucketId>,
+    /// The bucket the Reserve policy keeps topped up, if any.
+    pub reserve_bucket: Option<BucketId>,
+    /// Quantity the Reserve policy keeps the reserve bucket at or above.
+    pub reserve_level: u64,
+    /// Number of analyses a bucket is left out of after taking part in a transfer.
+    pub cooldown: u64,
+    /// Spread stops acting once the fullest and emptiest buckets are within this of each other.
+    pub balance_tolerance: u64,
+    /// The gains of the PID policy's controller.
+    pub gains: PidGains,
+    /// The level the Target policy drives every bucket to, if any.
+    pub target: Option<u64>,
+    /// The order the transfers of a multi-transfer are applied in.
+    pub batch_order: BatchOrder,
+}
+
+/// PolicyState is what policies carry over from one analysis to the next: the RNG that random
+/// choices are drawn from, and the PID policy's accumulated error terms. Whoever runs the policy
+/// owns it, so it outlives any single analysis.
+pub struct PolicyState {
+    pub rng: StdRng,
+    pid: PidState,
+}
+
+impl PolicyState {
+    /// Creates the state of a policy that hasn't analyzed anything yet, with its RNG seeded as by
+    /// `seeded_rng`.
+    pub fn new(seed: Option<u64>) -> Self {
+        PolicyState {
+            rng: seeded_rng(seed),
+            pid: PidState::default(),
+        }
+    }
+
+    /// Forgets the accumulated error terms, e.g. on a policy switch, so that one stint of the PID
+    /// policy doesn't carry its history into the next. The RNG carries on.
+    pub fn reset(&mut self) {
+        self.pid = PidState::default();
+    }
+}
+
+/// PidState holds the error terms the PID policy accumulates per bucket, where the error is how
+/// far below the mean a bucket is.
+#[derive(Default)]
+struct PidState {
+    integrals: HashMap<BucketId, f64>,
+    // The error as of the last analysis, which the derivative is taken against.
+    errors: HashMap<BucketId, f64>,
+}
+
+/// Creates the RNG that policies draw their random choices from, seeded from `seed` if given and
+/// from entropy otherwise. The seed is offset so that the policy and the fill, when given the same
+/// seed, don't draw the same sequence.
+pub fn seeded_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(1)),
+        None => StdRng::from_os_rng(),
+    }
+}
+
+/// Splits `total` into `n` integer shares that add up to exactly `total`, for policies that compute
+/// per-bucket targets. Plain division would lose the remainder to truncation (100 across 3 buckets
+/// is 33 each, losing 1), so the remainder goes one unit at a time to the first shares instead,
+/// e.g. 100 across 3 is `[34, 33, 33]`. Nothing can be split across no shares, so `n` of 0 gives
+/// no shares at all.
+pub fn distribute(total: u64, n: usize) -> Vec<u64> {
+    if n == 0 {
+        return Vec::new();
+    }
+    let (share, remainder) = (total / n as u64, total % n as u64);
+    (0..n as u64)
+        .map(|i| share + (i < remainder) as u64)
+        .collect()
+}
+
+/// Turns planned transfers into a single action: nothing, a plain transfer, or a multi-transfer.
+fn transfers_action(mut transfers: Vec<(BucketId, BucketId, u64)>) -> Action {
+    match transfers.len() {
+        0 => Action::NoAction,
+        1 => {
+            let (source, destination, amount) = transfers.remove(0);
+            Action::Transfer {
+                source,
+                destination,
+                amount,
+            }
+        }
+        _ => Action::MultiTransfer(transfers),
+    }
+}
+
+/// Pairs buckets that have fluid to give with buckets that have room to take it, as (source,
+/// destination, amount): the first source keeps giving to the first destination until either is
+/// done, then moves on to the next. Both lists are taken in the order given, and the transfers move
+/// the smaller of what the sources give and the destinations take in all.
+fn pair_up(
+    surpluses: Vec<(BucketId, u64)>,
+    deficits: Vec<(BucketId, u64)>,
+) -> Vec<(BucketId, BucketId, u64)> {
+    let mut transfers = Vec::new();
+    let (mut surpluses, mut deficits) = (surpluses.into_iter(), deficits.into_iter());
+    let (mut surplus, mut deficit) = (surpluses.next(), deficits.next());
+    while let (Some((source, give)), Some((destination, take))) = (surplus, deficit) {
+        let amount = give.min(take);
+        transfers.push((source, destination, amount));
+        surplus = if give > amount {
+            Some((source, give - amount))
+        } else {
+            surpluses.next()
+        };
+        deficit = if take > amount {
+            Some((destination, take - amount))
+        } else {
+            deficits.next()
+        };
+    }
+    transfers
+}
+
+/// Plans the transfers that even out the buckets in one go, as (source, destination, amount). Every
+/// bucket's target is its share of the total, as evenly as whole units allow: the fullest buckets
+/// get the shares with a unit of the remainder, since they have the least to give up. Then the
+/// fullest bucket above its target keeps giving to the emptiest one below it, until every bucket is
+/// at its target. This takes at most one transfer fewer than there are buckets, and no transfer
+/// exceeds capacity, since no target is above the fullest bucket. Ties go to the lowest bucket ID.
+fn spread_plan<S: Sensor>(sensor: &S) -> Vec<(BucketId, BucketId, u64)> {
+    let by_quantity = sensor
+        .buckets()
+        .iter()
+        .map(|(bucket, quantity)| (*bucket, *quantity))
+        .sorted_by_key(|(bucket, quantity)| (Reverse(*quantity), *bucket))
+        .collect::<Vec<_>>();
+    let targets = distribute(sensor.total(), by_quantity.len());
+    let mut surpluses = Vec::new();
+    let mut deficits = Vec::new();
+    for ((bucket, quantity), target) in by_quantity.into_iter().zip(targets) {
+        if quantity > target {
+            surpluses.push((bucket, quantity - target));
+        } else if quantity < target {
+            deficits.push((bucket, target - quantity));
+        }
+    }
+    // Surpluses are ordered from the fullest bucket down, deficits from the emptiest up.
+    deficits.reverse();
+    pair_up(surpluses, deficits)
+}
+
+/// Plans the Target policy's transfers, as (source, destination, amount). Unless the total happens
+/// to be exactly `target` per bucket, not every bucket can end up at the target: there's either
+/// too little fluid to bring the emptiest up to it, or too much to take the fullest down to it. So
+/// this moves as much as both sides allow, from the buckets above the target to those below it,
+/// which leaves the least total deviation from the target that the total allows. The buckets
+/// furthest above give first, and the buckets furthest below take first, so whatever deviation is
+/// left over is spread over as many buckets as possible. A target above the capacity is treated as
+/// the capacity. Ties go to the lowest bucket ID.
+fn target_plan<S: Sensor>(sensor: &S, target: u64) -> Vec<(BucketId, BucketId, u64)> {
+    let target = target.min(sensor.capacity());
+    let mut surpluses = Vec::new();
+    let mut deficits = Vec::new();
+    for (bucket, quantity) in sensor.buckets().iter().sorted() {
+        if *quantity > target {
+            surpluses.push((*bucket, quantity - target));
+        } else if *quantity < target {
+            deficits.push((*bucket, target - quantity));
+        }
+    }
+    // The sort is stable, so equal deviations stay in order of bucket ID.
+    surpluses.sort_by_key(|(_, give)| Reverse(*give));
+    deficits.sort_by_key(|(_, take)| Reverse(*take));
+    pair_up(surpluses, deficits)
+}
+
+/// Plans the PID policy's transfers, as (source, destination, amount), updating its accumulated
+/// error terms. Every bucket's output is how much it should gain (or, if negative, lose) this
+/// analysis, rounded to whole units and clamped to what it holds and has room for. The buckets that
+/// should lose the most give to those that should gain the most, as far as both sides allow. Ties
+/// go to the lowest bucket ID.
+fn pid_plan<S: Sensor>(
+    sensor: &S,
+    gains: &PidGains,
+    state: &mut PidState,
+) -> Vec<(BucketId, BucketId, u64)> {
+    let buckets = sensor.buckets();
+    if buckets.is_empty() {
+        return Vec::new();
+    }
+    let mean = sensor.total() as f64 / buckets.len() as f64;
+    // Buckets that are gone take their history with them.
+    state
+        .integrals
+        .retain(|bucket, _| buckets.contains_key(bucket));
+    state
+        .errors
+        .retain(|bucket, _| buckets.contains_key(bucket));
+
+    let mut surpluses = Vec::new();
+    let mut deficits = Vec::new();
+    for (bucket, quantity) in buckets.iter().sorted() {
+        let error = mean - *quantity as f64;
+        let integral = state.integrals.entry(*bucket).or_default();
+        *integral += error;
+        // A bucket seen for the first time has no previous error to change from.
+        let derivative = state
+            .errors
+            .insert(*bucket, error)
+            .map_or(0.0, |previous| error - previous);
+        let output = (gains.kp * error + gains.ki * *integral + gains.kd * derivative).round();
+        if output < 0.0 {
+            let give = (-output as u64).min(*quantity);
+            if give > 0 {
+                surpluses.push((*bucket, give));
+            }
+        } else {
+            let take = (output as u64).min(sensor.capacity().saturating_sub(*quantity));
+            if take > 0 {
+                deficits.push((*bucket, take));
+            }
+        }
+    }
+    // The sort is stable, so equal outputs stay in order of bucket ID.
+    surpluses.sort_by_key(|(_, give)| Reverse(*give));
+    deficits.sort_by_key(|(_, take)| Reverse(*take));
+    pair_up(surpluses, deficits)
+}
+
+/// ObjectiveWeights scales each of the objectives the Weighted policy combines into its cost:
+///   * balance: the variance of the bucket quantities.
+///   * cap: the total quantity held above the soft cap.
+///   * priority: the total quantity held in priority buckets.
+#[derive(Clone, Debug)]
+pub struct ObjectiveWeights {
+    pub balance: f64,
+    pub cap: f64,
+    pub priority: f64,
+}
+
+/// PidGains scales each of the terms the PID policy sums into a bucket's output:
+///   * kp: the error itself, i.e. how far below the mean the bucket is.
+///   * ki: the error accumulated over every analysis so far.
+///   * kd: how much the error changed since the last analysis.
+#[derive(Clone, Debug)]
+pub struct PidGains {
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+}
+
 impl Display for Policy {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Policy::Spread => {
                 write!(f, "Spread")
             }
+            Policy::Sort => {
+                write!(f, "Sort")
+            }
+            Policy::EmergencyDrain => {
+                write!(f, "EmergencyDrain")
+            }
+            Policy::Weighted => {
+                write!(f, "Weighted")
+            }
+            Policy::Reserve => {
+                write!(f, "Reserve")
+            }
+            Policy::Random => {
+                write!(f, "Random")
+            }
+            Policy::Pid => {
+                write!(f, "PID")
+            }
+            Policy::Target => {
+                write!(f, "Target")
+            }
+            Policy::Concentrate => {
+                write!(f, "Concentrate")
+            }
             Policy::NoOp => {
                 write!(f, "NoOp")
             }
@@ -29,35 +338,700 @@ impl Display for Policy {
 }
 
 impl Policy {
-    pub(crate) fn analyze<S: Sensor>(&self, sensor: MutexGuard<S>) -> Action {
-        eprintln!("analyzing sensor data for policy: {}", self);
-        match self {
+    /// Decides on the action to take given the sensor data. Policies that make random choices draw
+    /// them from the state's RNG, and those that learn from past analyses keep what they learned in
+    /// the state too. Fails if the sensor data is inconsistent in a way that would make the
+    /// policy's arithmetic overflow, or if `ct` is cancelled before a long-running policy finishes
+    /// its search.
+    pub fn analyze<S: Sensor>(
+        &self,
+        sensor: &S,
+        params: &PolicyParams,
+        state: &mut PolicyState,
+        ct: &CancellationToken,
+    ) -> Result<Action> {
+        let action = match self {
             Policy::Spread => {
-                // NOTE: This implementation is actually a bit inefficient, since we could
-                // technically try to fix the imbalance immediately instead of doing a single tiny
-                // transfer each time.
-                let min_bucket = sensor.get_smallest_bucket();
-                let max_bucket = sensor.get_largest_bucket();
-                if min_bucket == max_bucket {
-                    // All buckets are equal, nothing to do!
-                    return Action::NoAction;
-                }
-                if let Some((min_bucket, min_qty)) = min_bucket {
-                    if let Some((max_bucket, max_qty)) = max_bucket {
-                        let diff = max_qty - min_qty;
-                        let transfer_amount = diff / 2;
-                        return Action::Transfer {
-                            source: max_bucket,
-                            destination: min_bucket,
-                            amount: transfer_amount,
-                        };
+                if sensor.is_balanced(params.balance_tolerance) {
+                    // Balanced as far as we care, nothing to do!
+                    return Ok(Action::NoAction);
+                }
+                transfers_action(spread_plan(sensor))
+            }
+            Policy::Sort => {
+                // The sorted arrangement holds exactly the quantities we have now, just assigned to
+                // the buckets in increasing order of ID. So each bucket's target is the quantity at
+                // its ID's rank, and we move fluid from the bucket furthest above its target into
+                // the one furthest below it. Ties go to the lowest bucket ID.
+                let buckets = sensor.buckets();
+                let deviations = buckets
+                    .keys()
+                    .sorted()
+                    .zip(buckets.values().sorted())
+                    .map(|(bucket, target)| (*bucket, buckets[bucket] as i128 - *target as i128))
+                    .collect::<Vec<_>>();
+                let source = deviations
+                    .iter()
+                    .filter(|(_, deviation)| *deviation > 0)
+                    .max_by_key(|(bucket, deviation)| (*deviation, Reverse(*bucket)));
+                let destination = deviations
+                    .iter()
+                    .filter(|(_, deviation)| *deviation < 0)
+                    .min_by_key(|(bucket, deviation)| (*deviation, *bucket));
+                match (source, destination) {
+                    (Some((source, surplus)), Some((destination, deficit))) => Action::Transfer {
+                        source: *source,
+                        destination: *destination,
+                        amount: (*surplus).min(-*deficit) as u64,
+                    },
+                    // Already sorted, nothing to do!
+                    _ => Action::NoAction,
+                }
+            }
+            Policy::EmergencyDrain => {
+                // Only the fullest bucket can be drained in a single action, so deal with that one
+                // first. Any others over the critical level get their turn on later ticks.
+                let Some((source, quantity)) = sensor.get_largest_bucket() else {
+                    return Ok(Action::NoAction);
+                };
+                if quantity < params.critical_level {
+                    return Ok(Action::NoAction);
+                }
+                let Some((destination, destination_quantity)) = sensor.get_smallest_bucket() else {
+                    return Ok(Action::NoAction);
+                };
+                // Drain all the way to the safe level if the emptiest bucket has room for it, and
+                // as much as fits otherwise.
+                let room = sensor.capacity().saturating_sub(destination_quantity);
+                let amount = quantity.saturating_sub(params.safe_level).min(room);
+                if destination == source || amount == 0 {
+                    return Ok(Action::NoAction);
+                }
+                Action::Transfer {
+                    source,
+                    destination,
+                    amount,
+                }
+            }
+            Policy::Weighted => {
+                // Score a handful of candidate transfers between every pair of buckets and pick
+                // the one that lowers the weighted cost the most. Doing nothing is a candidate too,
+                // so we only act if something actually improves on the current state.
+                let buckets = sensor.buckets();
+                let capacity = sensor.capacity();
+                let cost = WeightedCost::new(sensor, params);
+                let mut best = (cost.current(), Action::NoAction);
+                for ((source, source_qty), (destination, destination_qty)) in buckets
+                    .iter()
+                    .sorted()
+                    .cartesian_product(buckets.iter().sorted())
+                {
+                    // The search is quadratic in the number of buckets, so give up on it as soon
+                    // as we're told to, rather than finishing it only to have it thrown away.
+                    if ct.is_cancelled() {
+                        return Err(anyhow!("'{}' policy analysis was cancelled", self));
+                    }
+                    if source == destination {
+                        continue;
+                    }
+                    let room = capacity.saturating_sub(*destination_qty);
+                    let most = (*source_qty).min(room);
+                    let halfway = source_qty.saturating_sub(*destination_qty) / 2;
+                    for amount in [1, halfway, most] {
+                        if amount == 0 || amount > most {
+                            continue;
+                        }
+                        let candidate = cost.after_transfer(
+                            (*source, *source_qty),
+                            (*destination, *destination_qty),
+                            amount,
+                        );
+                        if candidate < best.0 {
+                            best = (
+                                candidate,
+                                Action::Transfer {
+                                    source: *source,
+                                    destination: *destination,
+                                    amount,
+                                },
+                            );
+                        }
                     }
                 }
-
-                eprintln!("unreachable?: could not unpack min/max bucket information");
-                Action::NoAction
+                best.1
+            }
+            Policy::Reserve => {
+                let buckets = sensor.buckets();
+                let reserve = params
+                    .reserve_bucket
+                    .and_then(|bucket| buckets.get(&bucket).map(|quantity| (bucket, *quantity)));
+                let Some((reserve, reserve_qty)) = reserve else {
+                    // Without a reserve there is nothing to keep topped up, so just spread.
+                    return Policy::Spread.analyze(sensor, params, state, ct);
+                };
+                let others = Readings::new(
+                    buckets
+                        .iter()
+                        .filter(|(bucket, _)| **bucket != reserve)
+                        .map(|(bucket, quantity)| (*bucket, *quantity))
+                        .collect(),
+                    sensor.capacity(),
+                );
+                // Topping up the reserve comes first, from whichever other bucket has the most to
+                // spare. Only once it's at its level do the others get balanced among themselves.
+                let need = params.reserve_level.saturating_sub(reserve_qty);
+                if need == 0 {
+                    return Policy::Spread.analyze(&others, params, state, ct);
+                }
+                let Some((source, source_qty)) = others.get_largest_bucket() else {
+                    return Ok(Action::NoAction);
+                };
+                let room = sensor.capacity().saturating_sub(reserve_qty);
+                let amount = need.min(source_qty).min(room);
+                if amount == 0 {
+                    return Ok(Action::NoAction);
+                }
+                Action::Transfer {
+                    source,
+                    destination: reserve,
+                    amount,
+                }
+            }
+            Policy::Random => {
+                // Sort the buckets so that seeded runs pick the same ones.
+                let buckets = sensor.buckets().iter().sorted().collect::<Vec<_>>();
+                if buckets.len() < 2 {
+                    return Ok(Action::NoAction);
+                }
+                let rng = &mut state.rng;
+                let source = rng.random_range(0..buckets.len());
+                // Pick the destination among the other buckets, skipping over the source.
+                let destination = rng.random_range(0..buckets.len() - 1);
+                let destination = destination + (destination >= source) as usize;
+                let (source, source_qty) = buckets[source];
+                let (destination, destination_qty) = buckets[destination];
+                // Clamp to what the source holds and the destination has room for, so the
+                // transfer is always legal.
+                let most = (*source_qty).min(sensor.capacity().saturating_sub(*destination_qty));
+                if most == 0 {
+                    return Ok(Action::NoAction);
+                }
+                Action::Transfer {
+                    source: *source,
+                    destination: *destination,
+                    amount: rng.random_range(1..=most),
+                }
+            }
+            Policy::Pid => {
+                if sensor.is_balanced(params.balance_tolerance) {
+                    // Settled, so what led up to it shouldn't push the buckets back out of
+                    // balance.
+                    state.reset();
+                    return Ok(Action::NoAction);
+                }
+                transfers_action(pid_plan(sensor, &params.gains, &mut state.pid))
+            }
+            Policy::Target => {
+                let Some(target) = params.target else {
+                    // Without a target, the mean is as good a level as any, so just spread.
+                    return Policy::Spread.analyze(sensor, params, state, ct);
+                };
+                transfers_action(target_plan(sensor, target))
+            }
+            Policy::Concentrate => {
+                // Empty buckets have nothing to give, so the source is the emptiest of the rest.
+                // Ties go to the lowest bucket ID for the source, and the highest for the
+                // destination, so the two differ as long as more than one bucket holds anything.
+                let holding = Readings::new(
+                    sensor
+                        .buckets()
+                        .iter()
+                        .filter(|(_, quantity)| **quantity > 0)
+                        .map(|(bucket, quantity)| (*bucket, *quantity))
+                        .collect(),
+                    sensor.capacity(),
+                );
+                if holding.buckets().len() < 2 {
+                    return Ok(Action::NoAction);
+                }
+                let (Some((source, source_qty)), Some((destination, destination_qty))) =
+                    (holding.get_smallest_bucket(), holding.get_largest_bucket())
+                else {
+                    return Ok(Action::NoAction);
+                };
+                // Move as much as fits, which is nothing once the fullest bucket is full.
+                let amount = source_qty.min(sensor.capacity().saturating_sub(destination_qty));
+                if amount == 0 {
+                    return Ok(Action::NoAction);
+                }
+                Action::Transfer {
+                    source,
+                    destination,
+                    amount,
+                }
             }
             Policy::NoOp => Action::NoAction,
+        };
+        Ok(params.batch_order.order(action))
+    }
+
+    /// Returns a description of the critical condition the policy is reacting to, if any.
+    pub fn alarm<S: Sensor>(&self, sensor: &S, params: &PolicyParams) -> Option<String> {
+        match self {
+            Policy::EmergencyDrain => sensor
+                .get_largest_bucket()
+                .filter(|(_, quantity)| *quantity >= params.critical_level)
+                .map(|(bucket, quantity)| {
+                    format!(
+                        "bucket {} is at {}, at or above the critical level of {}",
+                        bucket, quantity, params.critical_level
+                    )
+                }),
+            Policy::Spread
+            | Policy::Sort
+            | Policy::Weighted
+            | Policy::Reserve
+            | Policy::Random
+            | Policy::Pid
+            | Policy::Target
+            | Policy::Concentrate
+            | Policy::NoOp => None,
+        }
+    }
+}
+
+/// WeightedCost is the Weighted policy's cost of a bucket state, kept as the sums it is made of.
+/// A transfer only changes two buckets (and not the total), so the cost after any transfer can be
+/// computed from these in constant time, rather than by copying and rescoring the whole state for
+/// every candidate.
+struct WeightedCost<'a> {
+    params: &'a PolicyParams,
+    count: f64,
+    mean: f64,
+    sum_of_squares: f64,
+    over_cap: u64,
+    priority_load: u64,
+}
+
+impl<'a> WeightedCost<'a> {
+    fn new<S: Sensor>(sensor: &S, params: &'a PolicyParams) -> Self {
+        let buckets = sensor.buckets();
+        WeightedCost {
+            params,
+            count: buckets.len() as f64,
+            mean: sensor.mean().unwrap_or(0.0),
+            sum_of_squares: buckets
+                .values()
+                .map(|quantity| (*quantity as f64).powi(2))
+                .sum(),
+            over_cap: buckets
+                .values()
+                .map(|quantity| quantity.saturating_sub(params.soft_cap))
+                .fold(0u64, u64::saturating_add),
+            priority_load: buckets
+                .iter()
+                .filter(|(bucket, _)| params.priority_buckets.contains(bucket))
+                .map(|(_, quantity)| *quantity)
+                .fold(0u64, u64::saturating_add),
+        }
+    }
+
+    /// Returns the cost of the state as it is. Lower is better.
+    fn current(&self) -> f64 {
+        self.cost(self.sum_of_squares, self.over_cap, self.priority_load)
+    }
+
+    /// Returns the cost of the state after moving `amount` from the source bucket to the
+    /// destination bucket, each given as its ID and current quantity. The amount must be at most
+    /// the source's quantity.
+    fn after_transfer(
+        &self,
+        source: (BucketId, u64),
+        destination: (BucketId, u64),
+        amount: u64,
+    ) -> f64 {
+        let params = self.params;
+        let (source, source_qty) = source;
+        let (destination, destination_qty) = destination;
+        let new_source_qty = source_qty - amount;
+        let new_destination_qty = destination_qty.saturating_add(amount);
+
+        let square = |quantity: u64| (quantity as f64).powi(2);
+        let sum_of_squares = self.sum_of_squares - square(source_qty) - square(destination_qty)
+            + square(new_source_qty)
+            + square(new_destination_qty);
+
+        let over_cap = |quantity: u64| quantity.saturating_sub(params.soft_cap);
+        let over_cap = self
+            .over_cap
+            .saturating_sub(over_cap(source_qty).saturating_add(over_cap(destination_qty)))
+            .saturating_add(over_cap(new_source_qty).saturating_add(over_cap(new_destination_qty)));
+
+        let mut priority_load = self.priority_load;
+        if params.priority_buckets.contains(&source) {
+            priority_load = priority_load.saturating_sub(amount);
+        }
+        if params.priority_buckets.contains(&destination) {
+            priority_load = priority_load.saturating_add(amount);
+        }
+
+        self.cost(sum_of_squares, over_cap, priority_load)
+    }
+
+    fn cost(&self, sum_of_squares: f64, over_cap: u64, priority_load: u64) -> f64 {
+        let weights = &self.params.weights;
+        // Transfers keep the total, and so the mean, the same.
+        let imbalance = if self.count == 0.0 {
+            0.0
+        } else {
+            (sum_of_squares / self.count - self.mean.powi(2)).max(0.0)
+        };
+        weights.balance * imbalance
+            + weights.cap * over_cap as f64
+            + weights.priority * priority_load as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::buckets::n_buckets::NBuckets;
+    use crate::buckets::{FillStrategy, MAX_QUANTITY};
+    use crate::cli::Args;
+
+    // Buckets 1, 2, ... holding the given quantities of MAX_QUANTITY.
+    fn buckets(quantities: &[u64]) -> NBuckets {
+        let data = quantities
+            .iter()
+            .zip(1..)
+            .map(|(quantity, bucket)| (BucketId(bucket), *quantity))
+            .collect();
+        NBuckets::new(
+            data,
+            MAX_QUANTITY,
+            FillStrategy::Steady,
+            None,
+            None,
+            HashMap::new(),
+            Some(0),
+        )
+    }
+
+    // Analyzes the sensor once with the policy, tuned by the given command line flags.
+    fn analyze<S: Sensor>(policy: Policy, sensor: &S, flags: &[&str]) -> Result<Action> {
+        let args = Args::try_parse_from(["buckets"].iter().chain(flags))?;
+        policy.analyze(
+            sensor,
+            &args.policy_params(),
+            &mut PolicyState::new(Some(0)),
+            &CancellationToken::new(),
+        )
+    }
+
+    fn transfer(source: u64, destination: u64, amount: u64) -> Action {
+        Action::Transfer {
+            source: BucketId(source),
+            destination: BucketId(destination),
+            amount,
+        }
+    }
+
+    // Applies the policy's actions to the buckets until it takes no action, returning the
+    // quantities by bucket ID. Fails if the policy still acts after `steps` actions.
+    fn settle(
+        policy: Policy,
+        mut sensor: NBuckets,
+        flags: &[&str],
+        steps: usize,
+    ) -> Result<Vec<u64>> {
+        for _ in 0..steps {
+            let action = analyze(policy, &sensor, flags)?;
+            if action == Action::NoAction {
+                return Ok(sensor
+                    .buckets()
+                    .iter()
+                    .sorted()
+                    .map(|(_, quantity)| *quantity)
+                    .collect());
+            }
+            crate::actuator::apply(&mut sensor, &action)?;
+        }
+        Err(anyhow!("{} still acting after {} actions", policy, steps))
+    }
+
+    #[test]
+    fn distribute_preserves_the_total() {
+        for total in [0, 1, 99, 100, 101, 1000, u64::MAX] {
+            for n in 1..=7 {
+                assert_eq!(distribute(total, n).iter().sum::<u64>(), total);
+            }
+        }
+    }
+
+    #[test]
+    fn distribute_spreads_the_remainder_over_the_first_shares() {
+        assert_eq!(distribute(100, 3), [34, 33, 33]);
+        assert_eq!(distribute(5, 4), [2, 1, 1, 1]);
+        assert_eq!(distribute(2, 5), [1, 1, 0, 0, 0]);
+        assert_eq!(distribute(99, 3), [33, 33, 33]);
+    }
+
+    #[test]
+    fn distribute_across_no_shares() {
+        assert!(distribute(100, 0).is_empty());
+    }
+
+    /// The Weighted policy's cost as it used to be computed, rescoring the whole state from
+    /// scratch.
+    fn rescored_cost(buckets: &HashMap<BucketId, u64>, params: &PolicyParams) -> f64 {
+        let weights = &params.weights;
+        let imbalance = Readings::new(buckets.clone(), MAX_QUANTITY)
+            .variance()
+            .unwrap_or(0.0);
+        let over_cap = buckets
+            .values()
+            .map(|quantity| quantity.saturating_sub(params.soft_cap))
+            .fold(0u64, u64::saturating_add);
+        let priority_load = buckets
+            .iter()
+            .filter(|(bucket, _)| params.priority_buckets.contains(bucket))
+            .map(|(_, quantity)| *quantity)
+            .fold(0u64, u64::saturating_add);
+        weights.balance * imbalance
+            + weights.cap * over_cap as f64
+            + weights.priority * priority_load as f64
+    }
+
+    #[test]
+    fn cancelling_stops_a_long_analysis() {
+        // Scoring every pair of this many buckets would take far longer than the test allows.
+        let sensor = crate::bench::readings(20_000);
+        let params = Args::parse_from(["buckets"]).policy_params();
+        let ct = CancellationToken::new();
+        let canceller = {
+            let ct = ct.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                ct.cancel();
+            })
+        };
+        let started = std::time::Instant::now();
+        let analyzed =
+            Policy::Weighted.analyze(&sensor, &params, &mut PolicyState::new(Some(0)), &ct);
+        canceller.join().expect("canceller finished");
+        assert_eq!(
+            analyzed.unwrap_err().to_string(),
+            "'Weighted' policy analysis was cancelled"
+        );
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn weighted_cost_after_transfer_matches_rescoring() -> Result<()> {
+        let args = Args::try_parse_from([
+            "buckets",
+            "--soft-cap",
+            "60",
+            "--priority-buckets",
+            "2,5",
+            "--cap-weight",
+            "0.5",
+            "--priority-weight",
+            "0.25",
+        ])?;
+        let params = args.policy_params();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..50 {
+            let buckets = (1..=rng.random_range(1..8))
+                .map(|bucket| (BucketId(bucket), rng.random_range(0..=MAX_QUANTITY)))
+                .collect::<HashMap<_, _>>();
+            let cost = WeightedCost::new(&Readings::new(buckets.clone(), MAX_QUANTITY), &params);
+            assert!((cost.current() - rescored_cost(&buckets, &params)).abs() < 1e-6);
+            for ((source, source_qty), (destination, destination_qty)) in
+                buckets.iter().cartesian_product(buckets.iter())
+            {
+                if source == destination {
+                    continue;
+                }
+                for amount in 1..=*source_qty.min(&(MAX_QUANTITY - destination_qty)) {
+                    let mut after = buckets.clone();
+                    after.insert(*source, source_qty - amount);
+                    after.insert(*destination, destination_qty + amount);
+                    let incremental = cost.after_transfer(
+                        (*source, *source_qty),
+                        (*destination, *destination_qty),
+                        amount,
+                    );
+                    let rescored = rescored_cost(&after, &params);
+                    assert!(
+                        (incremental - rescored).abs() < 1e-6,
+                        "{:?} -({})-> {:?} from {:?}: {} != {}",
+                        source,
+                        amount,
+                        destination,
+                        buckets,
+                        incremental,
+                        rescored
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn emergency_drain_drains_a_critical_bucket() -> Result<()> {
+        let sensor = buckets(&[40, 95, 10]);
+        assert_eq!(
+            analyze(Policy::EmergencyDrain, &sensor, &[])?,
+            transfer(2, 3, 45)
+        );
+        assert!(Policy::EmergencyDrain
+            .alarm(&sensor, &Args::try_parse_from(["buckets"])?.policy_params())
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn emergency_drain_drains_a_bucket_at_the_critical_level() -> Result<()> {
+        let flags = ["--critical-level", "90"];
+        assert_eq!(
+            analyze(Policy::EmergencyDrain, &buckets(&[90, 10]), &flags)?,
+            transfer(1, 2, 40)
+        );
+        assert_eq!(
+            analyze(Policy::EmergencyDrain, &buckets(&[89, 10]), &flags)?,
+            Action::NoAction
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn spread_leaves_equal_buckets_alone() -> Result<()> {
+        let flags = ["--balance-tolerance", "0"];
+        for quantities in [&[50, 50][..], &[0, 0, 0], &[100, 100], &[50, 51]] {
+            assert_eq!(
+                analyze(Policy::Spread, &buckets(quantities), &flags)?,
+                Action::NoAction,
+                "{:?}",
+                quantities
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sort_moves_toward_sorted_order() -> Result<()> {
+        // Sorted, the buckets would hold 10, 50 and 90, so bucket 1 has the most to give.
+        assert_eq!(
+            analyze(Policy::Sort, &buckets(&[90, 10, 50]), &[])?,
+            transfer(1, 2, 40)
+        );
+        for quantities in [&[90, 10, 50][..], &[70, 0, 30, 100, 20], &[100, 0, 0, 0]] {
+            let settled = settle(Policy::Sort, buckets(quantities), &[], 10)?;
+            assert!(settled.is_sorted(), "{:?} => {:?}", quantities, settled);
+            assert_eq!(settled.iter().sum::<u64>(), quantities.iter().sum::<u64>());
+        }
+        assert_eq!(
+            analyze(Policy::Sort, &buckets(&[10, 10, 50]), &[])?,
+            Action::NoAction
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn weights_decide_the_weighted_objective() -> Result<()> {
+        let sensor = buckets(&[80, 20, 50]);
+        let weights = |balance, priority| {
+            analyze(
+                Policy::Weighted,
+                &sensor,
+                &[
+                    "--priority-buckets",
+                    "3",
+                    "--cap-weight",
+                    "0",
+                    "--balance-weight",
+                    balance,
+                    "--priority-weight",
+                    priority,
+                ],
+            )
+        };
+        // Balancing evens out buckets 1 and 2, but emptying bucket 3 moves all of it.
+        assert_eq!(weights("1", "0")?, transfer(1, 2, 30));
+        assert_eq!(weights("0", "1")?, transfer(3, 2, 50));
+        assert_eq!(weights("0", "0")?, Action::NoAction);
+        Ok(())
+    }
+
+    #[test]
+    fn reserve_is_topped_up_first() -> Result<()> {
+        let flags = ["--reserve-bucket", "1", "--reserve-level", "40"];
+        // Buckets 2 and 3 are far apart, but the reserve's shortfall comes first.
+        assert_eq!(
+            analyze(Policy::Reserve, &buckets(&[10, 90, 30, 70]), &flags)?,
+            transfer(2, 1, 30)
+        );
+        // Once it's topped up, only the others are balanced.
+        let action = analyze(Policy::Reserve, &buckets(&[60, 90, 30, 70]), &flags)?;
+        assert_ne!(action, Action::NoAction);
+        assert!(action
+            .transfers()
+            .iter()
+            .all(|(source, destination, _)| ![*source, *destination].contains(&BucketId(1))));
+        let settled = settle(Policy::Reserve, buckets(&[0, 90, 30, 70]), &flags, 20)?;
+        assert!(settled[0] >= 40, "{:?}", settled);
+        Ok(())
+    }
+
+    #[test]
+    fn random_only_emits_valid_transfers() -> Result<()> {
+        let params = Args::try_parse_from(["buckets"])?.policy_params();
+        let mut sensor = buckets(&[0, 100, 50, 100, 0]);
+        let mut state = PolicyState::new(Some(7));
+        let mut transfers = 0;
+        for _ in 0..1000 {
+            let action =
+                Policy::Random.analyze(&sensor, &params, &mut state, &CancellationToken::new())?;
+            crate::actuator::validate(&sensor, &HashSet::new(), &action)?;
+            crate::actuator::apply(&mut sensor, &action)?;
+            transfers += (action != Action::NoAction) as u32;
+        }
+        // Picks with an empty source or a full destination move nothing, but most go through.
+        assert!(transfers > 500, "only {} transfers", transfers);
+        assert_eq!(sensor.total(), 250);
+        Ok(())
+    }
+
+    #[test]
+    fn concentrate_never_overfills() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let quantities = (0..rng.random_range(1..6))
+                .map(|_| rng.random_range(0..=MAX_QUANTITY))
+                .collect::<Vec<_>>();
+            let mut sensor = buckets(&quantities);
+            for _ in 0..10 {
+                let action = analyze(Policy::Concentrate, &sensor, &[])?;
+                crate::actuator::validate(&sensor, &HashSet::new(), &action)?;
+                crate::actuator::apply(&mut sensor, &action)?;
+                assert!(sensor
+                    .buckets()
+                    .values()
+                    .all(|quantity| *quantity <= MAX_QUANTITY));
+            }
         }
+        assert_eq!(
+            analyze(Policy::Concentrate, &buckets(&[MAX_QUANTITY, 30]), &[])?,
+            Action::NoAction
+        );
+        assert_eq!(
+            analyze(Policy::Concentrate, &buckets(&[90, 30]), &[])?,
+            transfer(2, 1, 10)
+        );
+        Ok(())
     }
 }
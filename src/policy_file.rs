@@ -0,0 +1,219 @@
+//! With `--policy-file`, the policy and its parameters are read from a file that's re-read
+//! periodically, so they can be tuned while the simulation runs. The file holds one flag per line,
+//! named as on the command line but without the dashes, followed by its value, e.g.:
+//!
+//! ```text
+//! policy weighted
+//! balance-weight 2.0
+//! soft-cap 70
+//! ```
+//!
+//! Only the policy flags (see `POLICY_FLAGS`) are allowed, and any that are left out take their
+//! default values. An invalid file is reported and otherwise ignored, leaving the policy as it was.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+use crate::cli::Args;
+use crate::controller::Controller;
+use crate::events::{EventSource, Events, Severity};
+use crate::policy::{Policy, PolicyParams};
+use crate::sensor::Sensor;
+
+/// The flags a policy file may set: the policy and everything that goes into its parameters.
+const POLICY_FLAGS: [&str; 15] = [
+    "policy",
+    "critical-level",
+    "safe-level",
+    "balance-weight",
+    "cap-weight",
+    "priority-weight",
+    "soft-cap",
+    "priority-buckets",
+    "reserve-bucket",
+    "reserve-level",
+    "cooldown",
+    "kp",
+    "ki",
+    "kd",
+    "target",
+];
+
+/// Parses a policy file, returning the policy and parameters it configures.
+pub fn parse(contents: &str) -> Result<(Policy, PolicyParams)> {
+    let mut argv = vec!["buckets".to_string()];
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (flag, value) = line.split_once(' ').unwrap_or((line, ""));
+        if !POLICY_FLAGS.contains(&flag) {
+            return Err(anyhow!(
+                "line {}: unknown policy flag '{}'",
+                lineno + 1,
+                flag
+            ));
+        }
+        argv.push(format!("--{}={}", flag, value.trim()));
+    }
+    // Parse the flags just like the command line, so they're validated the same way. Clap's
+    // errors go on to show the usage and suggest --help, so only keep their first paragraph.
+    let args = Args::try_parse_from(argv).map_err(|e| {
+        let message = e.to_string();
+        let first = message
+            .lines()
+            .take_while(|line| !line.trim().is_empty())
+            .map(str::trim)
+            .collect::<Vec<_>>()
+            .join(" ");
+        anyhow!("{}", first.trim_start_matches("error: "))
+    })?;
+    Ok((args.policy, args.policy_params()))
+}
+
+/// Re-reads the policy file every `interval_ms`, swapping the controller's policy whenever the
+/// file changes, until the run is cancelled. `active` is kept up to date with the policy in use.
+pub async fn run<S: Sensor>(
+    ct: CancellationToken,
+    path: PathBuf,
+    interval_ms: u64,
+    controller: Arc<Mutex<Controller<S>>>,
+    active: Arc<std::sync::Mutex<Policy>>,
+    events: Arc<Mutex<Events>>,
+) -> Result<()> {
+    // The contents last loaded (or rejected), so an unchanged file is neither reloaded nor
+    // reported again.
+    let mut last = None;
+    let mut latency_ms = 0;
+    loop {
+        tokio::select! {
+            _ = sleep(Duration::from_millis(latency_ms)) => {
+                latency_ms = interval_ms;
+                let contents = read(&path);
+                if last.as_ref() == Some(&contents) {
+                    continue;
+                }
+                let loaded = contents
+                    .as_ref()
+                    .map_err(|e| anyhow!("{}", e))
+                    .and_then(|contents| parse(contents));
+                match loaded {
+                    Ok((policy, params)) => {
+                        // The controller is locked before the events everywhere else, so never
+                        // hold the events while waiting for it.
+                        controller.lock().await.set_policy(policy, params);
+                        *active.lock().expect("policy lock poisoned") = policy;
+                        events.lock().await.add(
+                            EventSource::System,
+                            format!("loaded '{}' policy from {}", policy, path.display()),
+                        );
+                    }
+                    Err(e) => events.lock().await.add_with_severity(
+                        EventSource::System,
+                        Severity::Warn,
+                        format!("rejected policy file {}: {}", path.display(), e),
+                    ),
+                }
+                last = Some(contents);
+            },
+            _ = ct.cancelled() => return Ok(()),
+        }
+    }
+}
+
+/// Reads the policy file, keeping errors as strings so that they can be compared between reads.
+fn read(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::buckets::ids::BucketId;
+    use crate::controller::ManualOverride;
+    use crate::convergence::Tolerance;
+    use crate::sensor::Readings;
+
+    /// Waits for `active` to become `policy`, failing after a second.
+    async fn wait_for(active: &Arc<std::sync::Mutex<Policy>>, policy: Policy) {
+        for _ in 0..100 {
+            if *active.lock().unwrap() == policy {
+                return;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the policy never became '{}'", policy);
+    }
+
+    #[tokio::test]
+    async fn changing_the_file_swaps_the_policy() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("policy-{}.txt", std::process::id()));
+        fs::write(&path, "policy spread\n")?;
+
+        let args = Args::try_parse_from(["buckets"])?;
+        let events = Arc::new(Mutex::new(Events::new()));
+        let (control_signal_tx, _control_signal_rx) = mpsc::channel(1);
+        let sensor = Readings::new(HashMap::from([(BucketId(1), 10)]), 100);
+        let controller = Arc::new(Mutex::new(Controller::new(
+            Policy::NoOp,
+            args.policy_params(),
+            Arc::new(Mutex::new(sensor)),
+            None,
+            None,
+            HashSet::new(),
+            ManualOverride::new(false),
+            Tolerance::new(0),
+            Some(0),
+            events.clone(),
+            control_signal_tx,
+        )));
+        let active = Arc::new(std::sync::Mutex::new(Policy::NoOp));
+        let ct = CancellationToken::new();
+        let reloader = tokio::spawn(run(
+            ct.clone(),
+            path.clone(),
+            10,
+            controller,
+            active.clone(),
+            events.clone(),
+        ));
+
+        wait_for(&active, Policy::Spread).await;
+        fs::write(&path, "policy sort\n")?;
+        wait_for(&active, Policy::Sort).await;
+        // An invalid file leaves the policy as it was.
+        fs::write(&path, "policy sideways\n")?;
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(*active.lock().unwrap(), Policy::Sort);
+
+        ct.cancel();
+        reloader.await??;
+        fs::remove_file(&path)?;
+        let events = events.lock().await;
+        let messages = events
+            .get_all()
+            .iter()
+            .map(|event| event.message.as_str())
+            .collect::<Vec<_>>();
+        assert!(messages
+            .iter()
+            .any(|m| m.starts_with("loaded 'Sort' policy")));
+        assert!(messages
+            .iter()
+            .any(|m| m.starts_with("rejected policy file")));
+        Ok(())
+    }
+}
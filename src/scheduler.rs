@@ -0,0 +1,107 @@
+//! The actuator may be handed actions faster than it applies them. Rather than applying them
+//! strictly in the order they arrived, which would drain one bucket completely before touching the
+//! next when many transfers share a source, it interleaves them by source: every source with
+//! pending transfers gets one applied in turn. Transfers from the same source keep their order, and
+//! actions that aren't single transfers (including multi-transfers, which may have many sources)
+//! share a lane of their own.
+
+use std::collections::VecDeque;
+
+use crate::actuator::Action;
+use crate::buckets::ids::BucketId;
+
+pub struct FairQueue {
+    // One lane of pending actions per source bucket (None for actions that aren't single
+    // transfers), in the order they take turns.
+    lanes: VecDeque<(Option<BucketId>, VecDeque<Action>)>,
+}
+
+impl Default for FairQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FairQueue {
+    pub fn new() -> Self {
+        FairQueue {
+            lanes: VecDeque::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lanes.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lanes.iter().map(|(_, actions)| actions.len()).sum()
+    }
+
+    pub fn push(&mut self, action: Action) {
+        let source = match action {
+            Action::Transfer { source, .. } => Some(source),
+            Action::MultiTransfer(_)
+            | Action::AddBucket
+            | Action::RemoveBucket { .. }
+            | Action::NoAction => None,
+        };
+        match self.lanes.iter_mut().find(|(lane, _)| *lane == source) {
+            Some((_, actions)) => actions.push_back(action),
+            None => self.lanes.push_back((source, VecDeque::from([action]))),
+        }
+    }
+
+    /// Returns the next action of the lane whose turn it is, moving that lane to the back of the
+    /// line.
+    pub fn pop(&mut self) -> Option<Action> {
+        let (source, mut actions) = self.lanes.pop_front()?;
+        let action = actions.pop_front();
+        if !actions.is_empty() {
+            self.lanes.push_back((source, actions));
+        }
+        action
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(source: u64, amount: u64) -> Action {
+        Action::Transfer {
+            source: BucketId(source),
+            destination: BucketId(9),
+            amount,
+        }
+    }
+
+    #[test]
+    fn sources_take_turns() {
+        let mut queue = FairQueue::new();
+        for action in [
+            transfer(1, 1),
+            transfer(1, 2),
+            transfer(1, 3),
+            transfer(2, 1),
+            Action::AddBucket,
+            transfer(2, 2),
+        ] {
+            queue.push(action);
+        }
+        assert_eq!(queue.len(), 6);
+
+        let order = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            [
+                transfer(1, 1),
+                transfer(2, 1),
+                Action::AddBucket,
+                transfer(1, 2),
+                transfer(2, 2),
+                transfer(1, 3),
+            ]
+        );
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+//! With `--script`, the simulation also follows a timeline of scheduled steps, each taken at the
+//! start of a given tick, right after that tick's fill. The file holds one step per line, e.g.:
+//!
+//! ```text
+//! # Flood bucket 2, then see how the spread policy copes.
+//! at 5 fill 2 40
+//! at 5 fill 3 10
+//! at 8 policy spread
+//! ```
+//!
+//! `at T fill B N` pours N into bucket B, just as a fill would, and `at T policy P` switches to
+//! policy P, keeping its parameters. Ticks count from 1, and steps scheduled for the same tick are
+//! taken in the order they're listed. In the interactive mode, a tick is a fill tick.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use tokio::sync::Mutex;
+
+use crate::buckets::ids::BucketId;
+use crate::controller::Controller;
+use crate::policy::{Policy, PolicyParams};
+use crate::sensor::Sensor;
+
+#[derive(Clone, Copy)]
+pub enum Step {
+    Fill { bucket: BucketId, amount: u64 },
+    Policy(Policy),
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Fill { bucket, amount } => write!(f, "fill bucket {} by {}", bucket, amount),
+            Step::Policy(policy) => write!(f, "switch to '{}' policy", policy),
+        }
+    }
+}
+
+pub struct Script {
+    // Keyed by the tick the steps are taken at.
+    steps: BTreeMap<u64, Vec<Step>>,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Script> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow!("couldn't read script {}: {}", path.display(), e))?;
+        Script::parse(&contents).map_err(|e| anyhow!("{}:{}", path.display(), e))
+    }
+
+    /// Parses a script. Errors are prefixed with the line number they were found on.
+    pub fn parse(contents: &str) -> Result<Script> {
+        let mut steps = BTreeMap::<u64, Vec<Step>>::new();
+        for (lineno, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (tick, step) = parse_step(line).map_err(|e| anyhow!("{}: {}", lineno + 1, e))?;
+            steps.entry(tick).or_default().push(step);
+        }
+        Ok(Script { steps })
+    }
+
+    /// Returns the steps to take at the given tick, in order.
+    pub fn at(&self, tick: u64) -> &[Step] {
+        self.steps.get(&tick).map(Vec::as_slice).unwrap_or_default()
+    }
+}
+
+/// Parses a single `at T ...` line into its tick and step.
+fn parse_step(line: &str) -> Result<(u64, Step)> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let number = |word: &str, what: &str| {
+        word.parse::<u64>()
+            .map_err(|e| anyhow!("invalid {} '{}': {}", what, word, e))
+    };
+    let (tick, step) = match words.as_slice() {
+        ["at", tick, step @ ..] => (number(tick, "tick")?, step),
+        _ => return Err(anyhow!("expected 'at <tick> <step>', got '{}'", line)),
+    };
+    if tick == 0 {
+        return Err(anyhow!("ticks count from 1"));
+    }
+    let step = match step {
+        ["fill", bucket, amount] => Step::Fill {
+            bucket: BucketId(number(bucket, "bucket")?),
+            amount: number(amount, "amount")?,
+        },
+        ["policy", policy] => Step::Policy(
+            Policy::from_str(policy, true).map_err(|_| anyhow!("unknown policy '{}'", policy))?,
+        ),
+        _ => {
+            return Err(anyhow!(
+                "expected 'fill <bucket> <amount>' or 'policy <policy>', got '{}'",
+                step.join(" ")
+            ))
+        }
+    };
+    Ok((tick, step))
+}
+
+/// Director takes a script's policy steps in the interactive mode, where the policy belongs to the
+/// controller.
+pub struct Director<S: Sensor> {
+    controller: Arc<Mutex<Controller<S>>>,
+    // The parameters every scripted policy runs with.
+    params: PolicyParams,
+    // Kept up to date with the policy in use, like the policy file reloader does.
+    active: Arc<std::sync::Mutex<Policy>>,
+}
+
+impl<S: Sensor> Director<S> {
+    pub fn new(
+        controller: Arc<Mutex<Controller<S>>>,
+        params: PolicyParams,
+        active: Arc<std::sync::Mutex<Policy>>,
+    ) -> Self {
+        Director {
+            controller,
+            params,
+            active,
+        }
+    }
+
+    pub async fn set_policy(&self, policy: Policy) {
+        self.controller
+            .lock()
+            .await
+            .set_policy(policy, self.params.clone());
+        *self.active.lock().expect("policy lock poisoned") = policy;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(script: &Script, tick: u64) -> Vec<String> {
+        script.at(tick).iter().map(Step::to_string).collect()
+    }
+
+    #[test]
+    fn parses_steps_by_tick_in_order() -> Result<()> {
+        let script = Script::parse(
+            "# A comment, then a blank line.\n\
+             \n\
+             at 5 fill 2 40\n\
+             at 8 policy spread\n\
+             \x20 at 5 fill 3 10 \n",
+        )?;
+        assert_eq!(
+            steps(&script, 5),
+            ["fill bucket 2 by 40", "fill bucket 3 by 10"]
+        );
+        assert_eq!(steps(&script, 8), ["switch to 'Spread' policy"]);
+        assert!(script.at(1).is_empty());
+        assert!(script.at(6).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_steps() {
+        let error = |contents| Script::parse(contents).map(|_| ()).unwrap_err().to_string();
+        assert_eq!(error("at 0 fill 1 5"), "1: ticks count from 1");
+        assert_eq!(
+            error("at 1 fill 1 5\nfill 1 5"),
+            "2: expected 'at <tick> <step>', got 'fill 1 5'"
+        );
+        assert_eq!(
+            error("at x fill 1 5"),
+            "1: invalid tick 'x': invalid digit found in string"
+        );
+        assert_eq!(
+            error("at 1 fill 1"),
+            "1: expected 'fill <bucket> <amount>' or 'policy <policy>', got 'fill 1'"
+        );
+        assert_eq!(
+            error("at 1 policy sideways"),
+            "1: unknown policy 'sideways'"
+        );
+    }
+}
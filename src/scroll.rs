@@ -0,0 +1,113 @@
+//! EventScroll is where the TUI's event log is scrolled to. It starts out following the newest
+//! events. Scrolling up stops that, and from then on the log stays put as new events arrive, until
+//! End turns following back on.
+
+use crate::events::Events;
+
+pub struct EventScroll {
+    // The position of the top event shown, among every event ever added (see Events::dropped), or
+    // None while following the newest events.
+    top: Option<usize>,
+    // How many events fit in the log at once.
+    rows: usize,
+}
+
+impl EventScroll {
+    pub fn new(rows: usize) -> Self {
+        EventScroll { top: None, rows }
+    }
+
+    pub fn is_following(&self) -> bool {
+        self.top.is_none()
+    }
+
+    /// Returns the index, in `events.get_all()`, of the top event to show. If the events that were
+    /// on top have since been evicted, it's the oldest event still kept.
+    pub fn offset(&self, events: &Events) -> usize {
+        let bottom = events.get_all().len().saturating_sub(self.rows);
+        match self.top {
+            Some(top) => top.saturating_sub(events.dropped()).min(bottom),
+            None => bottom,
+        }
+    }
+
+    pub fn up(&mut self, events: &Events, lines: usize) {
+        self.top = Some(self.offset(events).saturating_sub(lines) + events.dropped());
+    }
+
+    /// Scrolls down, but never past the newest events. Only End starts following them again.
+    pub fn down(&mut self, events: &Events, lines: usize) {
+        if self.top.is_some() {
+            let bottom = events.get_all().len().saturating_sub(self.rows);
+            self.top = Some((self.offset(events) + lines).min(bottom) + events.dropped());
+        }
+    }
+
+    pub fn page_up(&mut self, events: &Events) {
+        self.up(events, self.rows);
+    }
+
+    pub fn page_down(&mut self, events: &Events) {
+        self.down(events, self.rows);
+    }
+
+    /// Scrolls to the oldest event still kept.
+    pub fn home(&mut self, events: &Events) {
+        self.top = Some(events.dropped());
+    }
+
+    /// Scrolls to the newest events and follows them from now on.
+    pub fn end(&mut self) {
+        self.top = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventSource;
+
+    // Adds the events numbered `from` up to but excluding `to`.
+    fn add(events: &mut Events, from: usize, to: usize) {
+        for i in from..to {
+            events.add(EventSource::System, format!("event {}", i));
+        }
+    }
+
+    // Returns the top event shown.
+    fn top<'a>(scroll: &EventScroll, events: &'a Events) -> &'a str {
+        &events.get_all()[scroll.offset(events)].message
+    }
+
+    #[test]
+    fn scrolled_up_log_stays_put_through_evictions() {
+        let mut events = Events::new_with_capacity(10);
+        let mut scroll = EventScroll::new(3);
+        add(&mut events, 0, 10);
+        assert_eq!(top(&scroll, &events), "event 7");
+        scroll.up(&events, 2);
+        assert!(!scroll.is_following());
+        assert_eq!(top(&scroll, &events), "event 5");
+
+        // Evicting older events doesn't move the log.
+        add(&mut events, 10, 12);
+        assert_eq!(scroll.offset(&events), 3);
+        assert_eq!(top(&scroll, &events), "event 5");
+        // Until the top event itself is evicted, which leaves the oldest one kept on top.
+        add(&mut events, 12, 17);
+        assert_eq!(top(&scroll, &events), "event 7");
+        scroll.down(&events, 1);
+        assert_eq!(top(&scroll, &events), "event 8");
+        scroll.home(&events);
+        assert_eq!(top(&scroll, &events), "event 7");
+        // Scrolling down stops at the newest events, and only End follows them again.
+        scroll.page_down(&events);
+        scroll.page_down(&events);
+        scroll.page_down(&events);
+        assert_eq!(top(&scroll, &events), "event 14");
+        assert!(!scroll.is_following());
+        scroll.end();
+        add(&mut events, 17, 18);
+        assert_eq!(top(&scroll, &events), "event 15");
+    }
+}
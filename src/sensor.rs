@@ -1,11 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use itertools::Itertools;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
-// TODO: We should have a dedicated type for bucket ID & for bucket ID + value tuple.
+use crate::buckets::ids::BucketId;
+
+// TODO: We should have a dedicated type for the bucket ID + value tuple.
 pub trait Sensor {
-    fn buckets(&self) -> &HashMap<u64, u64>;
-    fn get_smallest_bucket(&self) -> Option<(u64, u64)> {
+    fn buckets(&self) -> &HashMap<BucketId, u64>;
+    fn get_smallest_bucket(&self) -> Option<(BucketId, u64)> {
         self.buckets()
             .iter()
             // Sort the tuples with the value of the bucket first, so only the smallest buckets are
@@ -15,7 +20,7 @@ pub trait Sensor {
             // Now flip it back to normal.
             .map(|(value, bucket)| (*bucket, *value))
     }
-    fn get_largest_bucket(&self) -> Option<(u64, u64)> {
+    fn get_largest_bucket(&self) -> Option<(BucketId, u64)> {
         // NOTE: See the implementation of get_smallest_bucket().
         self.buckets()
             .iter()
@@ -26,5 +31,291 @@ pub trait Sensor {
             // Now flip it back to normal.
             .map(|(value, bucket)| (*bucket, *value))
     }
-    fn get_bucket_quantity(&self, bucket: u64) -> Result<u64>;
+    /// Returns the total quantity across all buckets, saturating at `u64::MAX`.
+    fn total(&self) -> u64 {
+        self.buckets()
+            .values()
+            .fold(0, |total, value| total.saturating_add(*value))
+    }
+    fn mean(&self) -> Option<f64> {
+        if self.buckets().is_empty() {
+            return None;
+        }
+        Some(self.total() as f64 / self.buckets().len() as f64)
+    }
+    /// Returns the population variance of the bucket quantities.
+    fn variance(&self) -> Option<f64> {
+        let mean = self.mean()?;
+        let squared_deviations = self
+            .buckets()
+            .values()
+            .map(|value| (*value as f64 - mean).powi(2))
+            .sum::<f64>();
+        Some(squared_deviations / self.buckets().len() as f64)
+    }
+    /// Returns whether the fullest and emptiest buckets are within `tolerance` of each other. No
+    /// buckets at all are trivially balanced.
+    fn is_balanced(&self, tolerance: u64) -> bool {
+        match (self.get_smallest_bucket(), self.get_largest_bucket()) {
+            (Some((_, min)), Some((_, max))) => max.saturating_sub(min) <= tolerance,
+            _ => true,
+        }
+    }
+    /// Returns the Gini coefficient of the bucket quantities: 0 when every bucket holds the same
+    /// quantity, approaching 1 as all of the fluid concentrates in a single bucket. Unlike the
+    /// variance, it doesn't depend on the scale of the quantities.
+    fn gini_coefficient(&self) -> Option<f64> {
+        let n = self.buckets().len();
+        if n == 0 {
+            return None;
+        }
+        let total = self.total();
+        if total == 0 {
+            // No fluid anywhere is as equal as it gets.
+            return Some(0.0);
+        }
+        // With the quantities sorted ascending, G = 2 * sum(i * x_i) / (n * sum(x)) - (n + 1) / n,
+        // where i is the 1-based rank of x_i.
+        let weighted_sum = self
+            .buckets()
+            .values()
+            .sorted()
+            .enumerate()
+            .map(|(i, value)| (i + 1) as f64 * *value as f64)
+            .sum::<f64>();
+        let n = n as f64;
+        Some(2.0 * weighted_sum / (n * total as f64) - (n + 1.0) / n)
+    }
+    /// Returns the `p`-th percentile (`p` in 0..=100, clamped) of the bucket quantities,
+    /// interpolating linearly between the two closest ranks. The 0th percentile is the smallest
+    /// quantity, the 50th the median and the 100th the largest.
+    #[allow(dead_code)]
+    fn percentile(&self, p: f64) -> Option<f64> {
+        let sorted = self.buckets().values().sorted().collect::<Vec<_>>();
+        if sorted.is_empty() {
+            return None;
+        }
+        let rank = p.clamp(0.0, 100.0) / 100.0 * (sorted.len() - 1) as f64;
+        let (below, above) = (rank.floor() as usize, rank.ceil() as usize);
+        let fraction = rank - below as f64;
+        Some(*sorted[below] as f64 + (*sorted[above] as f64 - *sorted[below] as f64) * fraction)
+    }
+    #[allow(dead_code)]
+    fn get_bucket_quantity(&self, bucket: BucketId) -> Result<u64>;
+    /// Returns the most that any single bucket can hold.
+    fn capacity(&self) -> u64;
+}
+
+/// Readings is a point-in-time copy of sensed bucket quantities. Policies analyze these instead of
+/// the buckets themselves whenever what is sensed may diverge from the true quantities.
+pub struct Readings {
+    readings: HashMap<BucketId, u64>,
+    // The capacity of the sensed buckets.
+    capacity: u64,
+}
+
+impl Readings {
+    pub fn new(readings: HashMap<BucketId, u64>, capacity: u64) -> Self {
+        Readings { readings, capacity }
+    }
+
+    /// Returns the sensor's readings without the given buckets, or None if the sensor has none of
+    /// them, in which case it can be used as is.
+    pub fn without<S: Sensor>(sensor: &S, buckets: &HashSet<BucketId>) -> Option<Readings> {
+        if !buckets
+            .iter()
+            .any(|bucket| sensor.buckets().contains_key(bucket))
+        {
+            return None;
+        }
+        Some(Readings::new(
+            sensor
+                .buckets()
+                .iter()
+                .filter(|(bucket, _)| !buckets.contains(bucket))
+                .map(|(bucket, quantity)| (*bucket, *quantity))
+                .collect(),
+            sensor.capacity(),
+        ))
+    }
+}
+
+impl Sensor for Readings {
+    fn buckets(&self) -> &HashMap<BucketId, u64> {
+        &self.readings
+    }
+
+    fn get_bucket_quantity(&self, bucket: BucketId) -> Result<u64> {
+        self.readings
+            .get(&bucket)
+            .copied()
+            .ok_or(anyhow!("no reading for bucket @ {}", bucket))
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+}
+
+/// SensorNoise models an imprecise sensor by adding zero-mean Gaussian noise to every sensed
+/// quantity. Only the readings are perturbed; the underlying buckets are never touched.
+pub struct SensorNoise {
+    stddev: f64,
+    rng: StdRng,
+}
+
+impl SensorNoise {
+    /// Creates the noise, seeded from `seed` if given and from entropy otherwise. Like the policy
+    /// RNG, the seed is offset, so that the noise doesn't draw the same sequence as the fill.
+    pub fn new(stddev: f64, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(2)),
+            None => StdRng::from_os_rng(),
+        };
+        SensorNoise { stddev, rng }
+    }
+
+    pub fn read<S: Sensor>(&mut self, sensor: &S) -> Readings {
+        let readings = sensor
+            .buckets()
+            .iter()
+            // Draw the noise in order of bucket, so that seeded noise always lands the same way.
+            .sorted()
+            .map(|(bucket, quantity)| {
+                let noisy = *quantity as f64 + self.sample() * self.stddev;
+                // Quantities can't be negative, so clamp noisy readings at 0.
+                (*bucket, noisy.round().max(0.0) as u64)
+            })
+            .collect();
+        Readings::new(readings, sensor.capacity())
+    }
+
+    /// Samples from the standard normal distribution via the Box-Muller transform.
+    fn sample(&mut self) -> f64 {
+        // random() is in [0, 1), so flip it to (0, 1] to keep ln() finite.
+        let u1 = 1.0 - self.rng.random::<f64>();
+        let u2 = self.rng.random::<f64>();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
+
+/// Calibration models miscalibrated sensors by adding a fixed offset to the sensed quantity of
+/// some buckets. Like noise, it only affects the readings and never the underlying buckets.
+pub struct Calibration {
+    offsets: HashMap<BucketId, i64>,
+}
+
+impl Calibration {
+    pub fn new(offsets: HashMap<BucketId, i64>) -> Self {
+        Calibration { offsets }
+    }
+
+    pub fn read<S: Sensor>(&self, sensor: &S) -> Readings {
+        let readings = sensor
+            .buckets()
+            .iter()
+            .map(|(bucket, quantity)| {
+                let offset = self.offsets.get(bucket).copied().unwrap_or(0);
+                // Quantities can't be negative, so clamp offset readings at 0.
+                (*bucket, quantity.saturating_add_signed(offset))
+            })
+            .collect();
+        Readings::new(readings, sensor.capacity())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readings(quantities: &[u64]) -> Readings {
+        let readings = quantities
+            .iter()
+            .enumerate()
+            .map(|(i, quantity)| (BucketId(i as u64 + 1), *quantity))
+            .collect();
+        Readings::new(readings, 100)
+    }
+
+    #[test]
+    fn percentile_matches_min_median_and_max() {
+        let sensor = readings(&[40, 10, 30, 20, 50]);
+        let smallest = sensor.get_smallest_bucket().map(|(_, value)| value as f64);
+        let largest = sensor.get_largest_bucket().map(|(_, value)| value as f64);
+        assert_eq!(sensor.percentile(0.0), smallest);
+        assert_eq!(sensor.percentile(50.0), Some(30.0));
+        assert_eq!(sensor.percentile(100.0), largest);
+        // Out of range percentiles are clamped.
+        assert_eq!(sensor.percentile(-10.0), smallest);
+        assert_eq!(sensor.percentile(110.0), largest);
+    }
+
+    #[test]
+    fn percentile_interpolates_between_ranks() {
+        let sensor = readings(&[10, 20, 30, 40]);
+        // The median of an even count is halfway between the middle two.
+        assert_eq!(sensor.percentile(50.0), Some(25.0));
+        // p95 is at rank 2.85, 85% of the way from 30 to 40.
+        let p95 = sensor.percentile(95.0).unwrap();
+        assert!((p95 - 38.5).abs() < 1e-9, "p95 was {}", p95);
+        assert_eq!(readings(&[7]).percentile(95.0), Some(7.0));
+    }
+
+    #[test]
+    fn percentile_of_no_buckets() {
+        assert_eq!(readings(&[]).percentile(50.0), None);
+    }
+
+    #[test]
+    fn gini_of_known_distributions() {
+        let gini = |quantities: &[u64]| readings(quantities).gini_coefficient().unwrap();
+        // The mean absolute difference over all 9 ordered pairs is 8/9, over twice the mean of 2.
+        assert!((gini(&[1, 2, 3]) - 2.0 / 9.0).abs() < 1e-9);
+        // It doesn't depend on the scale.
+        assert!((gini(&[30, 10, 20]) - 2.0 / 9.0).abs() < 1e-9);
+        // One bucket holding everything is as unequal as 4 buckets get, (n - 1) / n.
+        assert!((gini(&[0, 0, 100, 0]) - 0.75).abs() < 1e-9);
+        assert_eq!(gini(&[40, 40, 40]), 0.0);
+        assert_eq!(gini(&[0, 0]), 0.0);
+        assert_eq!(gini(&[7]), 0.0);
+        assert_eq!(readings(&[]).gini_coefficient(), None);
+    }
+
+    #[test]
+    fn noise_perturbs_the_readings_but_not_the_buckets() {
+        let sensor = readings(&[50; 10]);
+        let mut noise = SensorNoise::new(5.0, Some(0));
+        let deviations = (0..1000)
+            .flat_map(|_| {
+                noise
+                    .read(&sensor)
+                    .buckets()
+                    .values()
+                    .copied()
+                    .collect::<Vec<_>>()
+            })
+            .map(|value| value as f64 - 50.0)
+            .collect::<Vec<_>>();
+        assert_eq!(sensor.buckets(), readings(&[50; 10]).buckets());
+        assert!(deviations.iter().any(|deviation| *deviation != 0.0));
+        // The deviations should be zero-mean, with about the configured standard deviation.
+        let n = deviations.len() as f64;
+        let mean = deviations.iter().sum::<f64>() / n;
+        let stddev = (deviations.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / n).sqrt();
+        assert!(mean.abs() < 0.5, "mean deviation was {}", mean);
+        assert!((stddev - 5.0).abs() < 0.5, "stddev was {}", stddev);
+    }
+
+    #[test]
+    fn seeded_noise_repeats() {
+        let sensor = readings(&[10, 50, 90]);
+        let read = |seed| {
+            let mut noise = SensorNoise::new(5.0, Some(seed));
+            (0..20)
+                .map(|_| noise.read(&sensor).buckets().clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(read(1), read(1));
+        assert_ne!(read(1), read(2));
+    }
 }
@@ -0,0 +1,570 @@
+//! This file contains a headless, lockstep driver for the simulation.
+//! In the interactive mode, the filler, controller and actuator all run concurrently on their own
+//! timers, so the interleaving of their work (and therefore the outcome of a run) varies from run
+//! to run. Here, every tick performs exactly one fill, one control cycle and one actuation, in that
+//! order. Given seeded buckets, this makes a run exactly reproducible, down to the event log: its
+//! clock starts at the Unix epoch and advances by one second per tick.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, TimeDelta};
+use tokio_util::sync::CancellationToken;
+
+use crate::actuator::{self, Action, Effectiveness, FinalControlElement};
+use crate::buckets::any_buckets::AnyBuckets;
+use crate::buckets::ids::BucketId;
+use crate::buckets::leaky_buckets::LeakyBuckets;
+use crate::buckets::n_buckets::NBuckets;
+use crate::buckets::{BucketType, Buckets};
+use crate::cli::Args;
+use crate::clock::ManualClock;
+use crate::cooldown::Cooldown;
+use crate::events::{EventSource, Events, Severity};
+use crate::overflow::{AutoGrow, Overflows};
+use crate::policy::{Policy, PolicyParams, PolicyState};
+use crate::script::{Script, Step};
+use crate::sensor::{Calibration, Readings, Sensor, SensorNoise};
+
+const TICK_DURATION: TimeDelta = TimeDelta::seconds(1);
+
+pub struct Simulation<B: Buckets + Sensor + FinalControlElement> {
+    buckets: B,
+    policy: Policy,
+    params: PolicyParams,
+    state: PolicyState,
+    clock: Arc<ManualClock>,
+    events: Events,
+    effectiveness: Effectiveness,
+    cooldown: Cooldown,
+    // When set, the policy analyzes noisy readings rather than the true bucket quantities.
+    noise: Option<SensorNoise>,
+    // When set, the policy analyzes readings with these offsets added.
+    calibration: Option<Calibration>,
+    // Read-only buckets, which are always hidden from the policy.
+    readonly: HashSet<BucketId>,
+    // How many transfers have been applied so far.
+    transfers: u64,
+    overflows: Overflows,
+    auto_grow: Option<AutoGrow>,
+    script: Option<Script>,
+    // How many ticks have started so far.
+    ticks: u64,
+    // Never cancelled: a lockstep tick always runs its analysis to completion.
+    ct: CancellationToken,
+}
+
+impl<B: Buckets + Sensor + FinalControlElement> Simulation<B> {
+    /// Creates the simulation, seeding the policy's random choices with `seed`.
+    pub fn new(buckets: B, policy: Policy, params: PolicyParams, seed: u64) -> Self {
+        let clock = Arc::new(ManualClock::new(DateTime::<Local>::from(
+            DateTime::UNIX_EPOCH,
+        )));
+        Simulation {
+            buckets,
+            policy,
+            state: PolicyState::new(Some(seed)),
+            cooldown: Cooldown::new(params.cooldown),
+            params,
+            events: Events::with_clock(clock.clone()),
+            clock,
+            effectiveness: Effectiveness::new(),
+            noise: None,
+            calibration: None,
+            readonly: HashSet::new(),
+            transfers: 0,
+            overflows: Overflows::new(),
+            auto_grow: None,
+            script: None,
+            ticks: 0,
+            ct: CancellationToken::new(),
+        }
+    }
+
+    /// Runs a single tick. In strict mode, fails if the tick raised any event of Warn severity or
+    /// worse.
+    pub fn tick(&mut self) -> Result<()> {
+        self.step()?;
+        match self.events.fatal() {
+            Some(fatal) => Err(anyhow!("strict mode: aborted on {}", fatal)),
+            None => Ok(()),
+        }
+    }
+
+    fn step(&mut self) -> Result<()> {
+        self.fill();
+        let action = self.analyze()?;
+        self.actuate(&action)
+    }
+
+    /// Starts a tick with its fill, the first of its three phases.
+    pub fn fill(&mut self) {
+        self.clock.advance(TICK_DURATION);
+        self.ticks += 1;
+
+        let mut fills = self.buckets.fill();
+        if let Some(script) = &self.script {
+            for step in script.at(self.ticks) {
+                self.events.add(
+                    EventSource::System,
+                    format!("script step at tick {}: {}", self.ticks, step),
+                );
+                match *step {
+                    Step::Fill { bucket, amount } => match self.buckets.pour(bucket, amount) {
+                        Ok(fill) => fills.push(fill),
+                        Err(e) => self.events.add_with_severity(
+                            EventSource::System,
+                            Severity::Warn,
+                            format!("script step at tick {} failed: {}", self.ticks, e),
+                        ),
+                    },
+                    Step::Policy(policy) => {
+                        self.policy = policy;
+                        self.cooldown = Cooldown::new(self.params.cooldown);
+                        self.state.reset();
+                    }
+                }
+            }
+        }
+        for fill in &fills {
+            self.events.add(EventSource::Filler, fill.to_string());
+        }
+        let leaked = self.buckets.take_leaked();
+        if leaked > 0 {
+            self.events
+                .add(EventSource::Filler, format!("leaked {} in all", leaked));
+        }
+        let alarms = self.overflows.check(&fills, &self.buckets);
+        let grew = self
+            .auto_grow
+            .and_then(|auto_grow| auto_grow.apply(&mut self.buckets, &alarms));
+        for alarm in alarms {
+            self.events
+                .add_with_severity(EventSource::Filler, Severity::Critical, alarm);
+        }
+        if let Some(grew) = grew {
+            self.events.add(EventSource::System, grew);
+        }
+    }
+
+    /// Runs the control cycle of the tick, after its fill, returning the action the policy decided
+    /// on.
+    pub fn analyze(&mut self) -> Result<Action> {
+        if let Some(warning) = self.effectiveness.check(&self.buckets) {
+            self.events
+                .add_with_severity(EventSource::Actuator, Severity::Warn, warning);
+        }
+
+        // Sense the buckets just as the controller does: calibration offsets first, then noise on
+        // top of them.
+        let calibrated = self
+            .calibration
+            .as_ref()
+            .map(|calibration| calibration.read(&self.buckets));
+        let sensed = match (&mut self.noise, calibrated) {
+            (Some(noise), Some(calibrated)) => noise.read(&calibrated),
+            (Some(noise), None) => noise.read(&self.buckets),
+            (None, Some(calibrated)) => calibrated,
+            (None, None) => Readings::new(self.buckets.buckets().clone(), self.buckets.capacity()),
+        };
+        // Alarms are about every bucket, including those the policy doesn't get to see.
+        let alarm = self.policy.alarm(&sensed, &self.params);
+        let eligible = self.cooldown.eligible(&sensed).unwrap_or(sensed);
+        let readings = Readings::without(&eligible, &self.readonly).unwrap_or(eligible);
+        let action = self
+            .policy
+            .analyze(&readings, &self.params, &mut self.state, &self.ct)?;
+        self.cooldown.record(&action);
+        if let Some(alarm) = alarm {
+            self.events
+                .add_with_severity(EventSource::Controller, Severity::Critical, alarm);
+        }
+        self.events.add(
+            EventSource::Controller,
+            format!(
+                "analyzed sensor data with '{}' policy => {}",
+                self.policy, action
+            ),
+        );
+        if let Err(e) = actuator::validate(&self.buckets, &self.readonly, &action) {
+            self.events.add_with_severity(
+                EventSource::Controller,
+                Severity::Warn,
+                format!("dropped invalid action: {}: {}", action, e),
+            );
+            return Ok(Action::NoAction);
+        }
+        Ok(action)
+    }
+
+    /// Ends the tick by applying the action the control cycle decided on.
+    pub fn actuate(&mut self, action: &Action) -> Result<()> {
+        let warning =
+            actuator::degenerate(action).or_else(|| actuator::refused(&self.buckets, action));
+        if let Some(warning) = warning {
+            self.events
+                .add_with_severity(EventSource::Actuator, Severity::Warn, warning);
+            return Ok(());
+        }
+        let transfers = action.transfers().len() as u64;
+        if transfers > 0 {
+            self.effectiveness.track(action, &self.buckets);
+        }
+        if let Err(e) = actuator::apply(&mut self.buckets, action) {
+            self.effectiveness.untrack();
+            self.events.add_with_severity(
+                EventSource::Actuator,
+                Severity::Critical,
+                format!("failed to apply action: {}: {}", action, e),
+            );
+            return Ok(());
+        }
+        self.transfers += transfers;
+        if !matches!(action, Action::NoAction) {
+            self.events
+                .add(EventSource::Actuator, format!("applied action: {}", action));
+        }
+        Ok(())
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    pub fn buckets(&self) -> &B {
+        &self.buckets
+    }
+
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    pub fn transfers(&self) -> u64 {
+        self.transfers
+    }
+}
+
+impl Simulation<AnyBuckets> {
+    /// Builds the simulation configured by the given arguments, with its buckets seeded by `seed`.
+    pub fn from_args(args: &Args, seed: u64) -> Result<Self> {
+        let buckets = Simulation::buckets_from_args(args, args.initial_state()?, Some(seed));
+        let mut simulation = Simulation::new(buckets, args.policy, args.policy_params(), seed);
+        simulation.noise =
+            (args.sensor_noise > 0.0).then(|| SensorNoise::new(args.sensor_noise, Some(seed)));
+        simulation.calibration =
+            (!args.calibration.is_empty()).then(|| Calibration::new(args.calibration_offsets()));
+        simulation.readonly = args.readonly();
+        simulation.auto_grow = args.auto_grow();
+        simulation.script = args.script.as_deref().map(Script::load).transpose()?;
+        if args.strict {
+            // Nothing needs cancelling here, since tick() fails on the fatal event itself.
+            simulation.events.set_strict(CancellationToken::new());
+        }
+        Ok(simulation)
+    }
+
+    /// Builds the buckets configured by the given arguments, holding `data` and seeded by `seed`
+    /// if given and from entropy otherwise.
+    pub fn buckets_from_args(
+        args: &Args,
+        data: HashMap<BucketId, u64>,
+        seed: Option<u64>,
+    ) -> AnyBuckets {
+        let mut buckets = NBuckets::new(
+            data,
+            args.max_quantity,
+            args.fill_strategy(),
+            args.fill_fanout(),
+            args.fill_throttle(),
+            args.bucket_tags(),
+            seed,
+        );
+        buckets.set_readonly(args.readonly(), args.fill_readonly);
+        match args.bucket_type {
+            BucketType::NBuckets => AnyBuckets::NBuckets(buckets),
+            BucketType::LeakyBuckets => {
+                AnyBuckets::LeakyBuckets(LeakyBuckets::new(buckets, args.leak_rate))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Parser, ValueEnum};
+
+    use super::*;
+
+    /// Returns bucket 2's quantity after each of the first `ticks` ticks of a run that does nothing
+    /// but fill, following `script` if given.
+    fn bucket_2(script: Option<&str>, ticks: u64) -> Result<Vec<u64>> {
+        let args = Args::try_parse_from(["buckets", "--policy", "no-op", "-i", "1:20,2:20,3:20"])?;
+        let mut simulation = Simulation::from_args(&args, 7)?;
+        simulation.script = script.map(Script::parse).transpose()?;
+        (0..ticks)
+            .map(|_| {
+                simulation.tick()?;
+                Ok(simulation.buckets().buckets()[&BucketId(2)])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scripted_fill_happens_at_its_tick() -> Result<()> {
+        let unscripted = bucket_2(None, 6)?;
+        let scripted = bucket_2(Some("at 3 fill 2 40"), 6)?;
+        let poured = scripted
+            .iter()
+            .zip(&unscripted)
+            .map(|(scripted, unscripted)| scripted - unscripted)
+            .collect::<Vec<_>>();
+        assert_eq!(poured, [0, 0, 40, 40, 40, 40]);
+        Ok(())
+    }
+
+    #[test]
+    fn strict_mode_aborts_on_warnings() -> Result<()> {
+        // Pouring into a bucket that doesn't exist fails with a warning at tick 2.
+        let run = |strict: &[&str]| -> Result<u64> {
+            let argv = ["buckets", "--policy", "no-op", "-i", "1:20,2:20,3:20"];
+            let args = Args::try_parse_from(argv.iter().chain(strict))?;
+            let mut simulation = Simulation::from_args(&args, 7)?;
+            simulation.script = Some(Script::parse("at 2 fill 9 10")?);
+            for tick in 1..=5 {
+                simulation
+                    .tick()
+                    .map_err(|e| anyhow!("tick {}: {}", tick, e))?;
+            }
+            Ok(simulation.ticks)
+        };
+        assert_eq!(run(&[])?, 5);
+        let error = run(&["--strict"]).unwrap_err().to_string();
+        assert!(
+            error
+                .starts_with("tick 2: strict mode: aborted on Warn event from System: script step"),
+            "{}",
+            error
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn critical_buckets_alarm_while_cooling_down() -> Result<()> {
+        // The first drain can only move 5 into bucket 2, leaving bucket 1 critical, and then both
+        // buckets cool down.
+        let args = Args::try_parse_from([
+            "buckets",
+            "--policy",
+            "emergency-drain",
+            "--critical-level",
+            "90",
+            "--cooldown",
+            "5",
+            "-i",
+            "1:99,2:95",
+        ])?;
+        let mut simulation = Simulation::from_args(&args, 7)?;
+        for _ in 0..3 {
+            simulation.tick()?;
+        }
+        let alarms = simulation
+            .events()
+            .get_all()
+            .iter()
+            .filter(|event| event.severity == Severity::Critical)
+            .filter(|event| event.message.contains("above the critical level"))
+            .count();
+        assert_eq!(alarms, 3);
+        Ok(())
+    }
+
+    /// Returns the bucket states of the first `ticks` ticks of a spread run with the given extra
+    /// arguments.
+    fn states(extra: &[&str], ticks: u64) -> Result<Vec<HashMap<BucketId, u64>>> {
+        let argv = ["buckets", "--policy", "spread", "-i", "1:10,2:50,3:90"];
+        let args = Args::try_parse_from(argv.iter().chain(extra))?;
+        let mut simulation = Simulation::from_args(&args, 7)?;
+        (0..ticks)
+            .map(|_| {
+                simulation.tick()?;
+                Ok(simulation.buckets().buckets().clone())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn policies_leave_readonly_buckets_alone() -> Result<()> {
+        for policy in ["spread", "emergency-drain", "concentrate", "weighted"] {
+            let args = Args::try_parse_from([
+                "buckets",
+                "--policy",
+                policy,
+                "-i",
+                "1:10,2:50,3:90",
+                "--readonly",
+                "3",
+            ])?;
+            let mut simulation = Simulation::from_args(&args, 7)?;
+            for _ in 0..20 {
+                simulation.tick()?;
+                assert_eq!(
+                    simulation.buckets().buckets()[&BucketId(3)],
+                    90,
+                    "{}",
+                    policy
+                );
+            }
+            // The policies never even tried to use it, which would have been refused or dropped.
+            for event in simulation.events().get_all() {
+                assert!(
+                    !event.message.contains("read-only"),
+                    "{}: {}",
+                    policy,
+                    event.message
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sensing_errors_apply_headless() -> Result<()> {
+        let exact = states(&[], 10)?;
+        let noisy = states(&["--sensor-noise", "10"], 10)?;
+        assert_ne!(noisy, exact);
+        // Seeded noise is as reproducible as everything else.
+        assert_eq!(states(&["--sensor-noise", "10"], 10)?, noisy);
+        assert_ne!(states(&["--calibration", "1:+30"], 10)?, exact);
+        Ok(())
+    }
+
+    /// Returns the first 50 fills of the buckets configured by the given arguments.
+    fn fills(argv: &[&str]) -> Result<Vec<String>> {
+        let args = Args::try_parse_from(["buckets"].iter().chain(argv))?;
+        let data = (1..=5).map(|bucket| (BucketId(bucket), 50)).collect();
+        let mut buckets = Simulation::buckets_from_args(&args, data, args.seed);
+        Ok((0..50)
+            .flat_map(|_| buckets.fill())
+            .map(|fill| fill.to_string())
+            .collect())
+    }
+
+    #[test]
+    fn bucket_tags_reach_the_data() -> Result<()> {
+        let args = Args::try_parse_from([
+            "buckets",
+            "-i",
+            "1:10,2:50,3:90",
+            "--bucket-tags",
+            "1:source,3:sink",
+        ])?;
+        let buckets = Simulation::buckets_from_args(&args, args.initial_state()?, Some(7));
+        let tags = buckets
+            .data()
+            .into_iter()
+            .map(|datum| (datum.bucket, datum.tag))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tags,
+            [
+                (BucketId(1), Some("source".to_string())),
+                (BucketId(2), None),
+                (BucketId(3), Some("sink".to_string())),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn seeded_buckets_fill_the_same() -> Result<()> {
+        for extra in [
+            &[][..],
+            &["--fill-burst"],
+            &["--fill-capacity-weighted"],
+            &["--fill-fanout", "2"],
+            &["--bucket-type", "leaky-buckets"],
+        ] {
+            let seeded = |seed| fills(&[&["--seed", seed][..], extra].concat());
+            assert_eq!(seeded("42")?, seeded("42")?, "{:?}", extra);
+            assert_ne!(seeded("42")?, seeded("43")?, "{:?}", extra);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn target_converges_on_its_level() -> Result<()> {
+        // Hold back every fill, so only the policy moves fluid.
+        let args = Args::try_parse_from([
+            "buckets",
+            "--policy",
+            "target",
+            "--target",
+            "50",
+            "--fill-rate-limit",
+            "0",
+            "-i",
+            "1:10,2:90",
+        ])?;
+        let mut simulation = Simulation::from_args(&args, 7)?;
+        let at_target = HashMap::from([(BucketId(1), 50), (BucketId(2), 50)]);
+        let mut converged_at = None;
+        for tick in 1..=10 {
+            simulation.tick()?;
+            let buckets = simulation.buckets().buckets();
+            match converged_at {
+                None if *buckets == at_target => converged_at = Some(tick),
+                // Once there, it stays there.
+                Some(_) => assert_eq!(*buckets, at_target, "tick {}", tick),
+                None => {}
+            }
+        }
+        assert!(
+            converged_at.is_some(),
+            "{:?}",
+            simulation.buckets().buckets()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn empty_systems_tick_without_acting() -> Result<()> {
+        for policy in Policy::value_variants() {
+            for bucket_type in ["n-buckets", "leaky-buckets"] {
+                let args = Args::try_parse_from([
+                    "buckets",
+                    "--policy",
+                    policy.to_possible_value().expect("not skipped").get_name(),
+                    "--bucket-type",
+                    bucket_type,
+                    "--target",
+                    "50",
+                    "--reserve-bucket",
+                    "1",
+                    "-i",
+                    "",
+                ])?;
+                let mut simulation = Simulation::from_args(&args, 7)?;
+                for _ in 0..5 {
+                    simulation.tick()?;
+                }
+                assert!(simulation.buckets().buckets().is_empty());
+                let warnings = simulation
+                    .events()
+                    .get_all()
+                    .iter()
+                    .filter(|event| event.severity > Severity::Info)
+                    .map(|event| event.message.clone())
+                    .collect::<Vec<_>>();
+                assert!(
+                    warnings.is_empty(),
+                    "{} on {}: {:?}",
+                    policy,
+                    bucket_type,
+                    warnings
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+//! With `--dump-state-on-signal`, sending the process SIGUSR1 prints the bucket quantities and the
+//! most recent events to stderr, without otherwise interrupting the run. This helps to debug a run
+//! that seems stuck while it's still going.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+use crate::buckets::Buckets;
+use crate::cli::format_initial_data;
+use crate::events::Events;
+use crate::sensor::Sensor;
+
+/// How many of the most recent events a dump includes.
+const RECENT_EVENTS: usize = 20;
+
+/// Dumps the state to stderr on every SIGUSR1, until the run is cancelled.
+pub async fn run<S: Buckets + Sensor>(
+    ct: CancellationToken,
+    events: Arc<Mutex<Events>>,
+    sensor: Arc<Mutex<S>>,
+) -> Result<()> {
+    let mut signals = signal(SignalKind::user_defined1())?;
+    loop {
+        tokio::select! {
+            _ = signals.recv() => {
+                let sensor = sensor.lock().await;
+                let events = events.lock().await;
+                eprint!("{}", dump(&*sensor, &events));
+            },
+            _ = ct.cancelled() => return Ok(()),
+        }
+    }
+}
+
+/// Formats the bucket quantities, in the same format as `--initial-data`, and the tags of any
+/// tagged buckets, followed by the most recent events, oldest first.
+pub fn dump<S: Buckets + Sensor>(sensor: &S, events: &Events) -> String {
+    let mut dump = format!("buckets: {}\n", format_initial_data(sensor.buckets()));
+    let tags = sensor
+        .data()
+        .into_iter()
+        .filter_map(|datum| Some(format!("{}:{}", datum.bucket, datum.tag?)))
+        .collect::<Vec<_>>();
+    if !tags.is_empty() {
+        dump.push_str(&format!("tags: {}\n", tags.join(",")));
+    }
+    let all = events.get_all();
+    let recent = all.range(all.len().saturating_sub(RECENT_EVENTS)..);
+    dump.push_str(&format!("last {} of {} events:\n", recent.len(), all.len()));
+    for event in recent {
+        dump.push_str(&format!(
+            "  {} {} {} {}\n",
+            event.timestamp.to_rfc3339(),
+            event.source,
+            event.severity,
+            event.message
+        ));
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::{Local, TimeZone};
+
+    use super::*;
+    use crate::buckets::ids::BucketId;
+    use crate::buckets::n_buckets::NBuckets;
+    use crate::buckets::FillStrategy;
+    use crate::clock::ManualClock;
+    use crate::events::EventSource;
+
+    #[test]
+    fn dump_shows_the_buckets_and_recent_events() {
+        let buckets = NBuckets::new(
+            HashMap::from([(BucketId(1), 10), (BucketId(2), 20)]),
+            100,
+            FillStrategy::Steady,
+            None,
+            None,
+            HashMap::from([(BucketId(2), String::from("sink"))]),
+            Some(0),
+        );
+        let start = Local.timestamp_opt(1_700_000_000, 0).unwrap();
+        let mut events = Events::with_clock(Arc::new(ManualClock::new(start)));
+        for i in 0..25 {
+            events.add(EventSource::Filler, format!("event {}", i));
+        }
+        let dump = dump(&buckets, &events);
+        let lines = dump.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[..3],
+            [
+                "buckets: 1:10,2:20",
+                "tags: 2:sink",
+                "last 20 of 25 events:"
+            ]
+        );
+        assert_eq!(lines.len(), 3 + RECENT_EVENTS);
+        assert_eq!(
+            lines[3],
+            format!("  {} Filler Info event 5", start.to_rfc3339())
+        );
+        assert!(lines[22].ends_with(" event 24"));
+    }
+}
@@ -0,0 +1,168 @@
+//! State stores persist the bucket state between runs, so that a run can pick up where the last
+//! one left off. Call sites only deal with the `StateStore` trait, so where the state actually
+//! lives is up to the implementation.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+
+use crate::atomic_write;
+use crate::buckets::ids::BucketId;
+use crate::cli::{format_initial_data, parse_initial_data};
+
+/// SavedState is everything a run needs to pick up where another left off: the bucket data and,
+/// if saved, the fill RNG, so that the resumed run draws the same fills the interrupted one would
+/// have gone on to draw.
+pub struct SavedState {
+    pub buckets: HashMap<BucketId, u64>,
+    pub fill_rng: Option<ChaCha12Rng>,
+}
+
+pub trait StateStore {
+    fn save_state(&mut self, state: &SavedState) -> Result<()>;
+    /// Returns the saved state, or None if nothing has been saved yet.
+    fn load_state(&self) -> Result<Option<SavedState>>;
+}
+
+/// FileStateStore keeps the state in a file: the bucket data on the first line, in the same format
+/// as `--initial-data`, optionally followed by a `fill-rng <seed>:<position>` line (see
+/// `format_state`). The file is replaced atomically on every save.
+pub struct FileStateStore {
+    path: PathBuf,
+}
+
+impl FileStateStore {
+    pub fn new(path: &Path) -> Self {
+        FileStateStore {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl StateStore for FileStateStore {
+    fn save_state(&mut self, state: &SavedState) -> Result<()> {
+        atomic_write::write(&self.path, format_state(state).as_bytes())
+    }
+
+    fn load_state(&self) -> Result<Option<SavedState>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        parse_state(&contents)
+            .map(Some)
+            .map_err(|e| anyhow!("{}: {}", self.path.display(), e))
+    }
+}
+
+/// Formats the state as a state file's contents. The RNG is saved as its 32-byte seed, in hex,
+/// and its position in the sequence that seed generates, in 32-bit words.
+pub fn format_state(state: &SavedState) -> String {
+    let mut contents = format!("{}\n", format_initial_data(&state.buckets));
+    if let Some(rng) = &state.fill_rng {
+        let seed = rng
+            .get_seed()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        contents.push_str(&format!("fill-rng {}:{}\n", seed, rng.get_word_pos()));
+    }
+    contents
+}
+
+/// Parses a state file's contents, as formatted by `format_state`.
+pub fn parse_state(contents: &str) -> Result<SavedState> {
+    let mut lines = contents.lines();
+    let buckets = parse_initial_data(lines.next().unwrap_or("").trim()).map_err(|e| anyhow!(e))?;
+    let mut fill_rng = None;
+    for line in lines.map(str::trim).filter(|line| !line.is_empty()) {
+        match line.split_once(' ') {
+            Some(("fill-rng", rng)) => fill_rng = Some(parse_rng(rng)?),
+            _ => return Err(anyhow!("unknown state entry '{}'", line)),
+        }
+    }
+    Ok(SavedState { buckets, fill_rng })
+}
+
+fn parse_rng(rng: &str) -> Result<ChaCha12Rng> {
+    let invalid = || anyhow!("invalid RNG state '{}'", rng);
+    let (seed, position) = rng.split_once(':').ok_or_else(invalid)?;
+    if seed.len() != 64 || !seed.is_ascii() {
+        return Err(invalid());
+    }
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&seed[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    let mut rng = ChaCha12Rng::from_seed(bytes);
+    rng.set_word_pos(position.parse().map_err(|_| invalid())?);
+    Ok(rng)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::RngCore;
+
+    use super::*;
+
+    /// Keeps the state in memory, formatted the way a state file would hold it.
+    #[derive(Default)]
+    struct MemoryStateStore {
+        contents: Option<String>,
+    }
+
+    impl StateStore for MemoryStateStore {
+        fn save_state(&mut self, state: &SavedState) -> Result<()> {
+            self.contents = Some(format_state(state));
+            Ok(())
+        }
+
+        fn load_state(&self) -> Result<Option<SavedState>> {
+            self.contents.as_deref().map(parse_state).transpose()
+        }
+    }
+
+    fn state(fill_rng: Option<ChaCha12Rng>) -> SavedState {
+        SavedState {
+            buckets: HashMap::from([(BucketId(1), 10), (BucketId(2), 0), (BucketId(3), 99)]),
+            fill_rng,
+        }
+    }
+
+    /// Saves the state to the store and loads it back.
+    fn round_trip(store: &mut dyn StateStore, state: &SavedState) -> Result<SavedState> {
+        store.save_state(state)?;
+        Ok(store.load_state()?.expect("state was saved"))
+    }
+
+    #[test]
+    fn states_round_trip() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("state-store-{}.txt", std::process::id()));
+        let mut file = FileStateStore::new(&path);
+        let mut memory = MemoryStateStore::default();
+        assert!(memory.load_state()?.is_none());
+        assert!(file.load_state()?.is_none());
+
+        for store in [&mut memory as &mut dyn StateStore, &mut file] {
+            let loaded = round_trip(store, &state(None))?;
+            assert_eq!(loaded.buckets, state(None).buckets);
+            assert!(loaded.fill_rng.is_none());
+
+            // The RNG resumes where it was saved, rather than from the start of its sequence.
+            let mut rng = ChaCha12Rng::seed_from_u64(7);
+            rng.next_u64();
+            let mut loaded = round_trip(store, &state(Some(rng.clone())))?
+                .fill_rng
+                .expect("RNG was saved");
+            assert_eq!(loaded.next_u64(), rng.next_u64());
+        }
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
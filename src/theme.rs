@@ -0,0 +1,189 @@
+//! A theme is the palette the TUI draws with. The default theme is built from the Okabe-Ito
+//! palette (see `palette`), whose colors stay distinguishable under the common kinds of color
+//! blindness, and `--theme-from-file` loads a JSON object mapping element names to colors on top of
+//! it, e.g.:
+//!
+//! ```json
+//! { "bar": "#5f87af", "label": "white", "filler": "magenta" }
+//! ```
+//!
+//! Colors are anything ratatui can parse: names (`light-blue`), hex (`#5f87af`) or indices (`67`).
+//! Elements left out of the file keep their default color.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use ratatui::style::Color;
+
+use crate::events::{EventSource, Severity};
+
+/// The Okabe-Ito palette, as 24-bit colors.
+pub mod palette {
+    use ratatui::style::Color;
+
+    pub const ORANGE: Color = Color::Rgb(0xe6, 0x9f, 0x00);
+    pub const SKY_BLUE: Color = Color::Rgb(0x56, 0xb4, 0xe9);
+    pub const BLUISH_GREEN: Color = Color::Rgb(0x00, 0x9e, 0x73);
+    pub const YELLOW: Color = Color::Rgb(0xf0, 0xe4, 0x42);
+    pub const BLUE: Color = Color::Rgb(0x00, 0x72, 0xb2);
+    pub const VERMILLION: Color = Color::Rgb(0xd5, 0x5e, 0x00);
+    pub const REDDISH_PURPLE: Color = Color::Rgb(0xcc, 0x79, 0xa7);
+}
+
+#[derive(Clone)]
+pub struct Theme {
+    pub bar: Color,
+    pub value: Color,
+    pub label: Color,
+    pub timestamp: Color,
+    pub message: Color,
+    pub controller: Color,
+    pub actuator: Color,
+    pub filler: Color,
+    pub system: Color,
+    pub warn: Color,
+    pub critical: Color,
+    pub transfer_source: Color,
+    pub transfer_destination: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            bar: palette::BLUE,
+            value: Color::White,
+            label: Color::White,
+            timestamp: Color::DarkGray,
+            message: Color::White,
+            controller: EventSource::Controller.color(),
+            actuator: EventSource::Actuator.color(),
+            filler: EventSource::Filler.color(),
+            system: EventSource::System.color(),
+            warn: palette::YELLOW,
+            critical: palette::VERMILLION,
+            // Source and destination are told apart by a warm/cool contrast rather than red/green.
+            transfer_source: palette::ORANGE,
+            transfer_destination: palette::SKY_BLUE,
+        }
+    }
+}
+
+impl Theme {
+    pub fn from_file(path: &Path) -> Result<Theme> {
+        let contents = fs::read_to_string(path)?;
+        let palette: BTreeMap<String, String> = serde_json::from_str(&contents)
+            .map_err(|e| anyhow!("invalid theme file {}: {}", path.display(), e))?;
+
+        let mut theme = Theme::default();
+        for (element, color) in palette {
+            let color = Color::from_str(&color).map_err(|_| {
+                anyhow!("invalid color '{}' for theme element '{}'", color, element)
+            })?;
+            let slot = match element.as_str() {
+                "bar" => &mut theme.bar,
+                "value" => &mut theme.value,
+                "label" => &mut theme.label,
+                "timestamp" => &mut theme.timestamp,
+                "message" => &mut theme.message,
+                "controller" => &mut theme.controller,
+                "actuator" => &mut theme.actuator,
+                "filler" => &mut theme.filler,
+                "system" => &mut theme.system,
+                "warn" => &mut theme.warn,
+                "critical" => &mut theme.critical,
+                "transfer-source" => &mut theme.transfer_source,
+                "transfer-destination" => &mut theme.transfer_destination,
+                _ => return Err(anyhow!("unknown theme element '{}'", element)),
+            };
+            *slot = color;
+        }
+        Ok(theme)
+    }
+
+    pub fn source(&self, source: EventSource) -> Color {
+        match source {
+            EventSource::Controller => self.controller,
+            EventSource::Actuator => self.actuator,
+            EventSource::Filler => self.filler,
+            EventSource::System => self.system,
+        }
+    }
+
+    /// Returns the color for messages of the given severity.
+    pub fn severity(&self, severity: Severity) -> Color {
+        match severity {
+            Severity::Info => self.message,
+            Severity::Warn => self.warn,
+            Severity::Critical => self.critical,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Loads a theme from a file with the given contents.
+    fn load(name: &str, contents: &str) -> Result<Theme> {
+        let path = std::env::temp_dir().join(format!("{}-{}.json", name, std::process::id()));
+        fs::write(&path, contents)?;
+        let theme = Theme::from_file(&path);
+        fs::remove_file(&path)?;
+        theme
+    }
+
+    #[test]
+    fn default_theme_is_color_blind_safe() {
+        let theme = Theme::default();
+        assert_eq!(theme.bar, palette::BLUE);
+        assert_eq!(theme.value, Color::White);
+        assert_eq!(theme.label, Color::White);
+        assert_eq!(theme.timestamp, Color::DarkGray);
+        assert_eq!(theme.message, Color::White);
+        assert_eq!(theme.controller, palette::ORANGE);
+        assert_eq!(theme.actuator, palette::SKY_BLUE);
+        assert_eq!(theme.filler, palette::BLUISH_GREEN);
+        assert_eq!(theme.system, palette::REDDISH_PURPLE);
+        assert_eq!(theme.warn, palette::YELLOW);
+        assert_eq!(theme.critical, palette::VERMILLION);
+        assert_eq!(theme.transfer_source, palette::ORANGE);
+        assert_eq!(theme.transfer_destination, palette::SKY_BLUE);
+    }
+
+    #[test]
+    fn theme_file_overrides_the_defaults() -> Result<()> {
+        let theme = load(
+            "theme",
+            r##"{ "bar": "#5f87af", "label": "light-blue", "filler": "67", "critical": "red" }"##,
+        )?;
+        assert_eq!(theme.bar, Color::Rgb(0x5f, 0x87, 0xaf));
+        assert_eq!(theme.label, Color::LightBlue);
+        assert_eq!(theme.source(EventSource::Filler), Color::Indexed(67));
+        assert_eq!(theme.severity(Severity::Critical), Color::Red);
+        // Everything else keeps its default color.
+        let default = Theme::default();
+        assert_eq!(theme.value, default.value);
+        assert_eq!(
+            theme.source(EventSource::Actuator),
+            default.source(EventSource::Actuator)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bad_theme_files_are_rejected() {
+        let reason = |name, contents| load(name, contents).map(|_| ()).unwrap_err().to_string();
+        assert_eq!(
+            reason("unknown-element", r#"{ "bars": "red" }"#),
+            "unknown theme element 'bars'"
+        );
+        assert_eq!(
+            reason("invalid-color", r##"{ "bar": "#12345" }"##),
+            "invalid color '#12345' for theme element 'bar'"
+        );
+        assert!(reason("not-json", "bar: red").starts_with("invalid theme file"));
+    }
+}